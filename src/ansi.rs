@@ -0,0 +1,600 @@
+use crate::{cp437, drawing::Raster};
+use chargrid::prelude::*;
+use grid_2d::Grid;
+use rgb_int::Rgb24;
+use std::fmt::Write;
+
+// The parts of a cell's style which can be expressed with SGR escape sequences
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+struct SgrStyle {
+    bold: bool,
+    underline: bool,
+    foreground: Option<Rgb24>,
+    background: Option<Rgb24>,
+}
+
+impl SgrStyle {
+    fn new(style: Style) -> Self {
+        // terminals can't display transparency, so translucent colours are blended with whatever
+        // is beneath them
+        let background = style
+            .background
+            .map(|background| background.alpha_composite(Rgba32::new_grey(0)));
+        let foreground = style.foreground.map(|foreground| {
+            foreground.alpha_composite(background.unwrap_or(Rgba32::new_grey(0)))
+        });
+        Self {
+            bold: style.bold == Some(true),
+            underline: style.underline == Some(true),
+            foreground: foreground.map(Rgba32::to_rgb24),
+            background: background.map(Rgba32::to_rgb24),
+        }
+    }
+
+    // Each sequence starts by resetting all attributes so it doesn't depend on the previous one
+    fn write_sgr(&self, out: &mut String) {
+        out.push_str("\x1b[0");
+        if self.bold {
+            out.push_str(";1");
+        }
+        if self.underline {
+            out.push_str(";4");
+        }
+        if let Some(Rgb24 { r, g, b }) = self.foreground {
+            write!(out, ";38;2;{};{};{}", r, g, b).unwrap();
+        }
+        if let Some(Rgb24 { r, g, b }) = self.background {
+            write!(out, ";48;2;{};{};{}", r, g, b).unwrap();
+        }
+        out.push('m');
+    }
+}
+
+// Converts a grid of cells into text with 24-bit colour escape sequences, suitable for printing
+// to a terminal. An escape sequence is only emitted where the style changes.
+pub fn export(grid: &Grid<RenderCell>) -> String {
+    let mut out = String::new();
+    for row in grid.rows() {
+        let mut current = SgrStyle::default();
+        for cell in row {
+            let character = cell.character.unwrap_or(' ');
+            let mut style = SgrStyle::new(cell.style);
+            if character == ' ' && !style.underline {
+                // the foreground of a space isn't visible so don't let it break up a run
+                style.foreground = current.foreground;
+                style.bold = current.bold;
+            }
+            if style != current {
+                style.write_sgr(&mut out);
+                current = style;
+            }
+            out.push(character);
+        }
+        // reset at the end of each line so colours don't bleed into the rest of the terminal
+        if current != SgrStyle::default() {
+            out.push_str("\x1b[0m");
+        }
+        out.push('\n');
+    }
+    out
+}
+
+// Like `export` but followed by an end-of-file character and a SAUCE record with the size of the
+// grid, so the drawing has the same size when it's imported
+pub fn export_with_sauce(grid: &Grid<RenderCell>) -> Vec<u8> {
+    let mut out = export(grid).into_bytes();
+    let file_size = out.len() as u32;
+    out.push(0x1a);
+    out.extend_from_slice(b"SAUCE00");
+    // the title, author, group and date are left blank
+    out.extend_from_slice(&[b' '; 35 + 20 + 20 + 8]);
+    out.extend_from_slice(&file_size.to_le_bytes());
+    // character data in the ANSi format
+    out.extend_from_slice(&[1, 1]);
+    for t_info in [grid.width(), grid.height(), 0, 0] {
+        out.extend_from_slice(&(t_info.min(u16::MAX as u32) as u16).to_le_bytes());
+    }
+    // no comments or flags, and no font name
+    out.extend_from_slice(&[0; 2 + 22]);
+    out
+}
+
+// The 16 colours of the VGA text mode palette in the order used by SGR escape sequences
+const VGA_COLOURS: [Rgb24; 16] = [
+    Rgb24::hex(0x000000),
+    Rgb24::hex(0xAA0000),
+    Rgb24::hex(0x00AA00),
+    Rgb24::hex(0xAA5500),
+    Rgb24::hex(0x0000AA),
+    Rgb24::hex(0xAA00AA),
+    Rgb24::hex(0x00AAAA),
+    Rgb24::hex(0xAAAAAA),
+    Rgb24::hex(0x555555),
+    Rgb24::hex(0xFF5555),
+    Rgb24::hex(0x55FF55),
+    Rgb24::hex(0xFFFF55),
+    Rgb24::hex(0x5555FF),
+    Rgb24::hex(0xFF55FF),
+    Rgb24::hex(0x55FFFF),
+    Rgb24::hex(0xFFFFFF),
+];
+
+// Files without SAUCE metadata are assumed to be this wide
+const DEFAULT_WIDTH: u32 = 80;
+
+// Files which would be larger than this are rejected rather than allocating a huge raster
+const MAX_WIDTH: u32 = 1024;
+const MAX_HEIGHT: u32 = 4096;
+
+const TAB_WIDTH: i32 = 8;
+
+// Colours from the 256-colour palette: the 16 VGA colours, a 6x6x6 colour cube, then 24 greys
+fn colour_256(index: u8) -> Rgb24 {
+    match index {
+        0..=15 => VGA_COLOURS[index as usize],
+        16..=231 => {
+            let level = |i: u8| if i == 0 { 0 } else { 55 + i * 40 };
+            let i = index - 16;
+            Rgb24::new(level(i / 36), level((i / 6) % 6), level(i % 6))
+        }
+        232..=255 => Rgb24::new_grey(8 + (index - 232) * 10),
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Colour {
+    Indexed(u8),
+    Rgb(Rgb24),
+}
+
+// The metadata from a SAUCE record at the end of a file that's relevant to drawing it
+#[derive(Default)]
+struct Sauce {
+    width: Option<u32>,
+    height: Option<u32>,
+    ice_colours: bool,
+}
+
+// Separates the contents of a file from its SAUCE record and comments, if present
+fn split_sauce(data: &[u8]) -> (&[u8], Sauce) {
+    const RECORD_SIZE: usize = 128;
+    const COMMENT_SIZE: usize = 64;
+    let mut sauce = Sauce::default();
+    let mut data = data;
+    if let Some(record_start) = data.len().checked_sub(RECORD_SIZE) {
+        let record = &data[record_start..];
+        if record.starts_with(b"SAUCE00") {
+            let data_type = record[94];
+            let file_type = record[95];
+            let t_info = |offset: usize| u16::from_le_bytes([record[offset], record[offset + 1]]);
+            // only the width and height of character data in the ANSi format are relevant
+            if data_type == 1 && file_type == 1 {
+                sauce.width = Some(t_info(96) as u32).filter(|&width| width > 0);
+                sauce.height = Some(t_info(98) as u32).filter(|&height| height > 0);
+            }
+            sauce.ice_colours = record[105] & 1 != 0;
+            let num_comments = record[104] as usize;
+            let comments_size = 5 + num_comments * COMMENT_SIZE;
+            data = &data[..record_start];
+            if num_comments > 0 {
+                if let Some(comments_start) = data.len().checked_sub(comments_size) {
+                    if data[comments_start..].starts_with(b"COMNT") {
+                        data = &data[..comments_start];
+                    }
+                }
+            }
+        }
+    }
+    // everything after an end-of-file character is metadata
+    if let Some(end) = data.iter().position(|&byte| byte == 0x1a) {
+        data = &data[..end];
+    }
+    (data, sauce)
+}
+
+// Emulates enough of a DOS terminal to draw ANSI art
+struct Terminal {
+    rows: Vec<Vec<RenderCell>>,
+    width: u32,
+    ice_colours: bool,
+    cursor: Coord,
+    saved_cursor: Coord,
+    foreground: Colour,
+    background: Colour,
+    bold: bool,
+    blink: bool,
+    underline: bool,
+    reverse: bool,
+}
+
+impl Terminal {
+    const BLANK_CELL: RenderCell = RenderCell {
+        character: None,
+        style: Style::DEFAULT.with_background(Rgba32::new_grey(0)),
+    };
+    const DEFAULT_FOREGROUND: Colour = Colour::Indexed(7);
+    const DEFAULT_BACKGROUND: Colour = Colour::Indexed(0);
+
+    fn new(width: u32, ice_colours: bool) -> Self {
+        Self {
+            rows: Vec::new(),
+            width,
+            ice_colours,
+            cursor: Coord::new(0, 0),
+            saved_cursor: Coord::new(0, 0),
+            foreground: Self::DEFAULT_FOREGROUND,
+            background: Self::DEFAULT_BACKGROUND,
+            bold: false,
+            blink: false,
+            underline: false,
+            reverse: false,
+        }
+    }
+
+    fn reset_attributes(&mut self) {
+        self.foreground = Self::DEFAULT_FOREGROUND;
+        self.background = Self::DEFAULT_BACKGROUND;
+        self.bold = false;
+        self.blink = false;
+        self.underline = false;
+        self.reverse = false;
+    }
+
+    // In ANSI art, bold and blink select the bright versions of the first 8 colours. Blink only
+    // does this when iCE colours are enabled since blinking can't be drawn. Bold text in colours
+    // outside the first 8 uses the bold font instead.
+    fn style(&self) -> Style {
+        let (foreground, bold) = match self.foreground {
+            Colour::Indexed(index @ 0..=7) if self.bold => (VGA_COLOURS[index as usize + 8], false),
+            Colour::Indexed(index) => (colour_256(index), self.bold && index > 7),
+            Colour::Rgb(rgb24) => (rgb24, self.bold),
+        };
+        let background = match self.background {
+            Colour::Indexed(index @ 0..=7) if self.blink && self.ice_colours => {
+                VGA_COLOURS[index as usize + 8]
+            }
+            Colour::Indexed(index) => colour_256(index),
+            Colour::Rgb(rgb24) => rgb24,
+        };
+        let (foreground, background) = if self.reverse {
+            (background, foreground)
+        } else {
+            (foreground, background)
+        };
+        Style::default()
+            .with_foreground(foreground.to_rgba32(255))
+            .with_background(background.to_rgba32(255))
+            .with_bold(bold)
+            .with_underline(self.underline)
+    }
+
+    fn row_mut(&mut self, y: i32) -> Result<&mut Vec<RenderCell>, String> {
+        if y >= MAX_HEIGHT as i32 {
+            return Err(format!("drawing is taller than {} rows", MAX_HEIGHT));
+        }
+        let y = y as usize;
+        if self.rows.len() <= y {
+            self.rows.resize_with(y + 1, Vec::new);
+        }
+        let width = self.width as usize;
+        let row = &mut self.rows[y];
+        row.resize(width, Self::BLANK_CELL);
+        Ok(row)
+    }
+
+    fn put_char(&mut self, character: char) -> Result<(), String> {
+        if self.cursor.x >= self.width as i32 {
+            self.cursor = Coord::new(0, self.cursor.y + 1);
+        }
+        let cell = RenderCell {
+            character: Some(character),
+            style: self.style(),
+        };
+        let Coord { x, y } = self.cursor;
+        self.row_mut(y)?[x as usize] = cell;
+        self.cursor.x += 1;
+        Ok(())
+    }
+
+    fn move_cursor(&mut self, x: i32, y: i32) {
+        self.cursor = Coord::new(x.clamp(0, self.width as i32 - 1), y.max(0));
+    }
+
+    fn select_graphic_rendition(&mut self, params: &[Option<u32>]) {
+        let mut params = params.iter().map(|param| param.unwrap_or(0));
+        while let Some(param) = params.next() {
+            match param {
+                0 => self.reset_attributes(),
+                1 => self.bold = true,
+                4 => self.underline = true,
+                5 | 6 => self.blink = true,
+                7 => self.reverse = true,
+                21 | 22 => self.bold = false,
+                24 => self.underline = false,
+                25 => self.blink = false,
+                27 => self.reverse = false,
+                30..=37 => self.foreground = Colour::Indexed(param as u8 - 30),
+                39 => self.foreground = Self::DEFAULT_FOREGROUND,
+                40..=47 => self.background = Colour::Indexed(param as u8 - 40),
+                49 => self.background = Self::DEFAULT_BACKGROUND,
+                90..=97 => self.foreground = Colour::Indexed(param as u8 - 90 + 8),
+                100..=107 => self.background = Colour::Indexed(param as u8 - 100 + 8),
+                38 | 48 => {
+                    let colour = match params.next() {
+                        Some(5) => params.next().map(|index| Colour::Indexed(index as u8)),
+                        Some(2) => {
+                            let mut channel = || params.next().unwrap_or(0) as u8;
+                            Some(Colour::Rgb(Rgb24::new(channel(), channel(), channel())))
+                        }
+                        _ => None,
+                    };
+                    if let Some(colour) = colour {
+                        if param == 38 {
+                            self.foreground = colour;
+                        } else {
+                            self.background = colour;
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+
+    fn control_sequence(&mut self, params: &[Option<u32>], command: char) -> Result<(), String> {
+        // parameters are clamped so that they fit in an i32
+        let param = |i: usize| {
+            params
+                .get(i)
+                .copied()
+                .flatten()
+                .unwrap_or(1)
+                .clamp(1, i32::MAX as u32) as i32
+        };
+        let n = param(0);
+        let Coord { x, y } = self.cursor;
+        match command {
+            'A' => self.move_cursor(x, y.saturating_sub(n)),
+            'B' => self.move_cursor(x, y.saturating_add(n)),
+            'C' => self.move_cursor(x.saturating_add(n), y),
+            'D' => self.move_cursor(x.saturating_sub(n), y),
+            'H' | 'f' => self.move_cursor(param(1) - 1, param(0) - 1),
+            'J' if params.first().copied().flatten() == Some(2) => {
+                self.rows.clear();
+                self.cursor = Coord::new(0, 0);
+            }
+            'K' => {
+                let x = x.min(self.width as i32) as usize;
+                for cell in &mut self.row_mut(y)?[x..] {
+                    *cell = Self::BLANK_CELL;
+                }
+            }
+            'm' => self.select_graphic_rendition(params),
+            's' => self.saved_cursor = self.cursor,
+            'u' => self.cursor = self.saved_cursor,
+            // PabloDraw's 24-bit colour extension
+            't' => {
+                if let [Some(layer), Some(r), Some(g), Some(b)] = params {
+                    let colour = Colour::Rgb(Rgb24::new(*r as u8, *g as u8, *b as u8));
+                    if *layer == 0 {
+                        self.background = colour;
+                    } else {
+                        self.foreground = colour;
+                    }
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn run(&mut self, text: &[char]) -> Result<(), String> {
+        let mut chars = text.iter().copied().peekable();
+        while let Some(character) = chars.next() {
+            match character {
+                '\x1b' => {
+                    if chars.next_if_eq(&'[').is_none() {
+                        // other escape sequences don't affect drawing, so skip their second
+                        // character
+                        chars.next();
+                        continue;
+                    }
+                    let mut params = String::new();
+                    let mut command = None;
+                    for character in chars.by_ref() {
+                        if ('\x40'..='\x7e').contains(&character) {
+                            command = Some(character);
+                            break;
+                        }
+                        params.push(character);
+                    }
+                    // private sequences such as "ESC[?7h" don't affect drawing
+                    if let (Some(command), false) = (command, params.starts_with('?')) {
+                        let params = params
+                            .split(';')
+                            .map(|param| param.parse().ok())
+                            .collect::<Vec<_>>();
+                        self.control_sequence(&params, command)?;
+                    }
+                }
+                '\r' => self.cursor.x = 0,
+                '\n' => self.cursor = Coord::new(0, self.cursor.y + 1),
+                '\t' => {
+                    let x = (self.cursor.x / TAB_WIDTH + 1) * TAB_WIDTH;
+                    self.move_cursor(x, self.cursor.y);
+                }
+                // NUL is drawn as a space as it isn't valid in most export formats
+                '\0' => self.put_char(' ')?,
+                '\x01'..='\x1f' => self.put_char(cp437::to_char(character as u8))?,
+                _ => self.put_char(character)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// Reads ANSI art into a raster. Files are decoded as CP437 unless they are valid UTF-8 (such as
+// those written by `export`).
+pub fn import(data: &[u8]) -> Result<Raster, String> {
+    let (data, sauce) = split_sauce(data);
+    let text = match std::str::from_utf8(data) {
+        Ok(text) => text.chars().collect::<Vec<_>>(),
+        Err(_) => data
+            .iter()
+            .map(|&byte| {
+                if byte < 0x20 {
+                    // control characters are interpreted by the terminal
+                    byte as char
+                } else {
+                    cp437::to_char(byte)
+                }
+            })
+            .collect(),
+    };
+    let width = sauce.width.unwrap_or(DEFAULT_WIDTH);
+    if width > MAX_WIDTH {
+        return Err(format!("drawing is wider than {} columns", MAX_WIDTH));
+    }
+    let mut terminal = Terminal::new(width, sauce.ice_colours);
+    terminal.run(&text)?;
+    let height = (terminal.rows.len() as u32)
+        .max(sauce.height.unwrap_or(0))
+        .max(1);
+    for y in 0..height.min(i32::MAX as u32) as i32 {
+        terminal.row_mut(y)?;
+    }
+    let cells = terminal.rows.into_iter().flatten().collect::<Vec<_>>();
+    Ok(Raster {
+        grid: Grid::new_iterator(Size::new(width, height), cells.into_iter()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cell(character: char, foreground: Rgba32) -> RenderCell {
+        RenderCell::BLANK
+            .with_character(character)
+            .with_foreground(foreground)
+    }
+
+    #[test]
+    fn export_only_emits_escapes_where_the_style_changes() {
+        let red = Rgba32::new(255, 0, 0, 255);
+        let blue = Rgba32::new(0, 0, 255, 255);
+        let cells = [
+            cell('a', red),
+            cell('b', red),
+            cell(' ', blue),
+            cell('c', blue),
+        ]
+        .into_iter()
+        .chain([RenderCell::BLANK; 4]);
+        let grid = Grid::new_iterator(Size::new(4, 2), cells);
+        assert_eq!(
+            export(&grid),
+            "\x1b[0;38;2;255;0;0mab \x1b[0;38;2;0;0;255mc\x1b[0m\n    \n"
+        );
+    }
+
+    #[test]
+    fn export_blends_translucent_colours_over_black() {
+        let cell = cell('a', Rgba32::new(0, 0, 255, 128))
+            .with_background(Rgba32::new(255, 255, 255, 128))
+            .with_bold(true);
+        assert_eq!(
+            export(&Grid::new_clone(Size::new(1, 1), cell)),
+            "\x1b[0;1;38;2;63;63;191;48;2;128;128;128ma\x1b[0m\n"
+        );
+    }
+
+    // Text followed by a SAUCE record for ANSi character data
+    fn with_sauce(text: &[u8], width: u16, height: u16, flags: u8) -> Vec<u8> {
+        let mut data = text.to_vec();
+        data.push(0x1a);
+        data.extend_from_slice(b"SAUCE00");
+        data.extend_from_slice(&[b' '; 87]);
+        data.extend_from_slice(&[1, 1]);
+        data.extend_from_slice(&width.to_le_bytes());
+        data.extend_from_slice(&height.to_le_bytes());
+        data.extend_from_slice(&[0; 5]);
+        data.push(flags);
+        data.extend_from_slice(&[0; 22]);
+        data
+    }
+
+    fn characters(raster: &Raster) -> String {
+        raster
+            .grid
+            .iter()
+            .map(|cell| cell.character.unwrap_or('.'))
+            .collect()
+    }
+
+    #[test]
+    fn exported_drawings_are_imported_with_the_same_size_and_cells() {
+        let size = Size::new(100, 3);
+        let grid = Grid::new_fn(size, |Coord { x, y }| {
+            let character = if x % 7 == 0 { '░' } else { 'a' };
+            let style = Style::default()
+                .with_foreground(Rgba32::new(x as u8, 0, 0, 255))
+                .with_background(Rgba32::new(0, y as u8, 0, 255))
+                .with_bold(x % 3 == 0)
+                .with_underline(y == 1);
+            RenderCell {
+                character: Some(character),
+                style,
+            }
+        });
+        let raster = import(&export_with_sauce(&grid)).unwrap();
+        assert!(raster.grid == grid);
+        // without a SAUCE record the drawing is assumed to be 80 columns wide
+        let raster = import(export(&grid).as_bytes()).unwrap();
+        assert_eq!(raster.grid.size(), Size::new(80, 6));
+    }
+
+    #[test]
+    fn sauce_sets_the_size_and_enables_ice_colours() {
+        let text = b"\x1b[5;41mA\x1b[0mB";
+        let background = |raster: &Raster| raster.grid.get_checked(Coord::new(0, 0)).background();
+        let raster = import(&with_sauce(text, 4, 3, 0)).unwrap();
+        assert_eq!(raster.grid.size(), Size::new(4, 3));
+        assert_eq!(characters(&raster), "AB..........");
+        assert_eq!(background(&raster), Some(Rgba32::hex_rgb(0xAA0000)));
+        let raster = import(&with_sauce(text, 4, 3, 1)).unwrap();
+        assert_eq!(background(&raster), Some(Rgba32::hex_rgb(0xFF5555)));
+        // lines wrap at the width from the SAUCE record
+        let raster = import(&with_sauce(b"abcdef", 4, 0, 0)).unwrap();
+        assert_eq!(characters(&raster), "abcdef..");
+    }
+
+    #[test]
+    fn files_are_decoded_as_utf8_if_valid_and_otherwise_cp437() {
+        let first_row = |data: &[u8]| {
+            characters(&import(data).unwrap())
+                .chars()
+                .take(3)
+                .collect::<String>()
+        };
+        assert_eq!(first_row("\x01é█".as_bytes()), "☺é█");
+        assert_eq!(first_row(b"\x01\x82\xdb"), "☺é█");
+    }
+
+    #[test]
+    fn oversized_drawings_are_rejected() {
+        assert_eq!(
+            import(&with_sauce(b"a", 1025, 1, 0)).err().unwrap(),
+            "drawing is wider than 1024 columns"
+        );
+        assert!(import(&with_sauce(b"a", 1024, 1, 0)).is_ok());
+        assert_eq!(
+            import(b"\x1b[4096Ba").err().unwrap(),
+            "drawing is taller than 4096 rows"
+        );
+        assert_eq!(
+            import(&with_sauce(b"a", 1, 4097, 0)).err().unwrap(),
+            "drawing is taller than 4096 rows"
+        );
+    }
+}
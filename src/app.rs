@@ -1,31 +1,35 @@
-use crate::palette::Palette;
-use chargrid::{self, border::Border, control_flow::*, prelude::*, text, text_field::TextField};
+use crate::{
+    bitmap::{self, ImportMode},
+    cp437,
+    document::Document,
+    drawing::*,
+    export::{self, ExportTarget},
+    keymap,
+    palette::{self, Palette, PaletteEdit, PaletteRow},
+};
+use chargrid::{
+    self, border::Border, control_flow::*, input::keys, prelude::*, text, text_field::TextField,
+};
 use grid_2d::Grid;
-use line_2d;
 use rgb_int::Rgb24;
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::{HashMap, HashSet},
-    fmt,
-    fs::File,
-    iter,
+    borrow::Cow,
+    collections::HashMap,
+    fmt, fs,
     path::{Path, PathBuf},
+    sync::LazyLock,
+    time::{Duration, SystemTime},
 };
+use unicode_width::UnicodeWidthChar;
 
 #[derive(Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 enum PaletteIndex {
     #[default]
     None,
     Index(usize),
-}
-
-impl PaletteIndex {
-    fn option(self) -> Option<usize> {
-        match self {
-            Self::None => None,
-            Self::Index(i) => Some(i),
-        }
-    }
+    // a colour or character chosen with a picker rather than from the palette
+    Custom,
 }
 
 #[derive(Default, Serialize, Deserialize)]
@@ -35,6 +39,16 @@ struct PerPalette<T> {
     bg: T,
 }
 
+impl<T> PerPalette<T> {
+    fn get_mut(&mut self, row: PaletteRow) -> &mut T {
+        match row {
+            PaletteRow::Ch => &mut self.ch,
+            PaletteRow::Fg => &mut self.fg,
+            PaletteRow::Bg => &mut self.bg,
+        }
+    }
+}
+
 type PaletteIndices = PerPalette<Option<PaletteIndex>>;
 
 #[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
@@ -44,6 +58,11 @@ enum Tool {
     Fill,
     Erase,
     Eyedrop,
+    Rectangle(RectangleMode),
+    Ellipse(EllipseMode),
+    Circle(EllipseMode),
+    Text,
+    Select,
 }
 
 impl fmt::Display for Tool {
@@ -54,6 +73,15 @@ impl fmt::Display for Tool {
             Self::Fill => "Fill",
             Self::Erase => "Erase",
             Self::Eyedrop => "Eyedrop",
+            Self::Rectangle(RectangleMode::Outline) => "Rect",
+            Self::Rectangle(RectangleMode::Filled) => "Rect Fill",
+            Self::Rectangle(RectangleMode::BoxDrawing) => "Rect Box",
+            Self::Ellipse(EllipseMode::Outline) => "Ellipse",
+            Self::Ellipse(EllipseMode::Filled) => "Ellipse Fill",
+            Self::Circle(EllipseMode::Outline) => "Circle",
+            Self::Circle(EllipseMode::Filled) => "Circle Fill",
+            Self::Text => "Text",
+            Self::Select => "Select",
         };
         write!(f, "{}", s)
     }
@@ -62,437 +90,772 @@ impl fmt::Display for Tool {
 impl Tool {
     fn all() -> Vec<Self> {
         use Tool::*;
-        vec![Pencil, Fill, Line, Erase, Eyedrop]
+        vec![
+            Pencil,
+            Fill,
+            Line,
+            Rectangle(RectangleMode::Outline),
+            Rectangle(RectangleMode::Filled),
+            Rectangle(RectangleMode::BoxDrawing),
+            Ellipse(EllipseMode::Outline),
+            Ellipse(EllipseMode::Filled),
+            Circle(EllipseMode::Outline),
+            Circle(EllipseMode::Filled),
+            Text,
+            Select,
+            Erase,
+            Eyedrop,
+        ]
     }
 
-    fn new_event(self, coord: Coord) -> Option<DrawingEvent> {
+    // `cell_aspect_ratio` is the height of a cell divided by its width, and is used to make
+    // circles look round on backends with non-square cells
+    fn new_event(
+        self,
+        coord: Coord,
+        cell_aspect_ratio: f64,
+        brush: &Brush,
+    ) -> Option<DrawingEvent> {
         match self {
+            Self::Pencil | Self::Erase if !brush.is_single_cell() => Some(DrawingEvent::stroke(
+                coord,
+                brush.clone(),
+                self == Self::Erase,
+            )),
             Self::Pencil => Some(DrawingEvent::pencil(coord)),
             Self::Fill => Some(DrawingEvent::flood_fill(coord)),
             Self::Line => Some(DrawingEvent::line(coord)),
             Self::Erase => Some(DrawingEvent::erase(coord)),
+            Self::Rectangle(mode) => Some(DrawingEvent::rectangle(coord, mode)),
+            Self::Ellipse(mode) => Some(DrawingEvent::ellipse(coord, mode, None)),
+            Self::Circle(mode) => Some(DrawingEvent::ellipse(coord, mode, Some(cell_aspect_ratio))),
+            Self::Text => Some(DrawingEvent::text(coord)),
             _ => None,
         }
     }
 }
 
-#[derive(Serialize, Deserialize)]
-struct PencilEvent {
-    coords: HashMap<Coord, u32>,
-    last_coord: Coord,
-}
-
-impl PencilEvent {
-    fn mouse_press(coord: Coord) -> Self {
-        Self {
-            coords: iter::once((coord, 1)).collect(),
-            last_coord: coord,
-        }
-    }
-    fn mouse_move(&mut self, coord: Coord) {
-        if coord != self.last_coord {
-            let iter =
-                line_2d::LineSegment::new(self.last_coord, coord).config_iter(line_2d::Config {
-                    exclude_start: true,
-                    exclude_end: false,
-                });
-            for coord in iter {
-                *self.coords.entry(coord).or_insert(0) += 1;
-            }
-            self.last_coord = coord;
-        }
-    }
-    fn commit(&self, render_cell: RenderCell, raster: &mut Raster) {
-        for (&coord, &count) in self.coords.iter() {
-            for _ in 0..count {
-                raster.set_coord(coord, render_cell);
-            }
-        }
-    }
-    fn preview(&self, raster: &Raster, render_cell: RenderCell, ctx: Ctx, fb: &mut FrameBuffer) {
-        for (&coord, &count) in self.coords.iter() {
-            // chargrid's alpha compositing doesn't blend foreground colours so fake it here
-            if let Some(&stacked_render_cell) = raster.grid.get(coord) {
-                let mut stacked_render_cell = stacked_render_cell;
-                for _ in 0..count {
-                    stacked_render_cell =
-                        Raster::stack_render_cells(stacked_render_cell, render_cell);
-                }
-                fb.set_cell_relative_to_ctx(ctx, coord, 0, stacked_render_cell);
-            }
-        }
-    }
-}
-
-#[derive(Serialize, Deserialize)]
-struct FillEvent {
-    start: Coord,
-}
-
-impl FillEvent {
-    fn mouse_press(coord: Coord) -> Self {
-        Self { start: coord }
-    }
-    fn mouse_move(&mut self, coord: Coord) {
-        self.start = coord;
-    }
-    fn commit(&self, render_cell: RenderCell, raster: &mut Raster) {
-        for coord in raster.flood_fill(self.start) {
-            raster.set_coord(coord, render_cell);
-        }
-    }
-    fn preview(&self, raster: &Raster, render_cell: RenderCell, ctx: Ctx, fb: &mut FrameBuffer) {
-        for coord in raster.flood_fill(self.start) {
-            if let Some(&current_cell) = raster.grid.get(coord) {
-                let stacked_render_cell = Raster::stack_render_cells(current_cell, render_cell);
-                fb.set_cell_relative_to_ctx(ctx, coord, 0, stacked_render_cell);
-            }
-        }
-    }
-}
-
-#[derive(Serialize, Deserialize)]
-struct LineEvent {
-    start: Coord,
-    end: Coord,
+// Everything that can be bound to a key in the keymap
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Action {
+    Tool(Tool),
+    Undo,
+    Redo,
+    Save,
+    SaveAs,
+    Export,
+    Copy,
+    Cut,
+    Paste,
+    ClearSelection,
+    PreviousFrame,
+    NextFrame,
+    SwapColours,
+    PreviousEntry(PaletteRow),
+    NextEntry(PaletteRow),
+    Pan(Coord),
+    MoveCursor(Coord),
+    CursorPress,
+    CanvasSize,
+    PaletteEditor,
+    CharPicker,
+    Help,
+    BrushSmaller,
+    BrushLarger,
+    BrushShape,
+    BrushFromSelection,
+    Lock(Channel),
 }
 
-impl LineEvent {
-    fn mouse_press(coord: Coord) -> Self {
-        Self {
-            start: coord,
-            end: coord,
-        }
-    }
-    fn mouse_move(&mut self, coord: Coord) {
-        self.end = coord;
-    }
-    fn commit(&self, render_cell: RenderCell, raster: &mut Raster) {
-        for coord in line_2d::coords_between(self.start, self.end) {
-            raster.set_coord(coord, render_cell);
-        }
-    }
-    fn preview(&self, raster: &Raster, render_cell: RenderCell, ctx: Ctx, fb: &mut FrameBuffer) {
-        for coord in line_2d::coords_between(self.start, self.end) {
-            if let Some(&current_cell) = raster.grid.get(coord) {
-                let stacked_render_cell = Raster::stack_render_cells(current_cell, render_cell);
-                fb.set_cell_relative_to_ctx(ctx, coord, 0, stacked_render_cell);
-            }
+impl Action {
+    fn all() -> Vec<Self> {
+        let mut actions = Tool::all().into_iter().map(Self::Tool).collect::<Vec<_>>();
+        actions.extend([
+            Self::Undo,
+            Self::Redo,
+            Self::Save,
+            Self::SaveAs,
+            Self::Export,
+            Self::Copy,
+            Self::Cut,
+            Self::Paste,
+            Self::ClearSelection,
+            Self::PreviousFrame,
+            Self::NextFrame,
+            Self::SwapColours,
+        ]);
+        for row in PaletteRow::ALL {
+            actions.extend([Self::PreviousEntry(row), Self::NextEntry(row)]);
         }
+        actions.extend([
+            Self::Pan(Coord::new(0, -1)),
+            Self::Pan(Coord::new(0, 1)),
+            Self::Pan(Coord::new(-1, 0)),
+            Self::Pan(Coord::new(1, 0)),
+            Self::MoveCursor(Coord::new(0, -1)),
+            Self::MoveCursor(Coord::new(0, 1)),
+            Self::MoveCursor(Coord::new(-1, 0)),
+            Self::MoveCursor(Coord::new(1, 0)),
+            Self::CursorPress,
+            Self::CanvasSize,
+            Self::PaletteEditor,
+            Self::CharPicker,
+            Self::Help,
+            Self::BrushSmaller,
+            Self::BrushLarger,
+            Self::BrushShape,
+            Self::BrushFromSelection,
+        ]);
+        actions.extend(Channel::ALL.map(Self::Lock));
+        actions
     }
-}
 
-#[derive(Serialize, Deserialize)]
-struct EraseEvent {
-    coords: HashSet<Coord>,
-    last_coord: Coord,
-}
-
-impl EraseEvent {
-    fn mouse_press(coord: Coord) -> Self {
-        Self {
-            coords: iter::once(coord).collect(),
-            last_coord: coord,
-        }
-    }
-    fn mouse_move(&mut self, coord: Coord) {
-        for coord in line_2d::coords_between(self.last_coord, coord) {
-            self.coords.insert(coord);
-        }
-        self.last_coord = coord;
-    }
-    fn commit(&self, raster: &mut Raster) {
-        for &coord in self.coords.iter() {
-            raster.clear_coord(coord);
-        }
-    }
-    fn preview(&self, ctx: Ctx, fb: &mut FrameBuffer) {
-        let blank_render_cell = RenderCell {
-            character: Some('█'),
-            style: Style {
-                foreground: Some(Rgba32::new_grey(0)),
-                ..Default::default()
-            },
+    // The name used in keymap files
+    fn name(self) -> String {
+        let row_name = |row| match row {
+            PaletteRow::Ch => "ch",
+            PaletteRow::Fg => "fg",
+            PaletteRow::Bg => "bg",
+        };
+        let direction_name = |Coord { x, y }| match (x, y) {
+            (0, -1) => "up",
+            (0, _) => "down",
+            (-1, _) => "left",
+            _ => "right",
         };
-        for &coord in self.coords.iter() {
-            fb.set_cell_relative_to_ctx(ctx, coord, 0, blank_render_cell);
-        }
-    }
-}
-
-#[derive(Serialize, Deserialize)]
-enum DrawingEvent {
-    Pencil(PencilEvent),
-    Fill(FillEvent),
-    Line(LineEvent),
-    Erase(EraseEvent),
-}
-
-impl DrawingEvent {
-    fn pencil(coord: Coord) -> Self {
-        Self::Pencil(PencilEvent::mouse_press(coord))
-    }
-    fn flood_fill(coord: Coord) -> Self {
-        Self::Fill(FillEvent::mouse_press(coord))
-    }
-    fn line(coord: Coord) -> Self {
-        Self::Line(LineEvent::mouse_press(coord))
-    }
-    fn erase(coord: Coord) -> Self {
-        Self::Erase(EraseEvent::mouse_press(coord))
-    }
-    fn mouse_move(&mut self, coord: Coord) {
-        match self {
-            Self::Pencil(pencil) => pencil.mouse_move(coord),
-            Self::Fill(flood_fill) => flood_fill.mouse_move(coord),
-            Self::Line(line) => line.mouse_move(coord),
-            Self::Erase(erase) => erase.mouse_move(coord),
-        }
-    }
-    fn commit(&self, render_cell: RenderCell, raster: &mut Raster) {
         match self {
-            Self::Pencil(pencil) => pencil.commit(render_cell, raster),
-            Self::Fill(flood_fill) => flood_fill.commit(render_cell, raster),
-            Self::Line(line) => line.commit(render_cell, raster),
-            Self::Erase(erase) => erase.commit(raster),
+            Self::Tool(tool) => {
+                format!("tool-{}", tool.to_string().to_lowercase().replace(' ', "-"))
+            }
+            Self::Undo => "undo".to_string(),
+            Self::Redo => "redo".to_string(),
+            Self::Save => "save".to_string(),
+            Self::SaveAs => "save-as".to_string(),
+            Self::Export => "export".to_string(),
+            Self::Copy => "copy".to_string(),
+            Self::Cut => "cut".to_string(),
+            Self::Paste => "paste".to_string(),
+            Self::ClearSelection => "clear-selection".to_string(),
+            Self::PreviousFrame => "previous-frame".to_string(),
+            Self::NextFrame => "next-frame".to_string(),
+            Self::SwapColours => "swap-colours".to_string(),
+            Self::PreviousEntry(row) => format!("previous-{}", row_name(row)),
+            Self::NextEntry(row) => format!("next-{}", row_name(row)),
+            Self::Pan(delta) => format!("pan-{}", direction_name(delta)),
+            Self::MoveCursor(delta) => format!("cursor-{}", direction_name(delta)),
+            Self::CursorPress => "cursor-press".to_string(),
+            Self::CanvasSize => "canvas-size".to_string(),
+            Self::PaletteEditor => "palette-editor".to_string(),
+            Self::CharPicker => "char-picker".to_string(),
+            Self::Help => "help".to_string(),
+            Self::BrushSmaller => "brush-smaller".to_string(),
+            Self::BrushLarger => "brush-larger".to_string(),
+            Self::BrushShape => "brush-shape".to_string(),
+            Self::BrushFromSelection => "brush-from-selection".to_string(),
+            Self::Lock(channel) => format!("lock-{}", channel.name()),
         }
     }
-    fn preview(&self, raster: &Raster, render_cell: RenderCell, ctx: Ctx, fb: &mut FrameBuffer) {
+
+    fn default_keys(self) -> Vec<KeyboardInput> {
+        use KeyboardInput::Char;
         match self {
-            Self::Pencil(pencil) => pencil.preview(raster, render_cell, ctx, fb),
-            Self::Fill(flood_fill) => flood_fill.preview(raster, render_cell, ctx, fb),
-            Self::Line(line) => line.preview(raster, render_cell, ctx, fb),
-            Self::Erase(erase) => erase.preview(ctx, fb),
+            Self::Tool(tool) => match tool {
+                Tool::Pencil => vec![Char('1')],
+                Tool::Fill => vec![Char('2')],
+                Tool::Line => vec![Char('3')],
+                Tool::Rectangle(RectangleMode::Outline) => vec![Char('4')],
+                Tool::Rectangle(RectangleMode::Filled) => vec![Char('5')],
+                Tool::Rectangle(RectangleMode::BoxDrawing) => vec![Char('6')],
+                Tool::Ellipse(EllipseMode::Outline) => vec![Char('7')],
+                Tool::Ellipse(EllipseMode::Filled) => vec![Char('8')],
+                Tool::Circle(EllipseMode::Outline) => vec![Char('9')],
+                Tool::Circle(EllipseMode::Filled) => vec![Char('0')],
+                Tool::Text => vec![Char('t')],
+                Tool::Select => vec![Char('m')],
+                Tool::Erase => vec![Char('d')],
+                Tool::Eyedrop => vec![Char('i')],
+            },
+            Self::Undo => vec![Char('u')],
+            Self::Redo => vec![Char('r')],
+            Self::Save => vec![Char('s')],
+            Self::SaveAs => vec![Char('S')],
+            Self::Export => vec![Char('e')],
+            Self::Copy => vec![Char('c')],
+            Self::Cut => vec![Char('x')],
+            Self::Paste => vec![Char('v')],
+            Self::ClearSelection => vec![keys::ESCAPE],
+            Self::PreviousFrame => vec![Char(',')],
+            Self::NextFrame => vec![Char('.')],
+            Self::SwapColours => vec![Char('w')],
+            Self::PreviousEntry(PaletteRow::Ch) => vec![Char('[')],
+            Self::NextEntry(PaletteRow::Ch) => vec![Char(']')],
+            Self::PreviousEntry(PaletteRow::Fg) => vec![Char('{')],
+            Self::NextEntry(PaletteRow::Fg) => vec![Char('}')],
+            Self::PreviousEntry(PaletteRow::Bg) => vec![Char('(')],
+            Self::NextEntry(PaletteRow::Bg) => vec![Char(')')],
+            Self::Pan(Coord { x: 0, y: -1 }) => vec![KeyboardInput::Up, Char('K')],
+            Self::Pan(Coord { x: 0, .. }) => vec![KeyboardInput::Down, Char('J')],
+            Self::Pan(Coord { x: -1, .. }) => vec![KeyboardInput::Left, Char('H')],
+            Self::Pan(_) => vec![KeyboardInput::Right, Char('L')],
+            Self::MoveCursor(Coord { x: 0, y: -1 }) => vec![Char('k')],
+            Self::MoveCursor(Coord { x: 0, .. }) => vec![Char('j')],
+            Self::MoveCursor(Coord { x: -1, .. }) => vec![Char('h')],
+            Self::MoveCursor(_) => vec![Char('l')],
+            Self::CursorPress => vec![Char(' '), keys::RETURN],
+            Self::CanvasSize => vec![Char('z')],
+            Self::PaletteEditor => vec![Char('p')],
+            Self::CharPicker => vec![Char('g')],
+            Self::Help => vec![Char('?'), KeyboardInput::Function(1)],
+            Self::BrushSmaller => vec![Char('-')],
+            Self::BrushLarger => vec![Char('='), Char('+')],
+            Self::BrushShape => vec![Char('b')],
+            Self::BrushFromSelection => vec![Char('B')],
+            Self::Lock(Channel::Ch) => vec![Char('C')],
+            Self::Lock(Channel::Fg) => vec![Char('F')],
+            Self::Lock(Channel::Bg) => vec![Char('G')],
+            Self::Lock(Channel::Bold) => vec![Char('O')],
+            Self::Lock(Channel::Underline) => vec![Char('U')],
         }
     }
 }
 
-#[derive(Clone, Serialize, Deserialize)]
-struct Raster {
-    grid: Grid<RenderCell>,
+// Maps keys to actions. Bindings are read from a file next to the palette, and any action not
+// mentioned in the file keeps its default keys.
+struct Keymap {
+    path: PathBuf,
+    loaded: bool,
+    bindings: HashMap<KeyboardInput, Action>,
 }
 
-impl Raster {
-    fn new(size: Size) -> Self {
-        let cell = RenderCell {
-            character: None,
-            style: Style::default().with_background(Rgba32::new_grey(0)),
-        };
-        Self {
-            grid: Grid::new_clone(size, cell),
-        }
-    }
-
-    fn stack_render_cells(bottom: RenderCell, top: RenderCell) -> RenderCell {
-        fn blend(a: Option<Rgba32>, b: Option<Rgba32>) -> Option<Rgba32> {
-            match (a, b) {
-                (None, None) => None,
-                (Some(x), None) | (None, Some(x)) => Some(x),
-                (Some(a), Some(b)) => Some(a.alpha_composite(b)),
-            }
-        }
-        let mut ret = bottom;
-        ret.character = top.character.or(bottom.character);
-        ret.style.background = blend(top.style.background, bottom.style.background);
-        // blend the foreground with the background if there is currently no character present
-        let bottom_foreground = if bottom.character.is_none() {
-            bottom.style.background
-        } else {
-            bottom.style.foreground
-        };
-        ret.style.foreground = blend(top.style.foreground, bottom_foreground);
-        ret.style.bold = top.style.bold.or(bottom.style.bold);
-        ret.style.underline = top.style.bold.or(bottom.style.underline);
-        ret
-    }
-
-    fn set_coord(&mut self, coord: Coord, cell: RenderCell) {
-        if let Some(raster_cell) = self.grid.get_mut(coord) {
-            *raster_cell = Self::stack_render_cells(*raster_cell, cell);
-        }
-    }
+impl Keymap {
+    const FILE_NAME: &'static str = "keymap.toml";
 
-    fn clear_coord(&mut self, coord: Coord) {
-        if let Some(raster_cell) = self.grid.get_mut(coord) {
-            *raster_cell = RenderCell {
-                character: None,
-                style: Style::default().with_background(Rgba32::new_grey(0)),
-            };
-        }
-    }
-    fn flood_fill(&self, coord: Coord) -> HashSet<Coord> {
-        use direction::CardinalDirection;
-        use std::collections::VecDeque;
-        let mut queue = VecDeque::new();
-        let mut seen = HashSet::new();
-        let initial_cell = self.grid.get_checked(coord);
-        queue.push_front(coord);
-        seen.insert(coord);
-        while let Some(coord) = queue.pop_back() {
-            for d in CardinalDirection::all() {
-                let nei_coord = coord + d.coord();
-                if !seen.contains(&nei_coord) {
-                    if let Some(nei_cell) = self.grid.get(nei_coord) {
-                        if nei_cell == initial_cell {
-                            seen.insert(nei_coord);
-                            queue.push_front(nei_coord);
-                        }
+    fn load(palette_path: &Path) -> Result<Self, String> {
+        let path = palette_path.with_file_name(Self::FILE_NAME);
+        let mut bindings = Action::all()
+            .into_iter()
+            .flat_map(|action| {
+                action
+                    .default_keys()
+                    .into_iter()
+                    .map(move |key| (key, action))
+            })
+            .collect::<HashMap<_, _>>();
+        let loaded = path.exists();
+        if loaded {
+            let error = |e| format!("failed to load keymap \"{}\" ({})", path.display(), e);
+            let actions = Action::all();
+            let mut bound = HashMap::new();
+            for (name, keys) in keymap::load(&path).map_err(error)? {
+                let action = *actions
+                    .iter()
+                    .find(|action| action.name() == name)
+                    .ok_or_else(|| error(format!("unknown action \"{}\"", name)))?;
+                bindings.retain(|_, &mut a| a != action);
+                for key in keys {
+                    if let Some(other) = bound.insert(key, name.clone()) {
+                        return Err(error(format!(
+                            "\"{}\" is bound to both \"{}\" and \"{}\"",
+                            keymap::key_to_string(key),
+                            other,
+                            name
+                        )));
                     }
+                    bindings.insert(key, action);
                 }
             }
         }
-        seen
+        Ok(Self {
+            path,
+            loaded,
+            bindings,
+        })
     }
 
-    fn commit_event(&mut self, event: &DrawingEventWithRenderCell) {
-        event.drawing_event.commit(event.render_cell, self);
+    fn keys(&self, action: Action) -> Vec<KeyboardInput> {
+        let mut keys = self
+            .bindings
+            .iter()
+            .filter(|&(_, &a)| a == action)
+            .map(|(&key, _)| key)
+            .collect::<Vec<_>>();
+        keys.sort();
+        keys
     }
 }
 
-#[derive(Serialize, Deserialize)]
-struct DrawingEventWithRenderCell {
-    drawing_event: DrawingEvent,
-    render_cell: RenderCell,
+// Controls how the frames either side of the current frame are drawn beneath it
+struct OnionSkin {
+    previous: bool,
+    next: bool,
+    opacity: u8,
 }
 
-#[derive(Serialize, Deserialize)]
-struct UndoBuffer {
-    initial: Raster,
-    events: Vec<DrawingEventWithRenderCell>,
-    redo_buffer: Vec<DrawingEventWithRenderCell>,
+// The brush used by the pencil and erase tools
+struct BrushSettings {
+    shape: BrushShape,
+    size: u32,
+    // cells captured from a selection, which are used instead of the shape while `use_custom`
+    custom: Option<Grid<RenderCell>>,
+    use_custom: bool,
 }
 
-impl UndoBuffer {
-    fn new(initial: Raster) -> Self {
-        Self {
-            initial,
-            events: Vec::new(),
-            redo_buffer: Vec::new(),
-        }
-    }
+impl BrushSettings {
+    const MAX_SIZE: u32 = 15;
 
-    fn undo(&mut self) -> Raster {
-        let mut raster = self.initial.clone();
-        if let Some(event) = self.events.pop() {
-            self.redo_buffer.push(event);
-            for event in &self.events {
-                raster.commit_event(event);
-            }
+    fn brush(&self) -> Brush {
+        match &self.custom {
+            Some(cells) if self.use_custom => Brush::Custom(cells.clone()),
+            _ => Brush::Shape {
+                shape: self.shape,
+                size: self.size,
+            },
         }
-        raster
     }
+}
 
-    fn redo(&mut self) -> Raster {
-        let mut raster = self.initial.clone();
-        if let Some(event) = self.redo_buffer.pop() {
-            self.events.push(event);
-        }
-        for event in &self.events {
-            raster.commit_event(event);
-        }
-        raster
-    }
+// An image to convert into the initial drawing
+pub struct ImageImport {
+    pub path: PathBuf,
+    pub mode: ImportMode,
+    pub size: Option<Size>,
+}
 
-    fn commit_event(&mut self, event: DrawingEventWithRenderCell) {
-        self.events.push(event);
-        self.redo_buffer.clear();
+impl ImageImport {
+    fn load(&self, palette: &Palette, cell_aspect_ratio: f64) -> Result<Raster, String> {
+        let data = fs::read(&self.path)
+            .map_err(|e| format!("failed to load \"{}\" ({})", self.path.display(), e))?;
+        bitmap::import(&data, self.mode, self.size, cell_aspect_ratio, palette)
+            .map_err(|e| format!("failed to import \"{}\" ({})", self.path.display(), e))
     }
 }
 
-#[derive(Serialize, Deserialize)]
+struct CustomEntries {
+    ch: char,
+    fg: Rgb24,
+    bg: Rgb24,
+}
+
 struct LivePaths {
     palette_path: PathBuf,
     output_path: PathBuf,
+    export_path: PathBuf,
 }
 
-#[derive(Serialize, Deserialize)]
 struct DrawingState {
     palette_indices: PaletteIndices,
     tools: Vec<Tool>,
     tool_index: usize,
-    canvas_state: Raster,
+    document: Document,
+    frame_index: usize,
+    layer_index: usize,
     current_event: Option<DrawingEvent>,
-    undo_buffer: UndoBuffer,
+    onion_skin: OnionSkin,
+    brush: BrushSettings,
+    locks: ChannelLocks,
+    playing: bool,
+    playback_time: Duration,
     eyedrop_render_cell: Option<RenderCell>,
     fg_opacity: u8,
     bg_opacity: u8,
     palette_hover: PaletteIndices,
     tool_hover: Option<usize>,
+    layer_hover: Option<usize>,
     canvas_hover: Option<Coord>,
+    selection: Option<Selection>,
+    clipboard: Option<Grid<RenderCell>>,
+    // the values used when the custom ch, fg or bg is selected
+    custom: CustomEntries,
+    // characters chosen with the character picker, most recent first
+    recent_chars: Vec<char>,
+    // the canvas coord shown at the top-left of the canvas view
+    camera: Coord,
+    // the number of cells of the canvas which fit on screen
+    view_size: Size,
+    // the mouse position during the previous event of a middle-drag
+    pan_coord: Option<Coord>,
+    // the canvas coord for drawing with the keyboard, which is hidden until it's first moved
+    cursor: Option<Coord>,
+    // whether the cursor is acting as a held mouse button
+    cursor_pressed: bool,
+    // whether the document has changed since it was last saved
+    modified: bool,
+    // the result of the last save or export, shown until the document next changes
+    status: Option<String>,
 }
 
 impl DrawingState {
-    fn new() -> Self {
-        let canvas_state = Raster::new(Size::new(80, 60));
-        let undo_buffer = UndoBuffer::new(canvas_state.clone());
+    fn new(document: Document) -> Self {
         Self {
             palette_indices: Default::default(),
             tools: Tool::all(),
             tool_index: 0,
-            canvas_state,
+            document,
+            frame_index: 0,
+            layer_index: 0,
             current_event: None,
-            undo_buffer,
+            onion_skin: OnionSkin {
+                previous: false,
+                next: false,
+                opacity: 63,
+            },
+            brush: BrushSettings {
+                shape: BrushShape::Square,
+                size: 1,
+                custom: None,
+                use_custom: false,
+            },
+            locks: Default::default(),
+            playing: false,
+            playback_time: Duration::ZERO,
             eyedrop_render_cell: None,
             fg_opacity: 255,
             bg_opacity: 255,
             palette_hover: Default::default(),
             tool_hover: None,
+            layer_hover: None,
             canvas_hover: None,
+            selection: None,
+            clipboard: None,
+            custom: CustomEntries {
+                ch: '?',
+                fg: Rgb24::new(255, 255, 255),
+                bg: Rgb24::new(0, 0, 0),
+            },
+            recent_chars: Vec::new(),
+            camera: Coord::new(0, 0),
+            view_size: Size::new(0, 0),
+            pan_coord: None,
+            cursor: None,
+            cursor_pressed: false,
+            modified: false,
+            status: None,
         }
     }
 
-    fn load<P: AsRef<Path>>(path: P) -> Self {
-        use std::io::Read;
-        let mut file = File::open(path).unwrap();
-        let mut data = Vec::new();
-        file.read_to_end(&mut data).unwrap();
-        bincode::deserialize(&data).unwrap()
+    fn frame(&self) -> &Frame {
+        &self.document.frames[self.frame_index]
     }
+
+    fn frame_mut(&mut self) -> &mut Frame {
+        &mut self.document.frames[self.frame_index]
+    }
+
+    fn canvas(&self) -> &Canvas {
+        &self.frame().canvas
+    }
+}
+
+// Tracks changes to the palette file so it can be reloaded while the app is running
+struct PaletteWatch {
+    modified: Option<SystemTime>,
+    time_since_check: Duration,
+}
+
+impl PaletteWatch {
+    const CHECK_PERIOD: Duration = Duration::from_millis(500);
+}
+
+// Periodically writes the document to a recovery file so work isn't lost if the app crashes
+struct Autosave {
+    time_since_save: Duration,
+    // whether the document has changed since it was last autosaved
+    pending: bool,
+}
+
+impl Autosave {
+    const PERIOD: Duration = Duration::from_secs(30);
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+// The last component of a path, or the whole path if it has none
+fn file_name(path: &Path) -> Cow<'_, str> {
+    path.file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_else(|| path.to_string_lossy())
 }
 
 struct AppData {
     live_paths: LivePaths,
     palette: Palette,
+    palette_watch: PaletteWatch,
+    autosave: Autosave,
+    keymap: Keymap,
     drawing_state: DrawingState,
+    cell_aspect_ratio: f64,
+    // size of each cell when exporting to an image
+    export_cell_size_px: Size,
 }
 
 impl AppData {
-    fn new_with_live_paths(live_paths: LivePaths, input_path: Option<PathBuf>) -> Self {
-        let palette = Palette::load(live_paths.palette_path.as_path()).unwrap();
-        let drawing_state = if let Some(input_path) = input_path {
-            DrawingState::load(input_path)
+    fn new(
+        palette_path: Option<PathBuf>,
+        input_path: Option<PathBuf>,
+        image_import: Option<ImageImport>,
+        size: Option<Size>,
+        output_path: PathBuf,
+        export_target: ExportTarget,
+        cell_aspect_ratio: f64,
+    ) -> Result<Self, String> {
+        if input_path.is_some() && image_import.is_some() {
+            return Err("an input file and an image can't both be opened".to_string());
+        }
+        if size.is_some() && (input_path.is_some() || image_import.is_some()) {
+            return Err("a size can only be given when starting a new drawing".to_string());
+        }
+        let mut document = if let Some(input_path) = input_path {
+            Document::load(input_path)?
         } else {
-            DrawingState::new()
+            Document::new(size.unwrap_or_else(|| Size::new(80, 60)))
         };
-        Self {
-            live_paths,
+        // a palette given on the command line takes precedence over the one the document was
+        // last saved with
+        let palette_path = palette_path
+            .or_else(|| document.palette_path.clone())
+            .ok_or_else(|| "no palette specified".to_string())?;
+        let palette = Palette::load(palette_path.as_path()).map_err(|e| {
+            format!(
+                "failed to load palette \"{}\" ({})",
+                palette_path.display(),
+                e
+            )
+        })?;
+        // images are converted after loading the palette as they may be matched against it
+        if let Some(image_import) = image_import {
+            let raster = image_import.load(&palette, cell_aspect_ratio)?;
+            document = Document::from_canvas(Canvas::from_raster(raster));
+        }
+        let palette_watch = PaletteWatch {
+            modified: modified_time(&palette_path),
+            time_since_check: Duration::ZERO,
+        };
+        let keymap = Keymap::load(&palette_path)?;
+        Ok(Self {
+            live_paths: LivePaths {
+                palette_path,
+                export_path: export_target
+                    .path
+                    .unwrap_or_else(|| output_path.with_extension("ans")),
+                output_path,
+            },
             palette,
-            drawing_state,
+            palette_watch,
+            autosave: Autosave {
+                time_since_save: Duration::ZERO,
+                pending: false,
+            },
+            keymap,
+            drawing_state: DrawingState::new(document),
+            cell_aspect_ratio,
+            export_cell_size_px: export_target.cell_size_px,
+        })
+    }
+
+    // Replaces the palette, keeping the selected entries selected if they've moved. If a selected
+    // entry was changed or removed then whichever entry is now at its index is selected instead.
+    fn set_palette(&mut self, palette: Palette) {
+        fn remap<T: PartialEq>(index: &mut Option<PaletteIndex>, old: &[T], new: &[T]) {
+            if let Some(PaletteIndex::Index(i)) = *index {
+                let new_index = old
+                    .get(i)
+                    .and_then(|value| new.iter().position(|new_value| new_value == value))
+                    .unwrap_or_else(|| i.min(new.len().saturating_sub(1)));
+                *index = Some(PaletteIndex::Index(new_index));
+            }
+        }
+        let indices = &mut self.drawing_state.palette_indices;
+        remap(&mut indices.ch, &self.palette.ch, &palette.ch);
+        remap(&mut indices.fg, &self.palette.fg, &palette.fg);
+        remap(&mut indices.bg, &self.palette.bg, &palette.bg);
+        self.drawing_state.palette_hover = Default::default();
+        self.palette = palette;
+    }
+
+    // Reloads the palette if its file has changed since it was last loaded or saved. If the new
+    // file can't be loaded then the current palette is kept until the file is fixed.
+    fn check_palette(&mut self, duration: Duration) -> Result<(), String> {
+        self.palette_watch.time_since_check += duration;
+        if self.palette_watch.time_since_check < PaletteWatch::CHECK_PERIOD {
+            return Ok(());
+        }
+        self.palette_watch.time_since_check = Duration::ZERO;
+        let palette_path = &self.live_paths.palette_path;
+        let modified = modified_time(palette_path);
+        if modified == self.palette_watch.modified {
+            return Ok(());
         }
+        self.palette_watch.modified = modified;
+        let palette = Palette::load(palette_path).map_err(|e| {
+            format!(
+                "failed to reload palette \"{}\" ({})",
+                palette_path.display(),
+                e
+            )
+        })?;
+        self.set_palette(palette);
+        Ok(())
+    }
+
+    // Applies a change from the palette editor and writes the palette back to its file
+    fn edit_palette(&mut self, row: PaletteRow, edit: PaletteEdit) -> Result<(), String> {
+        let mut palette = self.palette.clone();
+        palette.apply_edit(row, edit)?;
+        let palette_path = &self.live_paths.palette_path;
+        palette.save(palette_path).map_err(|e| {
+            format!(
+                "failed to save palette \"{}\" ({})",
+                palette_path.display(),
+                e
+            )
+        })?;
+        // don't reload the file that was just written
+        self.palette_watch.modified = modified_time(palette_path);
+        self.set_palette(palette);
+        Ok(())
     }
 
     fn get_ch(&self) -> Option<char> {
-        self.drawing_state
-            .palette_indices
-            .ch?
-            .option()
-            .map(|i| self.palette.ch[i])
+        match self.drawing_state.palette_indices.ch? {
+            PaletteIndex::None => None,
+            PaletteIndex::Index(i) => Some(self.palette.ch[i]),
+            PaletteIndex::Custom => Some(self.drawing_state.custom.ch),
+        }
     }
 
     fn get_fg(&self) -> Option<Rgba32> {
-        self.drawing_state
-            .palette_indices
-            .fg?
-            .option()
-            .map(|i| self.palette.fg[i].to_rgba32(self.drawing_state.fg_opacity))
+        let rgb24 = match self.drawing_state.palette_indices.fg? {
+            PaletteIndex::None => return None,
+            PaletteIndex::Index(i) => self.palette.fg[i],
+            PaletteIndex::Custom => self.drawing_state.custom.fg,
+        };
+        Some(rgb24.to_rgba32(self.drawing_state.fg_opacity))
     }
 
     fn get_bg(&self) -> Option<Rgba32> {
-        self.drawing_state
-            .palette_indices
-            .bg?
-            .option()
-            .map(|i| self.palette.bg[i].to_rgba32(self.drawing_state.bg_opacity))
+        let rgb24 = match self.drawing_state.palette_indices.bg? {
+            PaletteIndex::None => return None,
+            PaletteIndex::Index(i) => self.palette.bg[i],
+            PaletteIndex::Custom => self.drawing_state.custom.bg,
+        };
+        Some(rgb24.to_rgba32(self.drawing_state.bg_opacity))
+    }
+
+    // Selects a colour from the colour picker, optionally appending it to the palette. If the
+    // palette can't be saved then the colour is still selected as a custom colour.
+    fn set_custom_colour(
+        &mut self,
+        row: PaletteRow,
+        colour: Rgb24,
+        add_to_palette: bool,
+    ) -> Result<(), String> {
+        let mut result = Ok(());
+        let index = if add_to_palette {
+            let len = row.len(&self.palette);
+            let edit = PaletteEdit::Insert(len, palette::rgb24_to_hex(colour));
+            match self.edit_palette(row, edit) {
+                Ok(()) => PaletteIndex::Index(len),
+                Err(e) => {
+                    result = Err(e);
+                    PaletteIndex::Custom
+                }
+            }
+        } else {
+            PaletteIndex::Custom
+        };
+        let custom = &mut self.drawing_state.custom;
+        let indices = &mut self.drawing_state.palette_indices;
+        match row {
+            PaletteRow::Ch => return result,
+            PaletteRow::Fg => {
+                custom.fg = colour;
+                indices.fg = Some(index);
+            }
+            PaletteRow::Bg => {
+                custom.bg = colour;
+                indices.bg = Some(index);
+            }
+        }
+        self.drawing_state.eyedrop_render_cell = None;
+        result
+    }
+
+    // Selects a character from the character picker without adding it to the palette
+    fn set_custom_char(&mut self, ch: char) {
+        let drawing_state = &mut self.drawing_state;
+        drawing_state.custom.ch = ch;
+        drawing_state.palette_indices.ch = Some(PaletteIndex::Custom);
+        drawing_state.recent_chars.retain(|&c| c != ch);
+        drawing_state.recent_chars.insert(0, ch);
+        drawing_state
+            .recent_chars
+            .truncate(CharPickerComponent::RECENT_LEN);
+        drawing_state.eyedrop_render_cell = None;
+    }
+
+    // Selects the entry `step` places along from the selected entry in a row of the palette,
+    // wrapping around at the ends. If no entry is selected then the first or last is selected.
+    fn cycle_palette_index(&mut self, row: PaletteRow, step: isize) {
+        let len = row.len(&self.palette) as isize;
+        let index = self.drawing_state.palette_indices.get_mut(row);
+        let i = match *index {
+            Some(PaletteIndex::Index(i)) => (i as isize + step).rem_euclid(len),
+            _ if step > 0 => 0,
+            _ => len - 1,
+        };
+        *index = Some(PaletteIndex::Index(i as usize));
+        self.drawing_state.eyedrop_render_cell = None;
+    }
+
+    // The colour selected in the fg or bg row, ignoring opacity
+    fn selected_colour(&self, row: PaletteRow) -> Option<Rgb24> {
+        let (index, colours, custom) = match row {
+            PaletteRow::Bg => (
+                self.drawing_state.palette_indices.bg,
+                &self.palette.bg,
+                self.drawing_state.custom.bg,
+            ),
+            _ => (
+                self.drawing_state.palette_indices.fg,
+                &self.palette.fg,
+                self.drawing_state.custom.fg,
+            ),
+        };
+        match index? {
+            PaletteIndex::None => None,
+            PaletteIndex::Index(i) => Some(colours[i]),
+            PaletteIndex::Custom => Some(custom),
+        }
+    }
+
+    // The fg and bg palettes are separate so each colour is looked up in the other palette, and
+    // becomes the custom colour if it isn't there
+    fn swap_colours(&mut self) {
+        let fg = self.selected_colour(PaletteRow::Fg);
+        let bg = self.selected_colour(PaletteRow::Bg);
+        let drawing_state = &mut self.drawing_state;
+        let indices = &mut drawing_state.palette_indices;
+        let custom = &mut drawing_state.custom;
+        let swapped = |index, colour: Option<Rgb24>, palette: &[Rgb24], custom: &mut Rgb24| {
+            let Some(colour) = colour else {
+                return index;
+            };
+            Some(match palette.iter().position(|&c| c == colour) {
+                Some(i) => PaletteIndex::Index(i),
+                None => {
+                    *custom = colour;
+                    PaletteIndex::Custom
+                }
+            })
+        };
+        let new_fg = swapped(indices.bg, bg, &self.palette.fg, &mut custom.fg);
+        let new_bg = swapped(indices.fg, fg, &self.palette.bg, &mut custom.bg);
+        indices.fg = new_fg;
+        indices.bg = new_bg;
+        std::mem::swap(&mut drawing_state.fg_opacity, &mut drawing_state.bg_opacity);
+        drawing_state.eyedrop_render_cell = None;
     }
 
     fn current_render_cell(&self) -> RenderCell {
@@ -510,84 +873,678 @@ impl AppData {
         self.drawing_state.tools[self.drawing_state.tool_index]
     }
 
+    fn select_tool(&mut self, index: usize) {
+        // finish any text that was being typed with the previous tool
+        self.commit_current_event();
+        self.drawing_state.tool_index = index;
+    }
+
+    // Returns the pop-up to open for actions which open one
+    fn perform_action(&mut self, action: Action) -> Option<PopUp> {
+        match action {
+            Action::Tool(tool) => {
+                if let Some(index) = self.drawing_state.tools.iter().position(|&t| t == tool) {
+                    self.select_tool(index);
+                }
+            }
+            Action::Undo => return self.undo().err().map(PopUp::Error),
+            Action::Redo => return self.redo().err().map(PopUp::Error),
+            Action::Save => return self.save().err().map(PopUp::Error),
+            Action::SaveAs => return Some(PopUp::SaveAs),
+            Action::Export => return self.export().err().map(PopUp::Error),
+            Action::Copy => self.copy_selection(),
+            Action::Cut => self.cut_selection(),
+            Action::Paste => self.paste(),
+            Action::ClearSelection => self.drawing_state.selection = None,
+            Action::PreviousFrame => self.previous_frame(),
+            Action::NextFrame => self.next_frame(),
+            Action::SwapColours => self.swap_colours(),
+            Action::PreviousEntry(row) => self.cycle_palette_index(row, -1),
+            Action::NextEntry(row) => self.cycle_palette_index(row, 1),
+            // the view follows the keyboard cursor, so while it's shown panning moves it instead
+            Action::Pan(delta) if self.drawing_state.cursor.is_some() => self.move_cursor(delta),
+            Action::Pan(delta) => self.pan(delta),
+            Action::MoveCursor(delta) => self.move_cursor(delta),
+            Action::CursorPress => self.toggle_cursor_press(),
+            Action::CanvasSize => return Some(PopUp::CanvasSize),
+            Action::PaletteEditor => return Some(PopUp::PaletteEditor),
+            Action::CharPicker => return Some(PopUp::CharPicker),
+            Action::Help => return Some(PopUp::Help),
+            Action::BrushSmaller => self.resize_brush(-1),
+            Action::BrushLarger => self.resize_brush(1),
+            Action::BrushShape => self.cycle_brush_shape(),
+            Action::BrushFromSelection => self.brush_from_selection(),
+            Action::Lock(channel) => self.drawing_state.locks.toggle(channel),
+        }
+        None
+    }
+
+    fn current_layer(&self) -> &Layer {
+        &self.drawing_state.canvas().layers[self.drawing_state.layer_index]
+    }
+
+    fn start_event(&mut self, coord: Coord) {
+        // clicking away from an in-progress text event commits it
+        self.commit_current_event();
+        self.drawing_state.playing = false;
+        let editable = !self.current_layer().locked;
+        self.drawing_state.current_event = match self.current_tool() {
+            Tool::Select => match self.drawing_state.selection {
+                Some(selection) if selection.contains(coord) => {
+                    editable.then(|| DrawingEvent::move_selection(selection, coord))
+                }
+                _ => {
+                    self.drawing_state.selection = None;
+                    Some(DrawingEvent::select(coord))
+                }
+            },
+            _ if !editable => None,
+            tool => tool.new_event(
+                coord,
+                self.cell_aspect_ratio,
+                &self.drawing_state.brush.brush(),
+            ),
+        };
+    }
+
+    fn commit_history_event(&mut self, event: HistoryEvent) {
+        let frame = self.drawing_state.frame_mut();
+        frame.undo_buffer.commit_event(event, &mut frame.canvas);
+        self.mark_modified();
+        self.clamp_layer_index();
+    }
+
+    fn commit_drawing_event(&mut self, drawing_event: DrawingEvent) {
+        let locks = self.drawing_state.locks;
+        let drawing_event = if locks.any() {
+            drawing_event.locked(locks)
+        } else {
+            drawing_event
+        };
+        let event = DrawingEventWithRenderCell {
+            drawing_event,
+            render_cell: self.current_render_cell(),
+        };
+        self.commit_history_event(HistoryEvent::Drawing {
+            layer_index: self.drawing_state.layer_index,
+            event,
+        });
+    }
+
     fn commit_current_event(&mut self) {
         if let Some(drawing_event) = self.drawing_state.current_event.take() {
-            let event = DrawingEventWithRenderCell {
-                drawing_event,
-                render_cell: self.current_render_cell(),
-            };
-            self.drawing_state.canvas_state.commit_event(&event);
-            self.drawing_state.undo_buffer.commit_event(event);
+            if drawing_event.is_empty() {
+                return;
+            }
+            match &drawing_event {
+                DrawingEvent::Select(select) => {
+                    self.drawing_state.selection = Some(select.selection());
+                    return;
+                }
+                DrawingEvent::Move(move_) => {
+                    self.drawing_state.selection = Some(move_.destination());
+                }
+                _ => (),
+            }
+            self.commit_drawing_event(drawing_event);
         }
     }
 
-    fn undo(&mut self) {
-        self.drawing_state.canvas_state = self.drawing_state.undo_buffer.undo();
+    fn copy_selection(&mut self) {
+        if let Some(selection) = self.drawing_state.selection {
+            self.drawing_state.clipboard = Some(self.current_layer().raster.copy(selection));
+        }
     }
 
-    fn redo(&mut self) {
-        self.drawing_state.canvas_state = self.drawing_state.undo_buffer.redo();
+    // Changing the size or shape switches back from a custom brush so the change takes effect
+    fn resize_brush(&mut self, step: i32) {
+        let brush = &mut self.drawing_state.brush;
+        brush.size = (brush.size as i32 + step).clamp(1, BrushSettings::MAX_SIZE as i32) as u32;
+        brush.use_custom = false;
     }
 
-    fn save(&self) {
-        // TODO handle errors
-        use std::io::Write;
-        let mut file = File::create(self.live_paths.output_path.as_path()).unwrap();
-        let data = bincode::serialize(&self.drawing_state).unwrap();
-        file.write_all(&data).unwrap();
-        println!("wrote to {}", self.live_paths.output_path.to_str().unwrap());
+    fn cycle_brush_shape(&mut self) {
+        let brush = &mut self.drawing_state.brush;
+        brush.shape = match brush.shape {
+            BrushShape::Square => BrushShape::Circle,
+            BrushShape::Circle => BrushShape::Diamond,
+            BrushShape::Diamond => BrushShape::Square,
+        };
+        brush.use_custom = false;
     }
 
-    fn export(&self) {
-        use std::io::Write;
-        let path = "/tmp/x.bin";
-        println!("exporting to {}", path);
-        let mut file = File::create(path).unwrap();
-        let data = bincode::serialize(&self.drawing_state.canvas_state.grid).unwrap();
-        file.write_all(&data).unwrap();
+    fn brush_from_selection(&mut self) {
+        if let Some(selection) = self.drawing_state.selection {
+            let cells = self.current_layer().raster.copy(selection);
+            let brush = &mut self.drawing_state.brush;
+            brush.custom = Some(cells);
+            brush.use_custom = true;
+        }
     }
-}
 
-struct PaletteComponent {
-    ch_label: text::StyledString,
-    fg_label: text::StyledString,
-    bg_label: text::StyledString,
-}
+    fn toggle_custom_brush(&mut self) {
+        let brush = &mut self.drawing_state.brush;
+        brush.use_custom = !brush.use_custom && brush.custom.is_some();
+    }
 
-impl PaletteComponent {
-    fn new() -> Self {
-        Self {
-            ch_label: text::StyledString::plain_text("ch|".to_string()),
-            fg_label: text::StyledString::plain_text("fg|".to_string()),
-            bg_label: text::StyledString::plain_text("bg|".to_string()),
+    fn cut_selection(&mut self) {
+        if self.current_layer().locked {
+            return;
+        }
+        if let Some(selection) = self.drawing_state.selection {
+            self.copy_selection();
+            self.commit_drawing_event(DrawingEvent::cut(selection));
         }
     }
-    fn palette_x_offset(&self) -> i32 {
-        self.ch_label.string.len() as i32
+
+    // Pastes at the mouse if it's over the canvas, otherwise over the current selection. The
+    // pasted cells become the new selection so they can be dragged into place.
+    fn paste(&mut self) {
+        if self.current_layer().locked {
+            return;
+        }
+        if let Some(cells) = self.drawing_state.clipboard.clone() {
+            let top_left = self
+                .drawing_state
+                .canvas_hover
+                .or(self.drawing_state.selection.map(|s| s.top_left))
+                .unwrap_or_else(|| Coord::new(0, 0));
+            self.drawing_state.selection = Some(Selection {
+                top_left,
+                size: cells.size(),
+            });
+            self.commit_drawing_event(DrawingEvent::paste(top_left, cells));
+        }
     }
-    fn preview_offset(&self) -> i32 {
-        2
+
+    // Moves the camera by `delta` cells, keeping the view within the canvas
+    fn pan(&mut self, delta: Coord) {
+        let drawing_state = &mut self.drawing_state;
+        let max = drawing_state
+            .canvas()
+            .size()
+            .saturating_sub(drawing_state.view_size)
+            .to_coord()
+            .unwrap_or_else(|_| Coord::new(0, 0));
+        let camera = drawing_state.camera + delta;
+        drawing_state.camera = Coord::new(camera.x.clamp(0, max.x), camera.y.clamp(0, max.y));
     }
-}
 
-impl Component for PaletteComponent {
-    type Output = ();
-    type State = AppData;
-    fn render(&self, state: &Self::State, ctx: Ctx, fb: &mut FrameBuffer) {
-        {
-            let preview_cell = state.current_render_cell();
-            let preview_cell = preview_cell
-                .with_foreground_option(preview_cell.foreground().map(|c| c.with_a(255)))
-                .with_background_option(preview_cell.background().map(|c| c.with_a(255)));
-            fb.set_cell_relative_to_ctx(ctx, Coord::new(0, 1), 0, preview_cell);
+    // Moves the cursor within the canvas and scrolls to keep it in view. While the cursor is
+    // pressed this extends the current event as dragging the mouse would.
+    fn move_cursor(&mut self, delta: Coord) {
+        let drawing_state = &self.drawing_state;
+        let size = drawing_state.canvas().size();
+        let view_size = drawing_state.view_size;
+        let camera = drawing_state.camera;
+        // the cursor starts under the mouse or in the middle of the view
+        let cursor = drawing_state.cursor.map(|c| c + delta).unwrap_or_else(|| {
+            drawing_state.canvas_hover.unwrap_or_else(|| {
+                camera + Coord::new(view_size.width() as i32 / 2, view_size.height() as i32 / 2)
+            })
+        });
+        let cursor = Coord::new(
+            cursor.x.clamp(0, size.width() as i32 - 1),
+            cursor.y.clamp(0, size.height() as i32 - 1),
+        );
+        let scroll = |cursor: i32, camera: i32, view: u32| {
+            if cursor < camera {
+                cursor - camera
+            } else {
+                (cursor - (camera + view as i32 - 1)).max(0)
+            }
+        };
+        self.pan(Coord::new(
+            scroll(cursor.x, camera.x, view_size.width()),
+            scroll(cursor.y, camera.y, view_size.height()),
+        ));
+        self.drawing_state.cursor = Some(cursor);
+        if self.drawing_state.cursor_pressed {
+            if self.current_tool() == Tool::Eyedrop {
+                self.eyedrop(cursor);
+            } else if let Some(current_event) = self.drawing_state.current_event.as_mut() {
+                current_event.mouse_move(cursor);
+            }
         }
-        let ctx = ctx.add_x(self.preview_offset());
-        self.ch_label.render(&(), ctx, fb);
-        self.fg_label.render(&(), ctx.add_y(1), fb);
-        self.bg_label.render(&(), ctx.add_y(2), fb);
-        let ctx = ctx.add_x(self.palette_x_offset());
-        let hover_style = Style::plain_text().with_background(Rgba32::new_grey(127));
-        let select_style = Style::plain_text()
+    }
+
+    // Space and enter alternately press and release the mouse button at the cursor
+    fn toggle_cursor_press(&mut self) {
+        if self.drawing_state.cursor.is_none() {
+            self.move_cursor(Coord::new(0, 0));
+        }
+        let Some(cursor) = self.drawing_state.cursor else {
+            return;
+        };
+        if self.drawing_state.cursor_pressed {
+            self.drawing_state.cursor_pressed = false;
+            if let Some(current_event) = self.drawing_state.current_event.as_ref() {
+                if current_event.commits_on_mouse_release() {
+                    self.commit_current_event();
+                }
+            }
+            return;
+        }
+        self.drawing_state.cursor_pressed = true;
+        if self.current_tool() == Tool::Eyedrop {
+            self.eyedrop(cursor);
+        } else {
+            self.start_event(cursor);
+            // text events take over the keyboard so there's no way to release them
+            if let Some(DrawingEvent::Text(_)) = self.drawing_state.current_event {
+                self.drawing_state.cursor_pressed = false;
+            }
+        }
+    }
+
+    // Picks up the cell at `coord` to draw with, in place of the palette selection
+    fn eyedrop(&mut self, coord: Coord) {
+        if let Some(&render_cell) = self.drawing_state.canvas().flatten().grid.get(coord) {
+            self.drawing_state.eyedrop_render_cell = Some(render_cell);
+            self.drawing_state.palette_indices.ch = None;
+            self.drawing_state.palette_indices.fg = None;
+            self.drawing_state.palette_indices.bg = None;
+        }
+    }
+
+    fn set_view_size(&mut self, view_size: Size) {
+        self.drawing_state.view_size = view_size;
+        // the canvas or the view may have changed size
+        self.pan(Coord::new(0, 0));
+    }
+
+    fn clamp_layer_index(&mut self) {
+        let max_index = self.drawing_state.canvas().layers.len() - 1;
+        self.drawing_state.layer_index = self.drawing_state.layer_index.min(max_index);
+    }
+
+    // The new layer goes directly above the current layer and becomes the current layer
+    fn add_layer(&mut self) {
+        let index = self.drawing_state.layer_index + 1;
+        let name = format!("Layer {}", self.drawing_state.canvas().layers.len() + 1);
+        self.commit_history_event(HistoryEvent::Layer(LayerEvent::Add { index, name }));
+        self.drawing_state.layer_index = index;
+    }
+
+    fn delete_layer(&mut self) {
+        if self.drawing_state.canvas().layers.len() > 1 {
+            let index = self.drawing_state.layer_index;
+            self.commit_history_event(HistoryEvent::Layer(LayerEvent::Delete { index }));
+        }
+    }
+
+    fn move_layer(&mut self, up: bool) {
+        let from = self.drawing_state.layer_index;
+        let to = if up {
+            from + 1
+        } else if let Some(to) = from.checked_sub(1) {
+            to
+        } else {
+            return;
+        };
+        if to < self.drawing_state.canvas().layers.len() {
+            self.commit_history_event(HistoryEvent::Layer(LayerEvent::Move { from, to }));
+            self.drawing_state.layer_index = to;
+        }
+    }
+
+    fn toggle_layer_visible(&mut self, index: usize) {
+        let visible = !self.drawing_state.canvas().layers[index].visible;
+        self.commit_history_event(HistoryEvent::Layer(LayerEvent::SetVisible {
+            index,
+            visible,
+        }));
+    }
+
+    fn toggle_layer_locked(&mut self, index: usize) {
+        let locked = !self.drawing_state.canvas().layers[index].locked;
+        self.commit_history_event(HistoryEvent::Layer(LayerEvent::SetLocked { index, locked }));
+    }
+
+    fn set_layer_opacity(&mut self, index: usize, opacity: u8) {
+        self.commit_history_event(HistoryEvent::Layer(LayerEvent::SetOpacity {
+            index,
+            opacity,
+        }));
+    }
+
+    fn rename_layer(&mut self, index: usize, name: String) {
+        self.commit_history_event(HistoryEvent::Layer(LayerEvent::Rename { index, name }));
+    }
+
+    // Resizes are applied to every frame at once, so a resize can only be undone or redone when
+    // it's the next step in every frame, otherwise the frames would end up different sizes
+    fn step_history(
+        &mut self,
+        next_event: fn(&UndoBuffer) -> Option<&HistoryEvent>,
+        step: fn(&mut UndoBuffer, &mut Canvas),
+    ) -> Result<(), String> {
+        let resize = |frame: &Frame| match next_event(&frame.undo_buffer) {
+            Some(&HistoryEvent::Resize { size, anchor }) => Some((size, anchor)),
+            _ => None,
+        };
+        if let Some(current) = resize(self.drawing_state.frame()) {
+            let frames = &mut self.drawing_state.document.frames;
+            if frames.iter().any(|frame| resize(frame) != Some(current)) {
+                return Err(
+                    "the canvas resize isn't the next step in every frame so the frames would \
+                     end up different sizes"
+                        .to_string(),
+                );
+            }
+            self.drawing_state.selection = None;
+            for frame in frames.iter_mut() {
+                step(&mut frame.undo_buffer, &mut frame.canvas);
+            }
+        } else {
+            let frame = self.drawing_state.frame_mut();
+            step(&mut frame.undo_buffer, &mut frame.canvas);
+        }
+        self.mark_modified();
+        self.clamp_layer_index();
+        Ok(())
+    }
+
+    fn undo(&mut self) -> Result<(), String> {
+        self.step_history(UndoBuffer::next_undo_event, UndoBuffer::undo)
+    }
+
+    fn redo(&mut self) -> Result<(), String> {
+        self.step_history(UndoBuffer::next_redo_event, UndoBuffer::redo)
+    }
+
+    fn resize_canvas(&mut self, size: Size, anchor: Anchor) {
+        self.commit_current_event();
+        self.drawing_state.selection = None;
+        for frame in self.drawing_state.document.frames.iter_mut() {
+            frame
+                .undo_buffer
+                .commit_event(HistoryEvent::Resize { size, anchor }, &mut frame.canvas);
+        }
+        self.mark_modified();
+    }
+
+    fn select_frame(&mut self, index: usize) {
+        // finish any text that was being typed on the current frame
+        self.commit_current_event();
+        self.drawing_state.frame_index = index;
+        self.clamp_layer_index();
+    }
+
+    fn previous_frame(&mut self) {
+        let num_frames = self.drawing_state.document.frames.len();
+        self.select_frame((self.drawing_state.frame_index + num_frames - 1) % num_frames);
+    }
+
+    fn next_frame(&mut self) {
+        let num_frames = self.drawing_state.document.frames.len();
+        self.select_frame((self.drawing_state.frame_index + 1) % num_frames);
+    }
+
+    // New frames go directly after the current frame and become the current frame
+    fn add_frame(&mut self) {
+        let canvas = Canvas::new(self.drawing_state.canvas().size());
+        let index = self.drawing_state.frame_index + 1;
+        self.drawing_state
+            .document
+            .frames
+            .insert(index, Frame::new(canvas));
+        self.mark_modified();
+        self.select_frame(index);
+    }
+
+    fn duplicate_frame(&mut self) {
+        let canvas = self.drawing_state.canvas().clone();
+        let index = self.drawing_state.frame_index + 1;
+        self.drawing_state
+            .document
+            .frames
+            .insert(index, Frame::new(canvas));
+        self.mark_modified();
+        self.select_frame(index);
+    }
+
+    fn delete_frame(&mut self) {
+        if self.drawing_state.document.frames.len() > 1 {
+            self.drawing_state.current_event = None;
+            self.drawing_state
+                .document
+                .frames
+                .remove(self.drawing_state.frame_index);
+            self.mark_modified();
+            let index = self
+                .drawing_state
+                .frame_index
+                .min(self.drawing_state.document.frames.len() - 1);
+            self.select_frame(index);
+        }
+    }
+
+    fn move_frame(&mut self, later: bool) {
+        let from = self.drawing_state.frame_index;
+        let to = if later {
+            from + 1
+        } else if let Some(to) = from.checked_sub(1) {
+            to
+        } else {
+            return;
+        };
+        if to < self.drawing_state.document.frames.len() {
+            self.commit_current_event();
+            self.drawing_state.document.frames.swap(from, to);
+            self.drawing_state.frame_index = to;
+            self.mark_modified();
+        }
+    }
+
+    fn toggle_playing(&mut self) {
+        self.commit_current_event();
+        self.drawing_state.playing = !self.drawing_state.playing;
+        self.drawing_state.playback_time = Duration::ZERO;
+    }
+
+    fn tick(&mut self, duration: Duration) {
+        if !self.drawing_state.playing {
+            return;
+        }
+        let frame_duration = Duration::from_secs(1) / self.drawing_state.document.fps.max(1);
+        self.drawing_state.playback_time += duration;
+        while self.drawing_state.playback_time >= frame_duration {
+            self.drawing_state.playback_time -= frame_duration;
+            self.drawing_state.frame_index =
+                (self.drawing_state.frame_index + 1) % self.drawing_state.document.frames.len();
+        }
+        self.clamp_layer_index();
+    }
+
+    // The visible contents of the neighbouring frames at reduced opacity, if onion skinning is
+    // enabled
+    fn onion_skin(&self) -> Option<Raster> {
+        let onion_skin = &self.drawing_state.onion_skin;
+        let frame_index = self.drawing_state.frame_index;
+        let mut neighbours = Vec::new();
+        if onion_skin.previous && frame_index > 0 {
+            neighbours.push(frame_index - 1);
+        }
+        if onion_skin.next && frame_index + 1 < self.drawing_state.document.frames.len() {
+            neighbours.push(frame_index + 1);
+        }
+        if neighbours.is_empty() || self.drawing_state.playing {
+            return None;
+        }
+        let size = self.drawing_state.canvas().size();
+        let mut ret = Raster::new_transparent(size);
+        for index in neighbours {
+            let frame_raster = self.drawing_state.document.frames[index]
+                .canvas
+                .flatten_onto(Raster::new_transparent(size));
+            for (coord, cell) in ret.grid.enumerate_mut() {
+                if let Some(&frame_cell) = frame_raster.grid.get(coord) {
+                    let frame_cell = Raster::scale_alpha(frame_cell, onion_skin.opacity);
+                    *cell = Raster::stack_render_cells(*cell, frame_cell);
+                }
+            }
+        }
+        Some(ret)
+    }
+
+    fn mark_modified(&mut self) {
+        self.drawing_state.modified = true;
+        self.drawing_state.status = None;
+        self.autosave.pending = true;
+    }
+
+    fn save_to(&mut self, path: &Path) -> Result<(), String> {
+        let palette_path = &self.live_paths.palette_path;
+        self.drawing_state.document.palette_path =
+            Some(fs::canonicalize(palette_path).unwrap_or_else(|_| palette_path.clone()));
+        self.drawing_state
+            .document
+            .save(path, self.drawing_state.frame_index)
+    }
+
+    // Saving to a foreign format is refused if it would discard anything other than the undo
+    // history, so work is only lost by an explicit conversion
+    fn save(&mut self) -> Result<(), String> {
+        let output_path = self.live_paths.output_path.clone();
+        let discarded = self
+            .drawing_state
+            .document
+            .discarded_by_saving(&output_path);
+        if !discarded.is_empty() {
+            return Err(format!(
+                "saving to \"{}\" would discard {} (save as a .tp file instead, or use the convert command)",
+                output_path.display(),
+                discarded.join(", ")
+            ));
+        }
+        self.save_to(&output_path)?;
+        self.drawing_state.modified = false;
+        let status = if Document::saves_history(&output_path) {
+            "saved"
+        } else {
+            "saved without undo history"
+        };
+        self.drawing_state.status = Some(status.to_string());
+        // the recovery file is no longer needed once the work is saved
+        self.remove_recovery_file();
+        Ok(())
+    }
+
+    // The recovery file goes next to the output file. It always uses the native format, which
+    // unlike foreign formats keeps every frame and layer.
+    fn recovery_path(&self) -> PathBuf {
+        let output_path = &self.live_paths.output_path;
+        let mut file_name = output_path.file_name().unwrap_or_default().to_os_string();
+        file_name.push(".recovery");
+        output_path.with_file_name(file_name)
+    }
+
+    fn remove_recovery_file(&mut self) {
+        let _ = fs::remove_file(self.recovery_path());
+        self.autosave.pending = false;
+    }
+
+    // Whether there is a recovery file which is newer than the output file, meaning the app
+    // didn't exit cleanly after changes were last autosaved
+    fn recovery_available(&self) -> bool {
+        match modified_time(&self.recovery_path()) {
+            Some(recovery) => {
+                modified_time(&self.live_paths.output_path).is_none_or(|output| recovery > output)
+            }
+            None => false,
+        }
+    }
+
+    // Replaces the document with the one in the recovery file. It is left marked as modified
+    // since it hasn't been saved to the output file.
+    fn restore_recovery_file(&mut self) -> Result<(), String> {
+        let document = Document::load(self.recovery_path())?;
+        self.drawing_state = DrawingState::new(document);
+        self.mark_modified();
+        Ok(())
+    }
+
+    fn check_autosave(&mut self, duration: Duration) -> Result<(), String> {
+        self.autosave.time_since_save += duration;
+        if !self.autosave.pending || self.autosave.time_since_save < Autosave::PERIOD {
+            return Ok(());
+        }
+        self.autosave.time_since_save = Duration::ZERO;
+        // errors aren't retried until the next change so they aren't reported repeatedly
+        self.autosave.pending = false;
+        let recovery_path = self.recovery_path();
+        self.save_to(&recovery_path)
+            .map_err(|e| format!("failed to autosave ({})", e))
+    }
+
+    // Saves to a new path which is used for later saves. The old path is kept if saving fails.
+    fn save_as(&mut self, output_path: PathBuf) -> Result<(), String> {
+        let previous_path = std::mem::replace(&mut self.live_paths.output_path, output_path);
+        self.save()
+            .inspect_err(|_| self.live_paths.output_path = previous_path)
+    }
+
+    // Writes the current frame to the export path, which defaults to ANSI art next to the output
+    // file
+    fn export(&mut self) -> Result<(), String> {
+        let path = self.live_paths.export_path.as_path();
+        let grid = &self.drawing_state.canvas().flatten().grid;
+        export::export_to_path(grid, path, self.export_cell_size_px)?;
+        self.drawing_state.status = Some(format!("exported to {}", file_name(path)));
+        Ok(())
+    }
+}
+
+struct PaletteComponent {
+    ch_label: text::StyledString,
+    fg_label: text::StyledString,
+    bg_label: text::StyledString,
+}
+
+impl PaletteComponent {
+    fn new() -> Self {
+        Self {
+            ch_label: text::StyledString::plain_text("ch|".to_string()),
+            fg_label: text::StyledString::plain_text("fg|".to_string()),
+            bg_label: text::StyledString::plain_text("bg|".to_string()),
+        }
+    }
+    fn palette_x_offset(&self) -> i32 {
+        self.ch_label.string.len() as i32
+    }
+    fn preview_offset(&self) -> i32 {
+        2
+    }
+    // The custom character or colour is shown a cell to the right of the end of its row. This is
+    // relative to the first entry in the row.
+    fn custom_x(len: usize) -> i32 {
+        len as i32 + 1
+    }
+}
+
+fn black_foreground(Rgb24 { r, g, b }: Rgb24) -> bool {
+    r as u16 + g as u16 + b as u16 > 320
+}
+
+impl Component for PaletteComponent {
+    type Output = Option<PopUp>;
+    type State = AppData;
+    fn render(&self, state: &Self::State, ctx: Ctx, fb: &mut FrameBuffer) {
+        {
+            let preview_cell = state.current_render_cell();
+            let preview_cell = preview_cell
+                .with_foreground_option(preview_cell.foreground().map(|c| c.with_a(255)))
+                .with_background_option(preview_cell.background().map(|c| c.with_a(255)));
+            fb.set_cell_relative_to_ctx(ctx, Coord::new(0, 1), 0, preview_cell);
+        }
+        let ctx = ctx.add_x(self.preview_offset());
+        self.ch_label.render(&(), ctx, fb);
+        self.fg_label.render(&(), ctx.add_y(1), fb);
+        self.bg_label.render(&(), ctx.add_y(2), fb);
+        let ctx = ctx.add_x(self.palette_x_offset());
+        let hover_style = Style::plain_text().with_background(Rgba32::new_grey(127));
+        let select_style = Style::plain_text()
             .with_foreground(Rgba32::new_grey(0))
             .with_background(Rgba32::new_grey(255));
         {
@@ -663,8 +1620,23 @@ impl Component for PaletteComponent {
                 },
             );
         }
-        fn black_foreground(Rgb24 { r, g, b }: Rgb24) -> bool {
-            r as u16 + g as u16 + b as u16 > 320
+        {
+            // the custom character has a grey background when it isn't selected so it stands out
+            // as a button for opening the character picker
+            let style = if state.drawing_state.palette_indices.ch == Some(PaletteIndex::Custom) {
+                select_style
+            } else {
+                hover_style
+            };
+            fb.set_cell_relative_to_ctx(
+                ctx,
+                Coord::new(Self::custom_x(state.palette.ch.len()), 0),
+                0,
+                RenderCell {
+                    character: Some(state.drawing_state.custom.ch),
+                    style,
+                },
+            );
         }
         for (i, &fg) in state.palette.fg.iter().enumerate() {
             let character =
@@ -692,6 +1664,40 @@ impl Component for PaletteComponent {
                 },
             );
         }
+        for (row, y) in [(PaletteRow::Fg, 1), (PaletteRow::Bg, 2)] {
+            let (index, colour) = match row {
+                PaletteRow::Fg => (
+                    state.drawing_state.palette_indices.fg,
+                    state.drawing_state.custom.fg,
+                ),
+                _ => (
+                    state.drawing_state.palette_indices.bg,
+                    state.drawing_state.custom.bg,
+                ),
+            };
+            let foreground = if black_foreground(colour) {
+                Rgba32::new_grey(0)
+            } else {
+                Rgba32::new_grey(255)
+            };
+            // '?' marks the custom colour when it isn't selected
+            let character = if index == Some(PaletteIndex::Custom) {
+                '*'
+            } else {
+                '?'
+            };
+            fb.set_cell_relative_to_ctx(
+                ctx,
+                Coord::new(Self::custom_x(row.len(&state.palette)), y),
+                0,
+                RenderCell {
+                    character: Some(character),
+                    style: Style::default()
+                        .with_background(colour.to_rgba32(255))
+                        .with_foreground(foreground),
+                },
+            );
+        }
         for (i, &bg) in state.palette.bg.iter().enumerate() {
             let character =
                 if Some(PaletteIndex::Index(i)) == state.drawing_state.palette_indices.bg {
@@ -757,6 +1763,19 @@ impl Component for PaletteComponent {
                     button: MouseButton::Left,
                     coord,
                 } => {
+                    // the 'x' column comes before the first entry
+                    let custom_coord = |row: PaletteRow, y| {
+                        Coord::new(Self::custom_x(row.len(&state.palette)) + 1, y)
+                    };
+                    let relative_coord = ctx.bounding_box.coord_absolute_to_relative(coord);
+                    if relative_coord == Some(custom_coord(PaletteRow::Ch, 0)) {
+                        return Some(PopUp::CharPicker);
+                    }
+                    for (row, y) in [(PaletteRow::Fg, 1), (PaletteRow::Bg, 2)] {
+                        if relative_coord == Some(custom_coord(row, y)) {
+                            return Some(PopUp::ColourPicker(row));
+                        }
+                    }
                     if let Some(coord) = ch_bb.coord_absolute_to_relative(coord) {
                         state.drawing_state.palette_indices.ch = Some(coord_to_index(coord));
                     }
@@ -771,6 +1790,7 @@ impl Component for PaletteComponent {
                 _ => (),
             }
         }
+        None
     }
     fn size(&self, _state: &Self::State, _ctx: Ctx) -> Size {
         Size::new(40, 3)
@@ -842,6 +1862,68 @@ impl Component for OpacityComponent {
     }
 }
 
+struct LocksComponent;
+
+impl LocksComponent {
+    // the second column of toggles
+    const RIGHT_X: i32 = 6;
+
+    // The position of each channel's toggle
+    fn channel_coord(channel: Channel) -> Coord {
+        match channel {
+            Channel::Ch => Coord::new(0, 0),
+            Channel::Fg => Coord::new(0, 1),
+            Channel::Bg => Coord::new(0, 2),
+            Channel::Bold => Coord::new(Self::RIGHT_X, 0),
+            Channel::Underline => Coord::new(Self::RIGHT_X, 1),
+        }
+    }
+}
+
+impl Component for LocksComponent {
+    type Output = ();
+    type State = AppData;
+    fn render(&self, state: &Self::State, ctx: Ctx, fb: &mut FrameBuffer) {
+        let locks = &state.drawing_state.locks;
+        for channel in Channel::ALL {
+            let string = format!(
+                "{} {}",
+                if locks.get(channel) { 'L' } else { '-' },
+                channel.name()
+            );
+            text::StyledString::plain_text(string).render(
+                &(),
+                ctx.add_offset(Self::channel_coord(channel)),
+                fb,
+            );
+        }
+    }
+    fn update(&mut self, state: &mut Self::State, ctx: Ctx, event: Event) -> Self::Output {
+        if let Some(MouseInput::MousePress {
+            button: MouseButton::Left,
+            coord,
+        }) = event.mouse_input()
+        {
+            if let Some(coord) = ctx.bounding_box.coord_absolute_to_relative(coord) {
+                let x = if coord.x < Self::RIGHT_X {
+                    0
+                } else {
+                    Self::RIGHT_X
+                };
+                if let Some(channel) = Channel::ALL
+                    .into_iter()
+                    .find(|&channel| Self::channel_coord(channel) == Coord::new(x, coord.y))
+                {
+                    state.drawing_state.locks.toggle(channel);
+                }
+            }
+        }
+    }
+    fn size(&self, _state: &Self::State, _ctx: Ctx) -> Size {
+        Size::new(Self::RIGHT_X as u32 + "- underline".len() as u32, 3)
+    }
+}
+
 struct ToolsComponent;
 
 impl Component for ToolsComponent {
@@ -883,7 +1965,7 @@ impl Component for ToolsComponent {
                     coord,
                 } => {
                     if let Some(coord) = ctx.bounding_box.coord_absolute_to_relative(coord) {
-                        state.drawing_state.tool_index = coord.y as usize;
+                        state.select_tool(coord.y as usize);
                     }
                 }
                 _ => (),
@@ -891,85 +1973,477 @@ impl Component for ToolsComponent {
         }
     }
     fn size(&self, state: &Self::State, _ctx: Ctx) -> Size {
-        Size::new(10, state.drawing_state.tools.len() as u32)
+        // leave room for the asterisks either side of the selected tool's name
+        let width = state
+            .drawing_state
+            .tools
+            .iter()
+            .map(|tool| tool.to_string().len() as u32 + 2)
+            .max()
+            .unwrap_or(0)
+            .max(10);
+        Size::new(width, state.drawing_state.tools.len() as u32)
     }
 }
 
-struct CanvasComponent;
+struct LayersComponent;
 
-impl Component for CanvasComponent {
-    type Output = ();
+impl LayersComponent {
+    const NAME_WIDTH: usize = 11;
+    const VISIBLE_X: i32 = 0;
+    const LOCKED_X: i32 = 2;
+    const OPACITY_X: i32 = 16;
+    const WIDTH: u32 = 19;
+    const BUTTONS: &'static str = "+ - ^ v Rename";
+}
+
+impl Component for LayersComponent {
+    type Output = Option<PopUp>;
     type State = AppData;
     fn render(&self, state: &Self::State, ctx: Ctx, fb: &mut FrameBuffer) {
-        for (coord, &cell) in state.drawing_state.canvas_state.grid.enumerate() {
-            let mut cell = cell;
-            if Some(coord) == state.drawing_state.canvas_hover {
-                cell.style.background = if let Some(background) = cell.background() {
-                    Some(background.saturating_scalar_mul_div(4, 3))
-                } else {
-                    Some(Rgba32::new_grey(127))
-                };
-            }
-            fb.set_cell_relative_to_ctx(ctx, coord, 0, cell);
-        }
-        if let Some(current_event) = state.drawing_state.current_event.as_ref() {
-            current_event.preview(
-                &state.drawing_state.canvas_state,
-                state.current_render_cell(),
-                ctx.add_depth(1),
-                fb,
+        let layers = &state.drawing_state.canvas().layers;
+        let hover_style = Style::plain_text().with_background(Rgba32::new_grey(127));
+        let select_style = Style::plain_text()
+            .with_foreground(Rgba32::new_grey(0))
+            .with_background(Rgba32::new_grey(255));
+        // the top layer is listed first
+        for (i, layer) in layers.iter().enumerate() {
+            let y = (layers.len() - 1 - i) as i32;
+            let style = if i == state.drawing_state.layer_index {
+                select_style
+            } else if Some(i) == state.drawing_state.layer_hover {
+                hover_style
+            } else {
+                Style::plain_text()
+            };
+            let string = format!(
+                "{} {} {:<name_width$} {:>3}",
+                if layer.visible { 'o' } else { '-' },
+                if layer.locked { 'L' } else { '-' },
+                layer
+                    .name
+                    .chars()
+                    .take(Self::NAME_WIDTH)
+                    .collect::<String>(),
+                layer.opacity,
+                name_width = Self::NAME_WIDTH,
             );
+            text::StyledString { string, style }.render(&(), ctx.add_y(y), fb);
         }
+        text::StyledString::plain_text(Self::BUTTONS.to_string()).render(
+            &(),
+            ctx.add_y(layers.len() as i32),
+            fb,
+        );
     }
     fn update(&mut self, state: &mut Self::State, ctx: Ctx, event: Event) -> Self::Output {
+        let num_layers = state.drawing_state.canvas().layers.len();
+        let row_to_index = |y: i32| {
+            if y >= 0 && (y as usize) < num_layers {
+                Some(num_layers - 1 - y as usize)
+            } else {
+                None
+            }
+        };
         if let Some(mouse_input) = event.mouse_input() {
-            state.drawing_state.canvas_hover = ctx
-                .bounding_box
-                .coord_absolute_to_relative(mouse_input.coord());
-            if state.current_tool() == Tool::Eyedrop {
-                match mouse_input {
-                    MouseInput::MousePress {
-                        button: MouseButton::Left,
-                        coord,
-                    }
-                    | MouseInput::MouseMove {
-                        button: Some(MouseButton::Left),
-                        coord,
-                    } => {
-                        if let Some(coord) = ctx.bounding_box.coord_absolute_to_relative(coord) {
-                            if let Some(&render_cell) =
-                                state.drawing_state.canvas_state.grid.get(coord)
-                            {
-                                state.drawing_state.eyedrop_render_cell = Some(render_cell);
-                                state.drawing_state.palette_indices.ch = None;
-                                state.drawing_state.palette_indices.fg = None;
-                                state.drawing_state.palette_indices.bg = None;
+            match mouse_input {
+                MouseInput::MouseMove { coord, .. } => {
+                    state.drawing_state.layer_hover = ctx
+                        .bounding_box
+                        .coord_absolute_to_relative(coord)
+                        .and_then(|c| row_to_index(c.y));
+                }
+                MouseInput::MousePress {
+                    button: MouseButton::Left,
+                    coord,
+                } => {
+                    if let Some(coord) = ctx.bounding_box.coord_absolute_to_relative(coord) {
+                        // finish any text that was being typed on the current layer
+                        state.commit_current_event();
+                        if let Some(index) = row_to_index(coord.y) {
+                            match coord.x {
+                                Self::VISIBLE_X => state.toggle_layer_visible(index),
+                                Self::LOCKED_X => state.toggle_layer_locked(index),
+                                x if x >= Self::OPACITY_X => {
+                                    return Some(PopUp::LayerOpacity(index));
+                                }
+                                _ => state.drawing_state.layer_index = index,
+                            }
+                        } else if coord.y as usize == num_layers {
+                            match coord.x {
+                                0 => state.add_layer(),
+                                2 => state.delete_layer(),
+                                4 => state.move_layer(true),
+                                6 => state.move_layer(false),
+                                8..=13 => {
+                                    return Some(PopUp::RenameLayer(
+                                        state.drawing_state.layer_index,
+                                    ));
+                                }
+                                _ => (),
                             }
                         }
                     }
+                }
+                _ => (),
+            }
+        }
+        None
+    }
+    fn size(&self, state: &Self::State, _ctx: Ctx) -> Size {
+        Size::new(
+            Self::WIDTH,
+            state.drawing_state.canvas().layers.len() as u32 + 1,
+        )
+    }
+}
+
+struct TimelineComponent;
+
+impl TimelineComponent {
+    const WIDTH: u32 = 19;
+    const EDIT_BUTTONS: &'static str = "+ Dup Del [ ]";
+    const ONION_LABEL: &'static str = "Onion ";
+
+    fn frame_label(state: &AppData) -> String {
+        format!(
+            "< Frame {}/{} >",
+            state.drawing_state.frame_index + 1,
+            state.drawing_state.document.frames.len()
+        )
+    }
+
+    fn playback_label(state: &AppData) -> String {
+        let play = if state.drawing_state.playing {
+            "Stop"
+        } else {
+            "Play"
+        };
+        format!("{} {}fps", play, state.drawing_state.document.fps)
+    }
+}
+
+impl Component for TimelineComponent {
+    type Output = Option<PopUp>;
+    type State = AppData;
+    fn render(&self, state: &Self::State, ctx: Ctx, fb: &mut FrameBuffer) {
+        text::StyledString::plain_text(Self::frame_label(state)).render(&(), ctx, fb);
+        text::StyledString::plain_text(Self::EDIT_BUTTONS.to_string()).render(
+            &(),
+            ctx.add_y(1),
+            fb,
+        );
+        text::StyledString::plain_text(Self::playback_label(state)).render(&(), ctx.add_y(2), fb);
+        let onion_skin = &state.drawing_state.onion_skin;
+        let toggle_style = |enabled: bool| {
+            if enabled {
+                Style::plain_text()
+            } else {
+                Style::plain_text().with_foreground(Rgba32::new_grey(63))
+            }
+        };
+        text::Text::new(vec![
+            text::StyledString::plain_text(Self::ONION_LABEL.to_string()),
+            text::StyledString {
+                string: "<".to_string(),
+                style: toggle_style(onion_skin.previous),
+            },
+            text::StyledString::plain_text(" ".to_string()),
+            text::StyledString {
+                string: ">".to_string(),
+                style: toggle_style(onion_skin.next),
+            },
+            text::StyledString::plain_text(format!(" {}", onion_skin.opacity)),
+        ])
+        .render(&(), ctx.add_y(3), fb);
+    }
+    fn update(&mut self, state: &mut Self::State, ctx: Ctx, event: Event) -> Self::Output {
+        if let Some(MouseInput::MousePress {
+            button: MouseButton::Left,
+            coord,
+        }) = event.mouse_input()
+        {
+            if let Some(Coord { x, y }) = ctx.bounding_box.coord_absolute_to_relative(coord) {
+                let onion_x = Self::ONION_LABEL.len() as i32;
+                match y {
+                    0 => {
+                        if x == 0 {
+                            state.previous_frame();
+                        } else if x == Self::frame_label(state).len() as i32 - 1 {
+                            state.next_frame();
+                        }
+                    }
+                    1 => match x {
+                        0 => state.add_frame(),
+                        2..=4 => state.duplicate_frame(),
+                        // frames have their own history so deleting one can't be undone
+                        6..=8 if state.drawing_state.document.frames.len() > 1 => {
+                            return Some(PopUp::DeleteFrame)
+                        }
+                        10 => state.move_frame(false),
+                        12 => state.move_frame(true),
+                        _ => (),
+                    },
+                    2 => match x {
+                        0..=3 => state.toggle_playing(),
+                        x if x >= 5 => return Some(PopUp::Fps),
+                        _ => (),
+                    },
+                    3 => {
+                        let onion_skin = &mut state.drawing_state.onion_skin;
+                        if x == onion_x {
+                            onion_skin.previous = !onion_skin.previous;
+                        } else if x == onion_x + 2 {
+                            onion_skin.next = !onion_skin.next;
+                        } else if x >= onion_x + 4 {
+                            return Some(PopUp::OnionSkinOpacity);
+                        }
+                    }
                     _ => (),
                 }
+            }
+        }
+        None
+    }
+    fn size(&self, _state: &Self::State, _ctx: Ctx) -> Size {
+        Size::new(Self::WIDTH, 4)
+    }
+}
+
+struct BrushComponent;
+
+impl BrushComponent {
+    const WIDTH: u32 = 19;
+    const CUSTOM_LABEL: &'static str = "Custom";
+    const SET_BUTTON: &'static str = "Set";
+
+    fn size_label(state: &AppData) -> String {
+        format!("< Size {} >", state.drawing_state.brush.size)
+    }
+
+    fn shape_label(state: &AppData) -> &'static str {
+        match state.drawing_state.brush.shape {
+            BrushShape::Square => "Square",
+            BrushShape::Circle => "Circle",
+            BrushShape::Diamond => "Diamond",
+        }
+    }
+}
+
+impl Component for BrushComponent {
+    type Output = ();
+    type State = AppData;
+    fn render(&self, state: &Self::State, ctx: Ctx, fb: &mut FrameBuffer) {
+        let brush = &state.drawing_state.brush;
+        let enabled_style = |enabled: bool| {
+            if enabled {
+                Style::plain_text()
             } else {
+                Style::plain_text().with_foreground(Rgba32::new_grey(63))
+            }
+        };
+        let shape_style = enabled_style(!brush.use_custom);
+        text::StyledString {
+            string: Self::size_label(state),
+            style: shape_style,
+        }
+        .render(&(), ctx, fb);
+        text::StyledString {
+            string: Self::shape_label(state).to_string(),
+            style: shape_style,
+        }
+        .render(&(), ctx.add_y(1), fb);
+        let custom_size = match brush.custom.as_ref() {
+            Some(cells) => format!(" {}x{}", cells.width(), cells.height()),
+            None => String::new(),
+        };
+        text::Text::new(vec![
+            text::StyledString {
+                string: Self::CUSTOM_LABEL.to_string(),
+                style: enabled_style(brush.use_custom),
+            },
+            text::StyledString::plain_text(format!(" {}{}", Self::SET_BUTTON, custom_size)),
+        ])
+        .render(&(), ctx.add_y(2), fb);
+    }
+    fn update(&mut self, state: &mut Self::State, ctx: Ctx, event: Event) -> Self::Output {
+        if let Some(MouseInput::MousePress {
+            button: MouseButton::Left,
+            coord,
+        }) = event.mouse_input()
+        {
+            if let Some(Coord { x, y }) = ctx.bounding_box.coord_absolute_to_relative(coord) {
+                let set_x = Self::CUSTOM_LABEL.len() as i32 + 1;
+                match y {
+                    0 => {
+                        if x == 0 {
+                            state.resize_brush(-1);
+                        } else if x == Self::size_label(state).len() as i32 - 1 {
+                            state.resize_brush(1);
+                        }
+                    }
+                    1 => state.cycle_brush_shape(),
+                    2 => {
+                        if x < set_x - 1 {
+                            state.toggle_custom_brush();
+                        } else if x >= set_x && x < set_x + Self::SET_BUTTON.len() as i32 {
+                            state.brush_from_selection();
+                        }
+                    }
+                    _ => (),
+                }
+            }
+        }
+    }
+    fn size(&self, _state: &Self::State, _ctx: Ctx) -> Size {
+        Size::new(Self::WIDTH, 3)
+    }
+}
+
+struct CanvasComponent;
+
+impl CanvasComponent {
+    // how far each step of the scroll wheel pans the view
+    const SCROLL_CELLS: i32 = 3;
+}
+
+impl Component for CanvasComponent {
+    type Output = ();
+    type State = AppData;
+    fn render(&self, state: &Self::State, ctx: Ctx, fb: &mut FrameBuffer) {
+        // while a selection is being moved its preview shows where it will end up
+        let selection = match state.drawing_state.current_event {
+            Some(DrawingEvent::Move(_)) => None,
+            _ => state.drawing_state.selection,
+        };
+        let composite = state.drawing_state.canvas().flatten();
+        let onion_skin = state.onion_skin();
+        let camera = state.drawing_state.camera;
+        // the pencil and erase tools highlight the cells their brush will cover
+        let hover_coords = match state.drawing_state.canvas_hover {
+            Some(hover) => match state.current_tool() {
+                Tool::Pencil | Tool::Erase => state
+                    .drawing_state
+                    .brush
+                    .brush()
+                    .footprint()
+                    .into_iter()
+                    .map(|offset| hover + offset)
+                    .collect(),
+                _ => vec![hover],
+            },
+            None => Vec::new(),
+        };
+        for view_coord in self.size(state, ctx).coord_iter_row_major() {
+            let coord = view_coord + camera;
+            let Some(&cell) = composite.grid.get(coord) else {
+                continue;
+            };
+            let mut cell = cell;
+            // neighbouring frames only show through cells with no character
+            if cell.character.is_none() {
+                if let Some(&onion_cell) = onion_skin.as_ref().and_then(|r| r.grid.get(coord)) {
+                    if onion_cell.character.is_some() {
+                        cell = Raster::stack_render_cells(cell, onion_cell);
+                    }
+                }
+            }
+            if selection.map(|s| s.contains(coord)).unwrap_or(false) {
+                cell = Selection::highlight(cell);
+            }
+            if hover_coords.contains(&coord) {
+                cell.style.background = if let Some(background) = cell.background() {
+                    Some(background.saturating_scalar_mul_div(4, 3))
+                } else {
+                    Some(Rgba32::new_grey(127))
+                };
+            }
+            fb.set_cell_relative_to_ctx(ctx, view_coord, 0, cell);
+            // the cursor is drawn above any preview with its colours swapped
+            if Some(coord) == state.drawing_state.cursor {
+                let cursor_cell = RenderCell {
+                    character: cell.character,
+                    style: Style::default()
+                        .with_foreground(cell.background().unwrap_or(Rgba32::new_grey(0)))
+                        .with_background(cell.foreground().unwrap_or(Rgba32::new_grey(255))),
+                };
+                fb.set_cell_relative_to_ctx(ctx, view_coord, 2, cursor_cell);
+            }
+        }
+        if let Some(current_event) = state.drawing_state.current_event.as_ref() {
+            current_event.preview(
+                state.drawing_state.canvas(),
+                state.drawing_state.layer_index,
+                &composite,
+                state.drawing_state.locks.mask(state.current_render_cell()),
+                CanvasView {
+                    ctx: ctx.add_depth(1),
+                    offset: camera,
+                },
+                fb,
+            );
+        }
+    }
+    fn update(&mut self, state: &mut Self::State, ctx: Ctx, event: Event) -> Self::Output {
+        if let Some(mouse_input) = event.mouse_input() {
+            let camera = state.drawing_state.camera;
+            // converts a mouse coord to a canvas coord
+            let to_canvas = |coord| {
+                ctx.bounding_box
+                    .coord_absolute_to_relative(coord)
+                    .map(|coord| coord + camera)
+            };
+            state.drawing_state.canvas_hover = to_canvas(mouse_input.coord());
+            // clicking the canvas switches back from drawing with the keyboard
+            if let MouseInput::MousePress { .. } = mouse_input {
+                state.drawing_state.cursor = None;
+                state.drawing_state.cursor_pressed = false;
+            }
+            if let MouseInput::MouseScroll { direction, .. } = mouse_input {
+                let delta = match direction {
+                    ScrollDirection::Up => Coord::new(0, -1),
+                    ScrollDirection::Down => Coord::new(0, 1),
+                    ScrollDirection::Left => Coord::new(-1, 0),
+                    ScrollDirection::Right => Coord::new(1, 0),
+                };
+                state.pan(delta * Self::SCROLL_CELLS);
+            } else if let MouseInput::MousePress {
+                button: MouseButton::Middle,
+                coord,
+            } = mouse_input
+            {
+                state.drawing_state.pan_coord = Some(coord);
+            } else if state.current_tool() == Tool::Eyedrop {
                 match mouse_input {
                     MouseInput::MousePress {
                         button: MouseButton::Left,
                         coord,
+                    }
+                    | MouseInput::MouseMove {
+                        button: Some(MouseButton::Left),
+                        coord,
                     } => {
-                        if let Some(coord) = ctx.bounding_box.coord_absolute_to_relative(coord) {
-                            state.drawing_state.current_event =
-                                state.current_tool().new_event(coord);
+                        if let Some(coord) = to_canvas(coord) {
+                            state.eyedrop(coord);
                         }
                     }
                     _ => (),
                 }
+            } else if let MouseInput::MousePress {
+                button: MouseButton::Left,
+                coord,
+            } = mouse_input
+            {
+                if let Some(coord) = to_canvas(coord) {
+                    state.start_event(coord);
+                }
             }
         }
     }
     fn size(&self, state: &Self::State, ctx: Ctx) -> Size {
         state
             .drawing_state
-            .canvas_state
-            .grid
+            .canvas()
             .size()
             .pairwise_min(ctx.bounding_box.size())
     }
@@ -978,14 +2452,22 @@ impl Component for CanvasComponent {
 struct GuiComponent {
     palette: Border<PaletteComponent>,
     opacity: Border<OpacityComponent>,
+    locks: Border<LocksComponent>,
     tools: Border<ToolsComponent>,
+    layers: Border<LayersComponent>,
+    timeline: Border<TimelineComponent>,
+    brush: Border<BrushComponent>,
     canvas: Border<CanvasComponent>,
 }
 
 struct GuiChildCtxs<'a> {
     palette: Ctx<'a>,
     opacity: Ctx<'a>,
+    locks: Ctx<'a>,
     tools: Ctx<'a>,
+    layers: Ctx<'a>,
+    timeline: Ctx<'a>,
+    brush: Ctx<'a>,
     canvas: Ctx<'a>,
 }
 
@@ -1009,35 +2491,132 @@ impl GuiComponent {
     fn new() -> Self {
         let palette = Self::border(PaletteComponent::new(), "Palette");
         let opacity = Self::border(OpacityComponent::new(), "Opacity");
+        let locks = Self::border(LocksComponent, "Lock");
         let tools = Self::border(ToolsComponent, "Tools");
+        let layers = Self::border(LayersComponent, "Layers");
+        let timeline = Self::border(TimelineComponent, "Timeline");
+        let brush = Self::border(BrushComponent, "Brush");
         let canvas = Self::border(CanvasComponent, "Canvas");
         Self {
             palette,
             opacity,
+            locks,
             tools,
+            layers,
+            timeline,
+            brush,
             canvas,
         }
     }
 
+    // The canvas title shows the file being edited, whether it has unsaved changes and the result
+    // of the last save or export
+    fn canvas_title(state: &AppData) -> String {
+        let file_name = file_name(&state.live_paths.output_path);
+        let mut notes = Vec::new();
+        if state.drawing_state.modified {
+            notes.push("modified");
+        }
+        if let Some(status) = &state.drawing_state.status {
+            notes.push(status.as_str());
+        }
+        if notes.is_empty() {
+            format!("Canvas: {}", file_name)
+        } else {
+            format!("Canvas: {} ({})", file_name, notes.join(", "))
+        }
+    }
+
+    // The part of the canvas ctx inside its border
+    fn canvas_inner_ctx<'a>(&self, canvas_ctx: Ctx<'a>) -> Ctx<'a> {
+        let padding = &self.canvas.style.padding;
+        canvas_ctx
+            .add_offset(Coord::new(padding.left as i32 + 1, padding.top as i32 + 1))
+            .constrain_size_by(Coord::new(
+                padding.right as i32 + 1,
+                padding.bottom as i32 + 1,
+            ))
+    }
+
+    // Draws the visible portion of the canvas over the right and bottom edges of its border when
+    // the canvas doesn't fit on screen
+    fn render_scroll_bars(&self, state: &AppData, canvas_ctx: Ctx, fb: &mut FrameBuffer) {
+        let inner_ctx = self.canvas_inner_ctx(canvas_ctx);
+        let view_size = self.canvas.component.size(state, inner_ctx);
+        let canvas_size = state.drawing_state.canvas().size();
+        let camera = state.drawing_state.camera;
+        let padding = &self.canvas.style.padding;
+        let edge = inner_ctx.bounding_box.top_left() - canvas_ctx.bounding_box.top_left()
+            + view_size.to_coord().unwrap()
+            + Coord::new(padding.right as i32, padding.bottom as i32);
+        // the start and length of the thumb along a scroll bar of length `view`
+        let thumb = |camera: i32, view: u32, canvas: u32| {
+            let start = camera as u64 * view as u64 / canvas as u64;
+            let len = (view as u64 * view as u64 / canvas as u64).max(1);
+            (start as i32, len.min(view as u64 - start) as i32)
+        };
+        let cell = RenderCell::BLANK
+            .with_character('█')
+            .with_foreground(Rgba32::new_grey(127));
+        let top_left = inner_ctx.bounding_box.top_left() - canvas_ctx.bounding_box.top_left();
+        if canvas_size.height() > view_size.height() {
+            let (start, len) = thumb(camera.y, view_size.height(), canvas_size.height());
+            for y in start..(start + len) {
+                let coord = Coord::new(edge.x, top_left.y + y);
+                fb.set_cell_relative_to_ctx(canvas_ctx, coord, 1, cell);
+            }
+        }
+        if canvas_size.width() > view_size.width() {
+            let (start, len) = thumb(camera.x, view_size.width(), canvas_size.width());
+            for x in start..(start + len) {
+                let coord = Coord::new(top_left.x + x, edge.y);
+                fb.set_cell_relative_to_ctx(canvas_ctx, coord, 1, cell);
+            }
+        }
+    }
+
     fn child_ctxs<'a>(&self, state: &AppData, ctx: Ctx<'a>) -> GuiChildCtxs<'a> {
         let palette_size = self.palette.size(state, ctx);
         let opacity_size = self.opacity.size(state, ctx);
+        let locks_size = self.locks.size(state, ctx);
         let tools_size = self.tools.size(state, ctx);
+        let layers_size = self.layers.size(state, ctx);
+        let timeline_size = self.timeline.size(state, ctx);
+        let brush_size = self.brush.size(state, ctx);
+        let left_column_width = tools_size
+            .width()
+            .max(layers_size.width())
+            .max(timeline_size.width())
+            .max(brush_size.width());
         let palette =
             ctx.add_y(ctx.bounding_box.size().height() as i32 - palette_size.height() as i32);
         let opacity = palette
             .add_x(palette_size.width() as i32)
             .set_width(opacity_size.width());
+        let locks = opacity
+            .add_x(opacity_size.width() as i32)
+            .set_width(locks_size.width());
         let height_above_palette =
             (ctx.bounding_box.size().height() as i32 - palette_size.height() as i32) as u32;
         let tools = ctx.set_size(tools_size);
+        let layers = ctx.add_y(tools_size.height() as i32).set_size(layers_size);
+        let timeline = ctx
+            .add_y((tools_size.height() + layers_size.height()) as i32)
+            .set_size(timeline_size);
+        let brush = ctx
+            .add_y((tools_size.height() + layers_size.height() + timeline_size.height()) as i32)
+            .set_size(brush_size);
         let canvas = ctx
             .set_height(height_above_palette)
-            .add_x(tools_size.width() as i32);
+            .add_x(left_column_width as i32);
         GuiChildCtxs {
             palette,
             opacity,
+            locks,
             tools,
+            layers,
+            timeline,
+            brush,
             canvas,
         }
     }
@@ -1050,10 +2629,25 @@ impl Component for GuiComponent {
         let ctxs = self.child_ctxs(state, ctx);
         self.palette.render(state, ctxs.palette, fb);
         self.opacity.render(state, ctxs.opacity, fb);
+        self.locks.render(state, ctxs.locks, fb);
         self.tools.render(state, ctxs.tools, fb);
+        self.layers.render(state, ctxs.layers, fb);
+        self.timeline.render(state, ctxs.timeline, fb);
+        self.brush.render(state, ctxs.brush, fb);
         self.canvas.render(state, ctxs.canvas, fb);
+        self.render_scroll_bars(state, ctxs.canvas, fb);
     }
     fn update(&mut self, state: &mut Self::State, ctx: Ctx, event: Event) -> Self::Output {
+        self.canvas.style.title = Some(Self::canvas_title(state));
+        {
+            let ctxs = self.child_ctxs(state, ctx);
+            let inner_ctx = self.canvas_inner_ctx(ctxs.canvas);
+            let view_size = inner_ctx
+                .bounding_box
+                .size()
+                .pairwise_min(state.drawing_state.canvas().size());
+            state.set_view_size(view_size);
+        }
         if let Some(mouse_input) = event.mouse_input() {
             let ctxs = self.child_ctxs(state, ctx);
             if ctxs
@@ -1061,7 +2655,9 @@ impl Component for GuiComponent {
                 .bounding_box
                 .contains_coord(mouse_input.coord())
             {
-                self.palette.update(state, ctxs.palette, event)
+                if let Some(popup) = self.palette.update(state, ctxs.palette, event) {
+                    return Some(popup);
+                }
             } else {
                 state.drawing_state.palette_hover.ch = None;
                 state.drawing_state.palette_hover.fg = None;
@@ -1072,6 +2668,25 @@ impl Component for GuiComponent {
             } else {
                 state.drawing_state.tool_hover = None;
             }
+            if ctxs.layers.bounding_box.contains_coord(mouse_input.coord()) {
+                if let Some(popup) = self.layers.update(state, ctxs.layers, event) {
+                    return Some(popup);
+                }
+            } else {
+                state.drawing_state.layer_hover = None;
+            }
+            if ctxs
+                .timeline
+                .bounding_box
+                .contains_coord(mouse_input.coord())
+            {
+                if let Some(popup) = self.timeline.update(state, ctxs.timeline, event) {
+                    return Some(popup);
+                }
+            }
+            if ctxs.brush.bounding_box.contains_coord(mouse_input.coord()) {
+                self.brush.update(state, ctxs.brush, event);
+            }
             if ctxs.canvas.bounding_box.contains_coord(mouse_input.coord()) {
                 self.canvas.update(state, ctxs.canvas, event)
             } else {
@@ -1086,33 +2701,67 @@ impl Component for GuiComponent {
                     return Some(popup);
                 }
             }
+            if ctxs.locks.bounding_box.contains_coord(mouse_input.coord()) {
+                self.locks.update(state, ctxs.locks, event);
+            }
             match mouse_input {
                 MouseInput::MouseMove {
                     button: Some(MouseButton::Left),
                     coord,
                 } => {
                     if let Some(current_event) = state.drawing_state.current_event.as_mut() {
-                        let border_padding_top_left = Coord::new(
-                            self.canvas.style.padding.left as i32 + 1,
-                            self.canvas.style.padding.top as i32 + 1,
-                        );
+                        let inner_ctx = self.canvas_inner_ctx(ctxs.canvas);
                         let coord =
-                            coord - ctxs.canvas.bounding_box.top_left() - border_padding_top_left;
+                            coord - inner_ctx.bounding_box.top_left() + state.drawing_state.camera;
                         current_event.mouse_move(coord);
                     }
                 }
+                MouseInput::MouseMove {
+                    button: Some(MouseButton::Middle),
+                    coord,
+                } => {
+                    // dragging moves the canvas with the mouse
+                    if let Some(pan_coord) = state.drawing_state.pan_coord.replace(coord) {
+                        state.pan(pan_coord - coord);
+                    }
+                }
+                MouseInput::MouseRelease {
+                    button: Ok(MouseButton::Middle),
+                    ..
+                } => state.drawing_state.pan_coord = None,
                 MouseInput::MouseRelease { .. } => {
-                    state.commit_current_event();
+                    if let Some(current_event) = state.drawing_state.current_event.as_ref() {
+                        if current_event.commits_on_mouse_release() {
+                            state.commit_current_event();
+                        }
+                    }
                 }
                 _ => (),
             }
         } else if let Some(keyboard_input) = event.keyboard_input() {
-            match keyboard_input {
-                KeyboardInput::Char('u') => state.undo(),
-                KeyboardInput::Char('r') => state.redo(),
-                KeyboardInput::Char('s') => state.save(),
-                KeyboardInput::Char('e') => state.export(),
-                _ => (),
+            // sent when the window is closed or ctrl+c is pressed in a terminal
+            if keyboard_input == keys::ETX {
+                return Some(PopUp::Quit);
+            }
+            // the text tool captures the keyboard while it is active
+            if let Some(DrawingEvent::Text(text)) = state.drawing_state.current_event.as_mut() {
+                if keyboard_input == keys::ESCAPE {
+                    state.commit_current_event();
+                } else {
+                    text.keyboard_input(keyboard_input);
+                }
+                return None;
+            }
+            if let Some(&action) = state.keymap.bindings.get(&keyboard_input) {
+                return state.perform_action(action);
+            }
+        } else if let Some(duration) = event.tick() {
+            state.tick(duration);
+            if let Err(e) = state
+                .check_palette(duration)
+                .and_then(|()| state.check_autosave(duration))
+            {
+                return Some(PopUp::Error(e));
             }
         }
         None
@@ -1125,6 +2774,20 @@ impl Component for GuiComponent {
 enum PopUp {
     FgOpacity,
     BgOpacity,
+    LayerOpacity(usize),
+    RenameLayer(usize),
+    Fps,
+    OnionSkinOpacity,
+    CanvasSize,
+    PaletteEditor,
+    ColourPicker(PaletteRow),
+    CharPicker,
+    Help,
+    SaveAs,
+    Error(String),
+    Quit,
+    Recover,
+    DeleteFrame,
 }
 
 enum AppState {
@@ -1143,10 +2806,7 @@ fn opacity_text_field(initial_value: u8) -> CF<Option<OrEscapeOrClickOut<String>
     ))
     .ignore_state()
     .with_title_horizontal(
-        styled_string(
-            "Enter foreground opacity (0 - 255):".to_string(),
-            Style::plain_text(),
-        ),
+        styled_string("Enter opacity (0 - 255):".to_string(), Style::plain_text()),
         1,
     )
     .catch_escape_or_click_out()
@@ -1157,7 +2817,7 @@ fn pop_up_style<C: 'static + Component<State = AppData>>(
     title: Option<String>,
 ) -> CF<C::Output, AppData> {
     use chargrid::border::*;
-    cf(component)
+    cf(CloseAsEscape(component))
         .border(BorderStyle {
             title,
             title_style: Style::plain_text(),
@@ -1167,65 +2827,1638 @@ fn pop_up_style<C: 'static + Component<State = AppData>>(
         })
         .fill(Rgba32::new_grey(0))
         .centre()
-        .overlay_tint(gui_component(), chargrid::core::TintDim(127), 1)
+        // the ui is paused so keys typed into the pop-up don't also trigger its key bindings
+        .overlay_tint(gui_component().pause(), chargrid::core::TintDim(127), 1)
+}
+
+// Closing the window or pressing ctrl+c closes pop-ups as though escape was pressed
+struct CloseAsEscape<C>(C);
+
+impl<C: Component> Component for CloseAsEscape<C> {
+    type Output = C::Output;
+    type State = C::State;
+    fn render(&self, state: &Self::State, ctx: Ctx, fb: &mut FrameBuffer) {
+        self.0.render(state, ctx, fb);
+    }
+    fn update(&mut self, state: &mut Self::State, ctx: Ctx, event: Event) -> Self::Output {
+        let event = match event.keyboard_input() {
+            Some(keys::ETX) => Event::Input(Input::Keyboard(keys::ESCAPE)),
+            _ => event,
+        };
+        self.0.update(state, ctx, event)
+    }
+    fn size(&self, state: &Self::State, ctx: Ctx) -> Size {
+        self.0.size(state, ctx)
+    }
 }
 
-fn opacity_dialog(title: String, initial_value: u8) -> CF<Option<Option<u8>>, AppData> {
+// Yields None if the dialog is cancelled
+fn opacity_dialog(
+    title: String,
+    initial_value: u8,
+) -> CF<Option<Option<Result<u8, String>>>, AppData> {
     pop_up_style(opacity_text_field(initial_value), Some(title)).map(|result| {
-        if let Ok(string) = result {
-            if let Ok(opacity) = string.parse::<u8>() {
-                return Some(opacity);
-            } else {
-                println!(
-                    "Failed to parse \"{}\" as byte. Enter a number from 0 to 255.",
+        result.ok().map(|string| {
+            string.parse::<u8>().map_err(|_| {
+                format!(
+                    "\"{}\" isn't a valid opacity (enter a number from 0 to 255)",
                     string
-                );
-            }
-        }
-        None
+                )
+            })
+        })
     })
 }
 
-fn app_loop() -> CF<Option<app::Exit>, AppData> {
-    loop_(AppState::Ui, |state| match state {
-        AppState::Ui => gui_component().map(AppState::PopUp).continue_(),
-        AppState::PopUp(PopUp::FgOpacity) => on_state_then(|state: &mut AppData| {
-            opacity_dialog(
-                "Foreground Opacity".to_string(),
-                state.drawing_state.fg_opacity,
-            )
-            .map_side_effect(|opacity, data| {
-                if let Some(opacity) = opacity {
-                    data.drawing_state.fg_opacity = opacity;
-                }
-            })
-            .map_val(|| AppState::Ui)
-            .continue_()
-        }),
-        AppState::PopUp(PopUp::BgOpacity) => on_state_then(|state: &mut AppData| {
-            opacity_dialog(
-                "Background Opacity".to_string(),
-                state.drawing_state.bg_opacity,
-            )
-            .map_side_effect(|opacity, data| {
-                if let Some(opacity) = opacity {
-                    data.drawing_state.bg_opacity = opacity;
-                }
-            })
-            .map_val(|| AppState::Ui)
-            .continue_()
-        }),
+fn layer_name_dialog(initial_value: String) -> CF<Option<Option<String>>, AppData> {
+    let text_field = cf(TextField::with_initial_string(
+        LayersComponent::NAME_WIDTH as u32,
+        initial_value,
+    ))
+    .ignore_state()
+    .with_title_horizontal(
+        styled_string("Enter layer name:".to_string(), Style::plain_text()),
+        1,
+    )
+    .catch_escape_or_click_out();
+    pop_up_style(text_field, Some("Rename Layer".to_string()))
+        .map(|result| result.ok().filter(|name| !name.is_empty()))
+}
+
+fn fps_dialog(initial_value: u32) -> CF<Option<Option<u32>>, AppData> {
+    let text_field = cf(TextField::with_initial_string(
+        2,
+        format!("{}", initial_value),
+    ))
+    .ignore_state()
+    .with_title_horizontal(
+        styled_string(
+            "Enter frames per second (1 - 60):".to_string(),
+            Style::plain_text(),
+        ),
+        1,
+    )
+    .catch_escape_or_click_out();
+    pop_up_style(text_field, Some("Playback Speed".to_string())).map(|result| {
+        result
+            .ok()
+            .and_then(|string| string.parse::<u32>().ok())
+            .filter(|fps| (1..=60).contains(fps))
     })
 }
 
-pub fn app(palette_path: PathBuf, input_path: Option<PathBuf>, output_path: PathBuf) -> App {
-    let live_paths = LivePaths {
-        palette_path,
-        output_path,
-    };
-    let app_data = AppData::new_with_live_paths(live_paths, input_path);
-    app_loop()
-        .with_state(app_data)
-        .clear_each_frame()
-        .exit_on_close()
+// A text field for the new size and a grid of anchors which can be clicked or cycled with tab
+struct CanvasSizeComponent {
+    text_field: TextField,
+    anchor: Anchor,
+}
+
+impl CanvasSizeComponent {
+    const SIZE_LABEL: &'static str = "Enter size (WxH):";
+    const ANCHOR_LABEL: &'static str = "Anchor (click or tab):";
+    // drawn in a 3x3 grid where each character shows the part of the canvas kept in place
+    const ANCHOR_CHARS: [char; 9] = ['┌', '┬', '┐', '├', '┼', '┤', '└', '┴', '┘'];
+    const ANCHOR_Y: i32 = 4;
+
+    fn new(size: Size) -> Self {
+        Self {
+            text_field: TextField::with_initial_string(
+                Self::SIZE_LABEL.len() as u32,
+                format!("{}x{}", size.width(), size.height()),
+            ),
+            anchor: Anchor::TopLeft,
+        }
+    }
+
+    // Anchors are spaced out horizontally so they are easier to click
+    fn anchor_coord(anchor: Anchor) -> Coord {
+        let Coord { x, y } = anchor.coord();
+        Coord::new(x * 2, y + Self::ANCHOR_Y)
+    }
+}
+
+impl Component for CanvasSizeComponent {
+    type Output = Option<(String, Anchor)>;
+    type State = AppData;
+    fn render(&self, _state: &Self::State, ctx: Ctx, fb: &mut FrameBuffer) {
+        text::StyledString::plain_text(Self::SIZE_LABEL.to_string()).render(&(), ctx, fb);
+        self.text_field.render(&(), ctx.add_y(1), fb);
+        text::StyledString::plain_text(Self::ANCHOR_LABEL.to_string()).render(
+            &(),
+            ctx.add_y(3),
+            fb,
+        );
+        for (&anchor, &character) in Anchor::ALL.iter().zip(Self::ANCHOR_CHARS.iter()) {
+            let style = if anchor == self.anchor {
+                Style::plain_text()
+                    .with_foreground(Rgba32::new_grey(0))
+                    .with_background(Rgba32::new_grey(255))
+            } else {
+                Style::plain_text()
+            };
+            fb.set_cell_relative_to_ctx(
+                ctx,
+                Self::anchor_coord(anchor),
+                0,
+                RenderCell::BLANK
+                    .with_character(character)
+                    .with_style(style),
+            );
+        }
+    }
+    fn update(&mut self, _state: &mut Self::State, ctx: Ctx, event: Event) -> Self::Output {
+        if let Some(keys::TAB) = event.keyboard_input() {
+            let index = Anchor::ALL.iter().position(|&a| a == self.anchor).unwrap();
+            self.anchor = Anchor::ALL[(index + 1) % Anchor::ALL.len()];
+            return None;
+        }
+        if let Some(MouseInput::MousePress {
+            button: MouseButton::Left,
+            coord,
+        }) = event.mouse_input()
+        {
+            if let Some(coord) = ctx.bounding_box.coord_absolute_to_relative(coord) {
+                if let Some(&anchor) = Anchor::ALL
+                    .iter()
+                    .find(|&&anchor| Self::anchor_coord(anchor) == coord)
+                {
+                    self.anchor = anchor;
+                    return None;
+                }
+            }
+        }
+        self.text_field
+            .update(&mut (), ctx.add_y(1), event)
+            .map(|string| (string, self.anchor))
+    }
+    fn size(&self, _state: &Self::State, _ctx: Ctx) -> Size {
+        Size::new(
+            Self::SIZE_LABEL.len().max(Self::ANCHOR_LABEL.len()) as u32,
+            Self::ANCHOR_Y as u32 + 3,
+        )
+    }
+}
+
+fn canvas_size_dialog(size: Size) -> CF<Option<Option<(Size, Anchor)>>, AppData> {
+    let component = cf(CanvasSizeComponent::new(size)).catch_escape_or_click_out();
+    pop_up_style(component, Some("Canvas Size".to_string())).map(|result| {
+        let (string, anchor) = result.ok()?;
+        let (width, height) = string.split_once('x')?;
+        let size = Size::new(width.trim().parse().ok()?, height.trim().parse().ok()?);
+        (size.width() > 0 && size.height() > 0).then_some((size, anchor))
+    })
+}
+
+// Edits the palette in place. Clicking an entry selects it and puts its value in the text field.
+// Pressing enter replaces the selected entry with the value in the text field, and the buttons
+// move, delete or duplicate the selected entry.
+struct PaletteEditorComponent {
+    selected: Option<(PaletteRow, usize)>,
+    text_field: TextField,
+    message: Option<String>,
+}
+
+impl PaletteEditorComponent {
+    const LABEL_WIDTH: i32 = 3;
+    const BUTTONS: &'static str = "< > Del Add";
+    const BUTTONS_Y: i32 = 5;
+    const VALUE_Y: i32 = 7;
+    const MESSAGE_Y: i32 = 10;
+    const TEXT_FIELD_WIDTH: u32 = 16;
+
+    fn new() -> Self {
+        Self {
+            selected: None,
+            text_field: TextField::with_initial_string(Self::TEXT_FIELD_WIDTH, String::new()),
+            message: None,
+        }
+    }
+
+    fn row_label(row: PaletteRow) -> &'static str {
+        match row {
+            PaletteRow::Ch => "ch|",
+            PaletteRow::Fg => "fg|",
+            PaletteRow::Bg => "bg|",
+        }
+    }
+
+    fn row_y(row: PaletteRow) -> i32 {
+        PaletteRow::ALL.iter().position(|&r| r == row).unwrap() as i32
+    }
+
+    fn select(&mut self, state: &AppData, row: PaletteRow, index: usize) {
+        self.selected = Some((row, index));
+        let string = row.entry_string(&state.palette, index).unwrap_or_default();
+        self.text_field = TextField::with_initial_string(Self::TEXT_FIELD_WIDTH, string);
+    }
+
+    fn edit(&mut self, state: &mut AppData, row: PaletteRow, edit: PaletteEdit) -> bool {
+        match state.edit_palette(row, edit) {
+            Ok(()) => {
+                self.message = None;
+                true
+            }
+            Err(e) => {
+                self.message = Some(e);
+                false
+            }
+        }
+    }
+
+    fn press_button(&mut self, state: &mut AppData, x: i32) {
+        let Some((row, index)) = self.selected else {
+            return;
+        };
+        let len = row.len(&state.palette);
+        // each button makes an edit and then selects the entry at a new index
+        let (edit, new_index) = match x {
+            0 if index > 0 => (PaletteEdit::Swap(index, index - 1), index - 1),
+            2 if index + 1 < len => (PaletteEdit::Swap(index, index + 1), index + 1),
+            4..=6 => (PaletteEdit::Remove(index), index.min(len.saturating_sub(2))),
+            // new entries start as a copy of the selected entry
+            8..=10 => {
+                let value = row.entry_string(&state.palette, index).unwrap_or_default();
+                (PaletteEdit::Insert(index + 1, value), index + 1)
+            }
+            _ => return,
+        };
+        if self.edit(state, row, edit) {
+            self.select(state, row, new_index);
+        }
+    }
+}
+
+impl Component for PaletteEditorComponent {
+    type Output = Option<()>;
+    type State = AppData;
+    fn render(&self, state: &Self::State, ctx: Ctx, fb: &mut FrameBuffer) {
+        let select_style = Style::plain_text()
+            .with_foreground(Rgba32::new_grey(0))
+            .with_background(Rgba32::new_grey(255));
+        for row in PaletteRow::ALL {
+            let y = Self::row_y(row);
+            text::StyledString::plain_text(Self::row_label(row).to_string()).render(
+                &(),
+                ctx.add_y(y),
+                fb,
+            );
+            for index in 0..row.len(&state.palette) {
+                let selected = self.selected == Some((row, index));
+                let render_cell = match row {
+                    PaletteRow::Ch => RenderCell {
+                        character: Some(state.palette.ch[index]),
+                        style: if selected {
+                            select_style
+                        } else {
+                            Style::plain_text()
+                        },
+                    },
+                    PaletteRow::Fg | PaletteRow::Bg => {
+                        let colour = if row == PaletteRow::Fg {
+                            state.palette.fg[index]
+                        } else {
+                            state.palette.bg[index]
+                        };
+                        let foreground = if black_foreground(colour) {
+                            Rgba32::new_grey(0)
+                        } else {
+                            Rgba32::new_grey(255)
+                        };
+                        RenderCell {
+                            character: selected.then_some('*'),
+                            style: Style::default()
+                                .with_background(colour.to_rgba32(255))
+                                .with_foreground(foreground),
+                        }
+                    }
+                };
+                let coord = Coord::new(Self::LABEL_WIDTH + index as i32, y);
+                fb.set_cell_relative_to_ctx(ctx, coord, 0, render_cell);
+            }
+        }
+        let selected_label = match self.selected {
+            Some((row, index)) => format!(
+                "{} {}: {}",
+                Self::row_label(row).trim_end_matches('|'),
+                index,
+                row.entry_string(&state.palette, index).unwrap_or_default()
+            ),
+            None => "Click an entry to edit it".to_string(),
+        };
+        text::StyledString::plain_text(selected_label).render(&(), ctx.add_y(4), fb);
+        text::StyledString::plain_text(Self::BUTTONS.to_string()).render(
+            &(),
+            ctx.add_y(Self::BUTTONS_Y),
+            fb,
+        );
+        text::StyledString::plain_text("Enter a value:".to_string()).render(
+            &(),
+            ctx.add_y(Self::VALUE_Y),
+            fb,
+        );
+        self.text_field
+            .render(&(), ctx.add_y(Self::VALUE_Y + 1), fb);
+        if let Some(message) = self.message.as_ref() {
+            text::StyledString::plain_text(message.clone()).render(
+                &(),
+                ctx.add_y(Self::MESSAGE_Y),
+                fb,
+            );
+        }
+    }
+    fn update(&mut self, state: &mut Self::State, ctx: Ctx, event: Event) -> Self::Output {
+        if let Some(MouseInput::MousePress {
+            button: MouseButton::Left,
+            coord,
+        }) = event.mouse_input()
+        {
+            if let Some(Coord { x, y }) = ctx.bounding_box.coord_absolute_to_relative(coord) {
+                if let Some(&row) = PaletteRow::ALL.iter().find(|&&row| Self::row_y(row) == y) {
+                    let index = x - Self::LABEL_WIDTH;
+                    if index >= 0 && (index as usize) < row.len(&state.palette) {
+                        self.select(state, row, index as usize);
+                    }
+                    return None;
+                }
+                if y == Self::BUTTONS_Y {
+                    self.press_button(state, x);
+                    return None;
+                }
+            }
+        }
+        if let Some(string) = self
+            .text_field
+            .update(&mut (), ctx.add_y(Self::VALUE_Y + 1), event)
+        {
+            if let Some((row, index)) = self.selected {
+                if self.edit(state, row, PaletteEdit::Set(index, string)) {
+                    // show the value as it was written to the file
+                    self.select(state, row, index);
+                }
+            }
+        }
+        None
+    }
+    fn size(&self, state: &Self::State, _ctx: Ctx) -> Size {
+        let longest_row = PaletteRow::ALL
+            .iter()
+            .map(|row| row.len(&state.palette))
+            .max()
+            .unwrap_or(0);
+        Size::new(
+            (Self::LABEL_WIDTH as u32 + longest_row as u32).max(40),
+            Self::MESSAGE_Y as u32 + 1,
+        )
+    }
+}
+
+// Hue is in degrees from 0 to 360, and saturation and value are from 0 to 1
+#[derive(Clone, Copy)]
+struct Hsv {
+    h: f64,
+    s: f64,
+    v: f64,
+}
+
+impl Hsv {
+    fn to_rgb24(self) -> Rgb24 {
+        let Self { h, s, v } = self;
+        let c = v * s;
+        let h = (h / 60.).rem_euclid(6.);
+        let x = c * (1. - (h % 2. - 1.).abs());
+        let (r, g, b) = match h as u32 {
+            0 => (c, x, 0.),
+            1 => (x, c, 0.),
+            2 => (0., c, x),
+            3 => (0., x, c),
+            4 => (x, 0., c),
+            _ => (c, 0., x),
+        };
+        let m = v - c;
+        let to_u8 = |f: f64| ((f + m) * 255.).round().clamp(0., 255.) as u8;
+        Rgb24::new(to_u8(r), to_u8(g), to_u8(b))
+    }
+
+    // Hue and saturation aren't meaningful for every colour (e.g. greys have no hue) so they are
+    // taken from `previous` in those cases, which stops the bars jumping around while dragging
+    fn from_rgb24(Rgb24 { r, g, b }: Rgb24, previous: Self) -> Self {
+        let (r, g, b) = (r as f64 / 255., g as f64 / 255., b as f64 / 255.);
+        let max = r.max(g).max(b);
+        let min = r.min(g).min(b);
+        let delta = max - min;
+        let v = max;
+        let s = if max > 0. { delta / max } else { previous.s };
+        let h = if delta == 0. {
+            previous.h
+        } else if max == r {
+            60. * ((g - b) / delta).rem_euclid(6.)
+        } else if max == g {
+            60. * ((b - r) / delta + 2.)
+        } else {
+            60. * ((r - g) / delta + 4.)
+        };
+        Self { h, s, v }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ColourChannel {
+    Hue,
+    Saturation,
+    Value,
+    Red,
+    Green,
+    Blue,
+}
+
+impl ColourChannel {
+    // In the order they are drawn, with a gap between the hsv and rgb bars
+    const ALL: [(Self, i32); 6] = [
+        (Self::Hue, 0),
+        (Self::Saturation, 1),
+        (Self::Value, 2),
+        (Self::Red, 4),
+        (Self::Green, 5),
+        (Self::Blue, 6),
+    ];
+
+    fn label(self) -> char {
+        match self {
+            Self::Hue => 'H',
+            Self::Saturation => 'S',
+            Self::Value => 'V',
+            Self::Red => 'R',
+            Self::Green => 'G',
+            Self::Blue => 'B',
+        }
+    }
+
+    // The position of the colour along this channel's bar from 0 to 1
+    fn get(self, hsv: Hsv) -> f64 {
+        let rgb24 = hsv.to_rgb24();
+        match self {
+            Self::Hue => hsv.h / 360.,
+            Self::Saturation => hsv.s,
+            Self::Value => hsv.v,
+            Self::Red => rgb24.r as f64 / 255.,
+            Self::Green => rgb24.g as f64 / 255.,
+            Self::Blue => rgb24.b as f64 / 255.,
+        }
+    }
+
+    // The colour with this channel set to `t`, from 0 to 1
+    fn set(self, hsv: Hsv, t: f64) -> Hsv {
+        let t = t.clamp(0., 1.);
+        let to_u8 = |t: f64| (t * 255.).round() as u8;
+        let rgb24 = hsv.to_rgb24();
+        match self {
+            Self::Hue => Hsv { h: t * 360., ..hsv },
+            Self::Saturation => Hsv { s: t, ..hsv },
+            Self::Value => Hsv { v: t, ..hsv },
+            Self::Red => Hsv::from_rgb24(
+                Rgb24 {
+                    r: to_u8(t),
+                    ..rgb24
+                },
+                hsv,
+            ),
+            Self::Green => Hsv::from_rgb24(
+                Rgb24 {
+                    g: to_u8(t),
+                    ..rgb24
+                },
+                hsv,
+            ),
+            Self::Blue => Hsv::from_rgb24(
+                Rgb24 {
+                    b: to_u8(t),
+                    ..rgb24
+                },
+                hsv,
+            ),
+        }
+    }
+
+    fn value_string(self, hsv: Hsv) -> String {
+        match self {
+            Self::Hue => format!("{:.0}", hsv.h),
+            Self::Saturation | Self::Value => format!("{:.0}%", self.get(hsv) * 100.),
+            Self::Red | Self::Green | Self::Blue => format!("{:.0}", self.get(hsv) * 255.),
+        }
+    }
+}
+
+// Picks a colour by clicking or dragging along hsv and rgb bars, or by entering a hex value
+struct ColourPickerComponent {
+    hsv: Hsv,
+    text_field: TextField,
+    // the channel being dragged
+    dragging: Option<ColourChannel>,
+    message: Option<String>,
+}
+
+impl ColourPickerComponent {
+    const BAR_X: i32 = 2;
+    const BAR_WIDTH: u32 = 32;
+    const HEX_Y: i32 = 8;
+    const BUTTONS: &'static str = "Use  Add to palette";
+    const BUTTONS_Y: i32 = 10;
+    const MESSAGE_Y: i32 = 12;
+    const TEXT_FIELD_WIDTH: u32 = 8;
+
+    fn new(colour: Rgb24) -> Self {
+        let hsv = Hsv::from_rgb24(
+            colour,
+            Hsv {
+                h: 0.,
+                s: 0.,
+                v: 0.,
+            },
+        );
+        Self {
+            hsv,
+            text_field: Self::text_field(colour),
+            dragging: None,
+            message: None,
+        }
+    }
+
+    fn text_field(colour: Rgb24) -> TextField {
+        TextField::with_initial_string(Self::TEXT_FIELD_WIDTH, palette::rgb24_to_hex(colour))
+    }
+
+    fn set_hsv(&mut self, hsv: Hsv) {
+        self.hsv = hsv;
+        self.text_field = Self::text_field(hsv.to_rgb24());
+        self.message = None;
+    }
+
+    fn bar_t(x: i32) -> f64 {
+        (x - Self::BAR_X) as f64 / (Self::BAR_WIDTH - 1) as f64
+    }
+}
+
+impl Component for ColourPickerComponent {
+    type Output = Option<(Rgb24, bool)>;
+    type State = AppData;
+    fn render(&self, _state: &Self::State, ctx: Ctx, fb: &mut FrameBuffer) {
+        for (channel, y) in ColourChannel::ALL {
+            let label = RenderCell::BLANK
+                .with_character(channel.label())
+                .with_style(Style::plain_text());
+            fb.set_cell_relative_to_ctx(ctx, Coord::new(0, y), 0, label);
+            // the cell closest to the current value is marked
+            let marker_x = (channel.get(self.hsv) * (Self::BAR_WIDTH - 1) as f64).round() as i32;
+            for x in 0..(Self::BAR_WIDTH as i32) {
+                let colour = channel
+                    .set(self.hsv, Self::bar_t(x + Self::BAR_X))
+                    .to_rgb24();
+                let foreground = if black_foreground(colour) {
+                    Rgba32::new_grey(0)
+                } else {
+                    Rgba32::new_grey(255)
+                };
+                let cell = RenderCell {
+                    character: (x == marker_x).then_some('|'),
+                    style: Style::default()
+                        .with_background(colour.to_rgba32(255))
+                        .with_foreground(foreground),
+                };
+                fb.set_cell_relative_to_ctx(ctx, Coord::new(x + Self::BAR_X, y), 0, cell);
+            }
+            text::StyledString::plain_text(channel.value_string(self.hsv)).render(
+                &(),
+                ctx.add_xy(Self::BAR_X + Self::BAR_WIDTH as i32 + 1, y),
+                fb,
+            );
+        }
+        text::StyledString::plain_text("Hex".to_string()).render(&(), ctx.add_y(Self::HEX_Y), fb);
+        self.text_field.render(&(), ctx.add_xy(4, Self::HEX_Y), fb);
+        // a preview of the colour being picked
+        let preview = RenderCell::BLANK.with_background(self.hsv.to_rgb24().to_rgba32(255));
+        for x in 0..4 {
+            let coord = Coord::new(4 + Self::TEXT_FIELD_WIDTH as i32 + 1 + x, Self::HEX_Y);
+            fb.set_cell_relative_to_ctx(ctx, coord, 0, preview);
+        }
+        text::StyledString::plain_text(Self::BUTTONS.to_string()).render(
+            &(),
+            ctx.add_y(Self::BUTTONS_Y),
+            fb,
+        );
+        if let Some(message) = self.message.as_ref() {
+            text::StyledString::plain_text(message.clone()).render(
+                &(),
+                ctx.add_y(Self::MESSAGE_Y),
+                fb,
+            );
+        }
+    }
+    fn update(&mut self, _state: &mut Self::State, ctx: Ctx, event: Event) -> Self::Output {
+        if let Some(mouse_input) = event.mouse_input() {
+            let coord = mouse_input.coord() - ctx.bounding_box.top_left();
+            match mouse_input {
+                MouseInput::MousePress {
+                    button: MouseButton::Left,
+                    ..
+                } => {
+                    if let Some(&(channel, _)) = ColourChannel::ALL.iter().find(|(_, y)| {
+                        *y == coord.y
+                            && (Self::BAR_X..(Self::BAR_X + Self::BAR_WIDTH as i32))
+                                .contains(&coord.x)
+                    }) {
+                        self.dragging = Some(channel);
+                        self.set_hsv(channel.set(self.hsv, Self::bar_t(coord.x)));
+                        return None;
+                    }
+                    if coord.y == Self::BUTTONS_Y {
+                        let colour = self.hsv.to_rgb24();
+                        match coord.x {
+                            0..=2 => return Some((colour, false)),
+                            5..=18 => return Some((colour, true)),
+                            _ => (),
+                        }
+                    }
+                }
+                MouseInput::MouseMove {
+                    button: Some(MouseButton::Left),
+                    ..
+                } => {
+                    if let Some(channel) = self.dragging {
+                        self.set_hsv(channel.set(self.hsv, Self::bar_t(coord.x)));
+                    }
+                    return None;
+                }
+                MouseInput::MouseRelease { .. } => self.dragging = None,
+                _ => (),
+            }
+        }
+        if let Some(string) = self
+            .text_field
+            .update(&mut (), ctx.add_xy(4, Self::HEX_Y), event)
+        {
+            match palette::rgb24_from_hex(&string) {
+                Ok(colour) => self.set_hsv(Hsv::from_rgb24(colour, self.hsv)),
+                Err(e) => self.message = Some(e),
+            }
+        }
+        None
+    }
+    fn size(&self, _state: &Self::State, _ctx: Ctx) -> Size {
+        Size::new(
+            Self::BAR_X as u32 + Self::BAR_WIDTH + 5,
+            Self::MESSAGE_Y as u32 + 1,
+        )
+    }
+}
+
+fn colour_picker_dialog(
+    title: String,
+    colour: Rgb24,
+) -> CF<Option<Option<(Rgb24, bool)>>, AppData> {
+    let component = cf(ColourPickerComponent::new(colour)).catch_escape_or_click_out();
+    pop_up_style(component, Some(title)).map(|result| result.ok())
+}
+
+fn palette_editor_dialog() -> CF<Option<()>, AppData> {
+    let component = cf(PaletteEditorComponent::new()).catch_escape_or_click_out();
+    pop_up_style(component, Some("Palette Editor".to_string())).map(|_| ())
+}
+
+// The characters which can be found by name, along with their names. Looking up every name takes
+// a while, so this is built on the first search rather than on each one.
+static CHAR_NAMES: LazyLock<Vec<(char, String)>> = LazyLock::new(|| {
+    (0x20..=0x2FFFF)
+        .filter_map(char::from_u32)
+        // skip characters which don't take up exactly one cell, such as combining marks
+        .filter(|c| c.width() == Some(1))
+        .filter_map(|c| unicode_names2::name(c).map(|name| (c, name.to_string())))
+        .collect()
+});
+
+// A page of characters in the character picker
+struct CharPage {
+    name: String,
+    chars: Vec<char>,
+}
+
+impl CharPage {
+    fn range(name: &str, start: u32, end: u32) -> Self {
+        Self {
+            name: name.to_string(),
+            chars: (start..=end).filter_map(char::from_u32).collect(),
+        }
+    }
+
+    fn all() -> Vec<Self> {
+        vec![
+            Self {
+                name: "CP437".to_string(),
+                chars: (0..=255).map(cp437::to_char).collect(),
+            },
+            Self::range("Box Drawing", 0x2500, 0x257F),
+            Self::range("Block Elements", 0x2580, 0x259F),
+            Self::range("Braille", 0x2800, 0x28FF),
+            Self::range("Geometric Shapes", 0x25A0, 0x25FF),
+            Self::range("Arrows", 0x2190, 0x21FF),
+        ]
+    }
+
+    // Finds a character by codepoint (e.g. "U+2588") or the characters whose names contain each
+    // word of the query (e.g. "double arrow")
+    fn search(query: &str) -> Result<Self, String> {
+        let query = query.trim();
+        let upper = query.to_uppercase();
+        if let Some(hex) = upper
+            .strip_prefix("U+")
+            .or_else(|| upper.strip_prefix("0X"))
+        {
+            let ch = u32::from_str_radix(hex, 16)
+                .ok()
+                .and_then(char::from_u32)
+                .ok_or_else(|| format!("\"{}\" isn't a valid codepoint", query))?;
+            return Ok(Self {
+                name: query.to_string(),
+                chars: vec![ch],
+            });
+        }
+        let words = upper.split_whitespace().collect::<Vec<_>>();
+        if words.is_empty() {
+            return Err("enter a name or codepoint to search for".to_string());
+        }
+        let chars = CHAR_NAMES
+            .iter()
+            .filter(|(_, name)| words.iter().all(|word| name.contains(word)))
+            .map(|&(c, _)| c)
+            .take(CharPickerComponent::COLUMNS * CharPickerComponent::ROWS)
+            .collect::<Vec<_>>();
+        if chars.is_empty() {
+            return Err(format!("no characters match \"{}\"", query));
+        }
+        Ok(Self {
+            name: format!("Search: {}", query),
+            chars,
+        })
+    }
+}
+
+// Browses pages of characters and searches for characters by name or codepoint. Clicking a
+// character on the page or in the strip of recently chosen characters chooses it.
+struct CharPickerComponent {
+    pages: Vec<CharPage>,
+    page_index: usize,
+    // shown instead of the current page after a search
+    search_results: Option<CharPage>,
+    text_field: TextField,
+    hover: Option<char>,
+    message: Option<String>,
+}
+
+impl CharPickerComponent {
+    const COLUMNS: usize = 32;
+    const ROWS: usize = 8;
+    const GRID_Y: i32 = 2;
+    const INFO_Y: i32 = 11;
+    const SEARCH_Y: i32 = 13;
+    const RECENT_Y: i32 = 16;
+    const MESSAGE_Y: i32 = 19;
+    const WIDTH: u32 = 48;
+    const RECENT_LEN: usize = 32;
+
+    fn new() -> Self {
+        Self {
+            pages: CharPage::all(),
+            page_index: 0,
+            search_results: None,
+            text_field: TextField::with_initial_string(Self::WIDTH, String::new()),
+            hover: None,
+            message: None,
+        }
+    }
+
+    fn page(&self) -> &CharPage {
+        self.search_results
+            .as_ref()
+            .unwrap_or(&self.pages[self.page_index])
+    }
+
+    fn turn_page(&mut self, step: isize) {
+        let num_pages = self.pages.len() as isize;
+        self.page_index = (self.page_index as isize + step).rem_euclid(num_pages) as usize;
+        self.search_results = None;
+        self.message = None;
+    }
+
+    fn grid_coord(index: usize) -> Coord {
+        Coord::new(
+            (index % Self::COLUMNS) as i32,
+            (index / Self::COLUMNS) as i32 + Self::GRID_Y,
+        )
+    }
+
+    // The character at a coord relative to the top-left of the component
+    fn char_at(&self, state: &AppData, Coord { x, y }: Coord) -> Option<char> {
+        if x < 0 || x as usize >= Self::COLUMNS {
+            return None;
+        }
+        if y == Self::RECENT_Y + 1 {
+            return state.drawing_state.recent_chars.get(x as usize).copied();
+        }
+        let row = y - Self::GRID_Y;
+        if row < 0 || row as usize >= Self::ROWS {
+            return None;
+        }
+        self.page()
+            .chars
+            .get(row as usize * Self::COLUMNS + x as usize)
+            .copied()
+    }
+}
+
+impl Component for CharPickerComponent {
+    type Output = Option<char>;
+    type State = AppData;
+    fn render(&self, state: &Self::State, ctx: Ctx, fb: &mut FrameBuffer) {
+        let select_style = Style::plain_text()
+            .with_foreground(Rgba32::new_grey(0))
+            .with_background(Rgba32::new_grey(255));
+        let hover_style = Style::plain_text().with_background(Rgba32::new_grey(127));
+        let char_style = |ch: char| {
+            if Some(ch) == self.hover {
+                hover_style
+            } else if ch == state.drawing_state.custom.ch {
+                select_style
+            } else {
+                Style::plain_text()
+            }
+        };
+        let title = match self.search_results {
+            Some(_) => format!("< > {}", self.page().name),
+            None => format!(
+                "< > {} ({}/{})",
+                self.page().name,
+                self.page_index + 1,
+                self.pages.len()
+            ),
+        };
+        text::StyledString::plain_text(title).render(&(), ctx, fb);
+        for (i, &ch) in self.page().chars.iter().enumerate() {
+            let cell = RenderCell::BLANK
+                .with_character(ch)
+                .with_style(char_style(ch));
+            fb.set_cell_relative_to_ctx(ctx, Self::grid_coord(i), 0, cell);
+        }
+        if let Some(ch) = self.hover {
+            let name = unicode_names2::name(ch)
+                .map(|name| name.to_string())
+                .unwrap_or_default();
+            text::StyledString::plain_text(format!("U+{:04X} {}", ch as u32, name)).render(
+                &(),
+                ctx.add_y(Self::INFO_Y),
+                fb,
+            );
+        }
+        text::StyledString::plain_text("Search by name or codepoint (U+XXXX):".to_string()).render(
+            &(),
+            ctx.add_y(Self::SEARCH_Y),
+            fb,
+        );
+        self.text_field
+            .render(&(), ctx.add_y(Self::SEARCH_Y + 1), fb);
+        text::StyledString::plain_text("Recent:".to_string()).render(
+            &(),
+            ctx.add_y(Self::RECENT_Y),
+            fb,
+        );
+        for (x, &ch) in state.drawing_state.recent_chars.iter().enumerate() {
+            let cell = RenderCell::BLANK
+                .with_character(ch)
+                .with_style(char_style(ch));
+            fb.set_cell_relative_to_ctx(ctx, Coord::new(x as i32, Self::RECENT_Y + 1), 0, cell);
+        }
+        if let Some(message) = self.message.as_ref() {
+            text::StyledString::plain_text(message.clone()).render(
+                &(),
+                ctx.add_y(Self::MESSAGE_Y),
+                fb,
+            );
+        }
+    }
+    fn update(&mut self, state: &mut Self::State, ctx: Ctx, event: Event) -> Self::Output {
+        match event.keyboard_input() {
+            Some(KeyboardInput::PageUp) => {
+                self.turn_page(-1);
+                return None;
+            }
+            Some(KeyboardInput::PageDown | keys::TAB) => {
+                self.turn_page(1);
+                return None;
+            }
+            _ => (),
+        }
+        if let Some(mouse_input) = event.mouse_input() {
+            let coord = mouse_input.coord() - ctx.bounding_box.top_left();
+            self.hover = self.char_at(state, coord);
+            if let MouseInput::MousePress {
+                button: MouseButton::Left,
+                ..
+            } = mouse_input
+            {
+                match (coord.x, coord.y) {
+                    (0, 0) => self.turn_page(-1),
+                    (2, 0) => self.turn_page(1),
+                    // control characters such as the null at the start of CP437 can't be drawn
+                    _ => return self.hover.filter(|ch| !ch.is_control()),
+                }
+                return None;
+            }
+        }
+        if let Some(query) = self
+            .text_field
+            .update(&mut (), ctx.add_y(Self::SEARCH_Y + 1), event)
+        {
+            match CharPage::search(&query) {
+                Ok(page) => {
+                    self.search_results = Some(page);
+                    self.message = None;
+                }
+                Err(e) => self.message = Some(e),
+            }
+        }
+        None
+    }
+    fn size(&self, _state: &Self::State, _ctx: Ctx) -> Size {
+        Size::new(Self::WIDTH, Self::MESSAGE_Y as u32 + 1)
+    }
+}
+
+fn char_picker_dialog() -> CF<Option<Option<char>>, AppData> {
+    let component = cf(CharPickerComponent::new()).catch_escape_or_click_out();
+    pop_up_style(component, Some("Characters".to_string())).map(|result| result.ok())
+}
+
+// Lists every action in columns along with the keys bound to it
+struct HelpComponent {
+    header: String,
+    lines: Vec<String>,
+    column_width: usize,
+}
+
+impl HelpComponent {
+    const ROWS: usize = 20;
+    const NOTE: &'static str = "While the keyboard cursor is shown, the pan keys move it instead.";
+    const LINES_Y: i32 = 3;
+
+    fn new(keymap: &Keymap) -> Self {
+        let header = if keymap.loaded {
+            format!("Key bindings from {}", keymap.path.display())
+        } else {
+            format!(
+                "Default key bindings (create {} to change them)",
+                keymap.path.display()
+            )
+        };
+        let actions = Action::all();
+        let name_width = actions.iter().map(|a| a.name().len()).max().unwrap_or(0);
+        let lines = actions
+            .into_iter()
+            .map(|action| {
+                let keys = keymap
+                    .keys(action)
+                    .into_iter()
+                    .map(keymap::key_to_string)
+                    .collect::<Vec<_>>();
+                format!(
+                    "{:width$} {}",
+                    action.name(),
+                    keys.join(", "),
+                    width = name_width
+                )
+            })
+            .collect::<Vec<_>>();
+        let column_width = lines.iter().map(|l| l.chars().count()).max().unwrap_or(0) + 2;
+        Self {
+            header,
+            lines,
+            column_width,
+        }
+    }
+}
+
+impl Component for HelpComponent {
+    type Output = Option<()>;
+    type State = AppData;
+    fn render(&self, _state: &Self::State, ctx: Ctx, fb: &mut FrameBuffer) {
+        text::StyledString::plain_text(self.header.clone()).render(&(), ctx, fb);
+        text::StyledString::plain_text(Self::NOTE.to_string()).render(&(), ctx.add_y(1), fb);
+        for (i, line) in self.lines.iter().enumerate() {
+            let x = (i / Self::ROWS * self.column_width) as i32;
+            let y = (i % Self::ROWS) as i32 + Self::LINES_Y;
+            text::StyledString::plain_text(line.clone()).render(&(), ctx.add_xy(x, y), fb);
+        }
+    }
+    fn update(&mut self, _state: &mut Self::State, _ctx: Ctx, _event: Event) -> Self::Output {
+        None
+    }
+    fn size(&self, _state: &Self::State, _ctx: Ctx) -> Size {
+        let num_columns = self.lines.len().div_ceil(Self::ROWS);
+        Size::new(
+            (num_columns * self.column_width)
+                .max(self.header.chars().count())
+                .max(Self::NOTE.len()) as u32,
+            Self::LINES_Y as u32 + Self::ROWS as u32,
+        )
+    }
+}
+
+fn help_dialog(keymap: &Keymap) -> CF<Option<()>, AppData> {
+    let component = cf(HelpComponent::new(keymap)).catch_escape_or_click_out();
+    pop_up_style(component, Some("Help".to_string())).map(|_| ())
+}
+
+fn save_as_dialog(output_path: &Path) -> CF<Option<Option<PathBuf>>, AppData> {
+    let text_field = cf(TextField::with_initial_string(
+        MessageComponent::<()>::WIDTH as u32,
+        output_path.display().to_string(),
+    ))
+    .ignore_state()
+    .with_title_horizontal(
+        styled_string("Enter path to save to:".to_string(), Style::plain_text()),
+        1,
+    )
+    .catch_escape_or_click_out();
+    pop_up_style(text_field, Some("Save As".to_string())).map(|result| {
+        result
+            .ok()
+            .filter(|path| !path.trim().is_empty())
+            .map(|path| PathBuf::from(path.trim()))
+    })
+}
+
+// Shows a message word-wrapped over multiple lines, followed by some optional choices which are
+// made by pressing a key or clicking on them
+struct MessageComponent<T> {
+    lines: Vec<String>,
+    choices: Vec<(KeyboardInput, String, T)>,
+}
+
+impl<T: Copy> MessageComponent<T> {
+    const WIDTH: usize = 60;
+
+    fn new(message: &str, choices: Vec<(KeyboardInput, String, T)>) -> Self {
+        let mut lines = Vec::new();
+        let mut line = String::new();
+        for word in message.split_whitespace() {
+            if !line.is_empty() && line.chars().count() + 1 + word.chars().count() > Self::WIDTH {
+                lines.push(std::mem::take(&mut line));
+            }
+            if !line.is_empty() {
+                line.push(' ');
+            }
+            line.push_str(word);
+        }
+        lines.push(line);
+        Self { lines, choices }
+    }
+
+    fn choices_y(&self) -> i32 {
+        self.lines.len() as i32 + 1
+    }
+}
+
+impl<T: Copy> Component for MessageComponent<T> {
+    type Output = Option<T>;
+    type State = AppData;
+    fn render(&self, _state: &Self::State, ctx: Ctx, fb: &mut FrameBuffer) {
+        for (y, line) in self.lines.iter().enumerate() {
+            text::StyledString::plain_text(line.clone()).render(&(), ctx.add_y(y as i32), fb);
+        }
+        for (i, (key, label, _)) in self.choices.iter().enumerate() {
+            let string = format!("[{}] {}", keymap::key_to_string(*key), label);
+            text::StyledString::plain_text(string).render(
+                &(),
+                ctx.add_y(self.choices_y() + i as i32),
+                fb,
+            );
+        }
+    }
+    fn update(&mut self, _state: &mut Self::State, ctx: Ctx, event: Event) -> Self::Output {
+        if let Some(keyboard_input) = event.keyboard_input() {
+            return self
+                .choices
+                .iter()
+                .find(|(key, _, _)| *key == keyboard_input)
+                .map(|&(_, _, choice)| choice);
+        }
+        if let Some(MouseInput::MousePress {
+            button: MouseButton::Left,
+            coord,
+        }) = event.mouse_input()
+        {
+            let y = coord.y - ctx.bounding_box.top_left().y - self.choices_y();
+            return usize::try_from(y)
+                .ok()
+                .and_then(|i| self.choices.get(i))
+                .map(|&(_, _, choice)| choice);
+        }
+        None
+    }
+    fn size(&self, _state: &Self::State, _ctx: Ctx) -> Size {
+        let height = if self.choices.is_empty() {
+            self.lines.len()
+        } else {
+            self.choices_y() as usize + self.choices.len()
+        };
+        Size::new(Self::WIDTH as u32, height as u32)
+    }
+}
+
+fn error_dialog(message: &str) -> CF<Option<()>, AppData> {
+    let choices = vec![(keys::RETURN, "Close".to_string(), ())];
+    let component = cf(MessageComponent::new(message, choices)).catch_escape_or_click_out();
+    pop_up_style(component, Some("Error".to_string())).map(|_| ())
+}
+
+// Returns whether to save before quitting, or None if quitting was cancelled
+fn quit_dialog(output_path: &Path) -> CF<Option<Option<bool>>, AppData> {
+    let message = format!(
+        "There are unsaved changes to \"{}\".",
+        output_path.display()
+    );
+    let choices = vec![
+        (KeyboardInput::Char('s'), "Save and quit".to_string(), true),
+        (
+            KeyboardInput::Char('q'),
+            "Quit without saving".to_string(),
+            false,
+        ),
+    ];
+    let component = cf(MessageComponent::new(&message, choices)).catch_escape_or_click_out();
+    pop_up_style(component, Some("Quit".to_string())).map(|result| result.ok())
+}
+
+fn delete_frame_dialog(frame_index: usize) -> CF<Option<Option<()>>, AppData> {
+    let message = format!(
+        "Frame {} and its undo history will be deleted. This can't be undone.",
+        frame_index + 1
+    );
+    let choices = vec![(KeyboardInput::Char('d'), "Delete the frame".to_string(), ())];
+    let component = cf(MessageComponent::new(&message, choices)).catch_escape_or_click_out();
+    pop_up_style(component, Some("Delete Frame".to_string())).map(|result| result.ok())
+}
+
+// Returns whether to restore the recovery file, or None to decide later
+fn recover_dialog(recovery_path: &Path) -> CF<Option<Option<bool>>, AppData> {
+    let message = format!(
+        "Found \"{}\" which has changes that weren't saved when the app last closed.",
+        recovery_path.display()
+    );
+    let choices = vec![
+        (
+            KeyboardInput::Char('r'),
+            "Restore the unsaved changes".to_string(),
+            true,
+        ),
+        (
+            KeyboardInput::Char('d'),
+            "Discard the unsaved changes".to_string(),
+            false,
+        ),
+    ];
+    let component = cf(MessageComponent::new(&message, choices)).catch_escape_or_click_out();
+    pop_up_style(component, Some("Recover".to_string())).map(|result| result.ok())
+}
+
+fn app_loop(initial_state: AppState) -> CF<Option<app::Exit>, AppData> {
+    loop_(initial_state, |state| match state {
+        AppState::Ui => gui_component().map(AppState::PopUp).continue_(),
+        AppState::PopUp(PopUp::FgOpacity) => on_state_then(|state: &mut AppData| {
+            opacity_dialog(
+                "Foreground Opacity".to_string(),
+                state.drawing_state.fg_opacity,
+            )
+            .map_side_effect(|opacity, data| match opacity {
+                Some(Ok(opacity)) => {
+                    data.drawing_state.fg_opacity = opacity;
+                    AppState::Ui
+                }
+                Some(Err(e)) => AppState::PopUp(PopUp::Error(e)),
+                None => AppState::Ui,
+            })
+            .continue_()
+        }),
+        AppState::PopUp(PopUp::BgOpacity) => on_state_then(|state: &mut AppData| {
+            opacity_dialog(
+                "Background Opacity".to_string(),
+                state.drawing_state.bg_opacity,
+            )
+            .map_side_effect(|opacity, data| match opacity {
+                Some(Ok(opacity)) => {
+                    data.drawing_state.bg_opacity = opacity;
+                    AppState::Ui
+                }
+                Some(Err(e)) => AppState::PopUp(PopUp::Error(e)),
+                None => AppState::Ui,
+            })
+            .continue_()
+        }),
+        AppState::PopUp(PopUp::LayerOpacity(index)) => on_state_then(move |state: &mut AppData| {
+            opacity_dialog(
+                "Layer Opacity".to_string(),
+                state.drawing_state.canvas().layers[index].opacity,
+            )
+            .map_side_effect(move |opacity, data| match opacity {
+                Some(Ok(opacity)) => {
+                    data.set_layer_opacity(index, opacity);
+                    AppState::Ui
+                }
+                Some(Err(e)) => AppState::PopUp(PopUp::Error(e)),
+                None => AppState::Ui,
+            })
+            .continue_()
+        }),
+        AppState::PopUp(PopUp::RenameLayer(index)) => on_state_then(move |state: &mut AppData| {
+            layer_name_dialog(state.drawing_state.canvas().layers[index].name.clone())
+                .map_side_effect(move |name, data| {
+                    if let Some(name) = name {
+                        data.rename_layer(index, name);
+                    }
+                })
+                .map_val(|| AppState::Ui)
+                .continue_()
+        }),
+        AppState::PopUp(PopUp::Fps) => on_state_then(|state: &mut AppData| {
+            fps_dialog(state.drawing_state.document.fps)
+                .map_side_effect(|fps, data| {
+                    if let Some(fps) = fps {
+                        data.drawing_state.document.fps = fps;
+                        data.mark_modified();
+                    }
+                })
+                .map_val(|| AppState::Ui)
+                .continue_()
+        }),
+        AppState::PopUp(PopUp::CanvasSize) => on_state_then(|state: &mut AppData| {
+            canvas_size_dialog(state.drawing_state.canvas().size())
+                .map_side_effect(|size_and_anchor, data| {
+                    if let Some((size, anchor)) = size_and_anchor {
+                        data.resize_canvas(size, anchor);
+                    }
+                })
+                .map_val(|| AppState::Ui)
+                .continue_()
+        }),
+        AppState::PopUp(PopUp::ColourPicker(row)) => on_state_then(move |state: &mut AppData| {
+            let (title, colour) = match row {
+                PaletteRow::Bg => ("Background Colour", state.get_bg().map(Rgba32::to_rgb24)),
+                _ => ("Foreground Colour", state.get_fg().map(Rgba32::to_rgb24)),
+            };
+            // start from the current colour if there is one
+            let colour = colour.unwrap_or(match row {
+                PaletteRow::Bg => state.drawing_state.custom.bg,
+                _ => state.drawing_state.custom.fg,
+            });
+            colour_picker_dialog(title.to_string(), colour)
+                .map_side_effect(move |result, data| {
+                    if let Some((colour, add_to_palette)) = result {
+                        if let Err(e) = data.set_custom_colour(row, colour, add_to_palette) {
+                            return AppState::PopUp(PopUp::Error(e));
+                        }
+                    }
+                    AppState::Ui
+                })
+                .continue_()
+        }),
+        AppState::PopUp(PopUp::PaletteEditor) => {
+            palette_editor_dialog().map_val(|| AppState::Ui).continue_()
+        }
+        AppState::PopUp(PopUp::CharPicker) => char_picker_dialog()
+            .map_side_effect(|ch, data| {
+                if let Some(ch) = ch {
+                    data.set_custom_char(ch);
+                }
+            })
+            .map_val(|| AppState::Ui)
+            .continue_(),
+        AppState::PopUp(PopUp::SaveAs) => on_state_then(|state: &mut AppData| {
+            save_as_dialog(&state.live_paths.output_path)
+                .map_side_effect(|path, data| match path.map(|path| data.save_as(path)) {
+                    Some(Err(e)) => AppState::PopUp(PopUp::Error(e)),
+                    _ => AppState::Ui,
+                })
+                .continue_()
+        }),
+        AppState::PopUp(PopUp::Error(message)) => {
+            error_dialog(&message).map_val(|| AppState::Ui).continue_()
+        }
+        AppState::PopUp(PopUp::Quit) => on_state_then(|state: &mut AppData| {
+            if !state.drawing_state.modified {
+                return break_(app::Exit);
+            }
+            quit_dialog(&state.live_paths.output_path).map_side_effect(|save, data| match save {
+                None => LoopControl::Continue(AppState::Ui),
+                Some(true) => match data.save() {
+                    Ok(()) => LoopControl::Break(app::Exit),
+                    Err(e) => LoopControl::Continue(AppState::PopUp(PopUp::Error(e))),
+                },
+                Some(false) => {
+                    data.remove_recovery_file();
+                    LoopControl::Break(app::Exit)
+                }
+            })
+        }),
+        AppState::PopUp(PopUp::Recover) => on_state_then(|state: &mut AppData| {
+            recover_dialog(&state.recovery_path())
+                .map_side_effect(|restore, data| {
+                    let result = match restore {
+                        Some(true) => data.restore_recovery_file(),
+                        Some(false) => {
+                            data.remove_recovery_file();
+                            Ok(())
+                        }
+                        // keep the recovery file until the next autosave
+                        None => Ok(()),
+                    };
+                    match result {
+                        Ok(()) => AppState::Ui,
+                        Err(e) => AppState::PopUp(PopUp::Error(e)),
+                    }
+                })
+                .continue_()
+        }),
+        AppState::PopUp(PopUp::DeleteFrame) => on_state_then(|state: &mut AppData| {
+            delete_frame_dialog(state.drawing_state.frame_index)
+                .map_side_effect(|delete, data| {
+                    if delete.is_some() {
+                        data.delete_frame();
+                    }
+                })
+                .map_val(|| AppState::Ui)
+                .continue_()
+        }),
+        AppState::PopUp(PopUp::Help) => on_state_then(|state: &mut AppData| {
+            help_dialog(&state.keymap)
+                .map_val(|| AppState::Ui)
+                .continue_()
+        }),
+        AppState::PopUp(PopUp::OnionSkinOpacity) => on_state_then(|state: &mut AppData| {
+            opacity_dialog(
+                "Onion Skin Opacity".to_string(),
+                state.drawing_state.onion_skin.opacity,
+            )
+            .map_side_effect(|opacity, data| match opacity {
+                Some(Ok(opacity)) => {
+                    data.drawing_state.onion_skin.opacity = opacity;
+                    AppState::Ui
+                }
+                Some(Err(e)) => AppState::PopUp(PopUp::Error(e)),
+                None => AppState::Ui,
+            })
+            .continue_()
+        }),
+    })
+}
+
+pub fn app(
+    palette_path: Option<PathBuf>,
+    input_path: Option<PathBuf>,
+    image_import: Option<ImageImport>,
+    size: Option<Size>,
+    output_path: PathBuf,
+    export_target: ExportTarget,
+    cell_aspect_ratio: f64,
+) -> Result<App, String> {
+    let app_data = AppData::new(
+        palette_path,
+        input_path,
+        image_import,
+        size,
+        output_path,
+        export_target,
+        cell_aspect_ratio,
+    )?;
+    let initial_state = if app_data.recovery_available() {
+        AppState::PopUp(PopUp::Recover)
+    } else {
+        AppState::Ui
+    };
+    // closing is handled by the app so it can ask about unsaved changes
+    Ok(app_loop(initial_state)
+        .with_state(app_data)
+        .clear_each_frame())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_PALETTE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/resources/test_palette.toml");
+
+    // A new drawing which is never saved
+    fn app_data(size: Size) -> AppData {
+        let output_path = std::env::temp_dir().join("text-paint-test.tp");
+        let export_target = ExportTarget {
+            path: None,
+            cell_size_px: Size::new(16, 16),
+        };
+        let palette_path = Some(PathBuf::from(TEST_PALETTE));
+        AppData::new(
+            palette_path,
+            None,
+            None,
+            Some(size),
+            output_path,
+            export_target,
+            1.,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn panning_keeps_the_view_within_the_canvas() {
+        let mut data = app_data(Size::new(20, 10));
+        data.set_view_size(Size::new(8, 4));
+        data.pan(Coord::new(5, 3));
+        assert_eq!(data.drawing_state.camera, Coord::new(5, 3));
+        data.pan(Coord::new(100, 100));
+        assert_eq!(data.drawing_state.camera, Coord::new(12, 6));
+        data.pan(Coord::new(-3, -100));
+        assert_eq!(data.drawing_state.camera, Coord::new(9, 0));
+        // the view can't scroll when it's larger than the canvas
+        data.set_view_size(Size::new(30, 4));
+        assert_eq!(data.drawing_state.camera, Coord::new(0, 0));
+    }
+
+    #[test]
+    fn selected_palette_entries_follow_their_values() {
+        let mut data = app_data(Size::new(4, 4));
+        let mut palette = data.palette.clone();
+        let indices = &mut data.drawing_state.palette_indices;
+        indices.ch = Some(PaletteIndex::Index(1));
+        indices.fg = Some(PaletteIndex::Index(1));
+        indices.bg = Some(PaletteIndex::None);
+        let ch = palette.ch[1];
+        palette.ch.insert(0, '!');
+        palette.fg.remove(1);
+        data.set_palette(palette);
+        let indices = &data.drawing_state.palette_indices;
+        assert!(matches!(indices.ch, Some(PaletteIndex::Index(2))));
+        assert_eq!(data.palette.ch[2], ch);
+        // a removed entry is replaced by whichever entry is now at its index
+        assert!(matches!(indices.fg, Some(PaletteIndex::Index(1))));
+        assert!(matches!(indices.bg, Some(PaletteIndex::None)));
+    }
+
+    #[test]
+    fn hsv_and_rgb_convert_both_ways() {
+        let black = Hsv {
+            h: 0.,
+            s: 0.,
+            v: 0.,
+        };
+        for r in (0..=255).step_by(15) {
+            for g in (0..=255).step_by(15) {
+                for b in (0..=255).step_by(15) {
+                    let rgb24 = Rgb24::new(r, g, b);
+                    assert_eq!(Hsv::from_rgb24(rgb24, black).to_rgb24(), rgb24);
+                }
+            }
+        }
+        let hsv = |h, s, v| Hsv { h, s, v }.to_rgb24();
+        assert_eq!(hsv(120., 1., 1.), Rgb24::new(0, 255, 0));
+        assert_eq!(hsv(360., 1., 1.), Rgb24::new(255, 0, 0));
+        assert_eq!(hsv(240., 0.5, 1.), Rgb24::new(128, 128, 255));
+    }
+
+    #[test]
+    fn greys_keep_the_previous_hue() {
+        let previous = Hsv {
+            h: 200.,
+            s: 0.5,
+            v: 0.5,
+        };
+        let grey = Hsv::from_rgb24(Rgb24::new_grey(100), previous);
+        assert_eq!((grey.h, grey.s), (200., 0.));
+        let black = Hsv::from_rgb24(Rgb24::new_grey(0), previous);
+        assert_eq!((black.h, black.s, black.v), (200., 0.5, 0.));
+        // raising the value of black restores the colour it had before
+        let hsv = ColourChannel::Value.set(black, 1.);
+        assert_eq!(hsv.to_rgb24(), Rgb24::new(128, 212, 255));
+        let hsv = ColourChannel::Green.set(hsv, 0.);
+        assert_eq!(hsv.to_rgb24(), Rgb24::new(128, 0, 255));
+        assert_eq!(ColourChannel::Blue.get(hsv), 1.);
+    }
+
+    #[test]
+    fn custom_colours_can_be_added_to_the_palette() {
+        let mut data = app_data(Size::new(4, 4));
+        let colour = Rgb24::new(1, 2, 3);
+        data.set_custom_colour(PaletteRow::Fg, colour, false)
+            .unwrap();
+        assert!(matches!(
+            data.drawing_state.palette_indices.fg,
+            Some(PaletteIndex::Custom)
+        ));
+        let len = data.palette.bg.len();
+        let palette_path =
+            std::env::temp_dir().join(format!("text-paint-custom-{}.toml", std::process::id()));
+        fs::copy(TEST_PALETTE, &palette_path).unwrap();
+        data.live_paths.palette_path = palette_path.clone();
+        data.set_custom_colour(PaletteRow::Bg, colour, true)
+            .unwrap();
+        let saved = Palette::load(&palette_path).unwrap();
+        fs::remove_file(&palette_path).unwrap();
+        assert_eq!(saved.bg[len], colour);
+        assert_eq!(data.palette.bg[len], colour);
+        assert!(matches!(
+            data.drawing_state.palette_indices.bg,
+            Some(PaletteIndex::Index(i)) if i == len
+        ));
+        // the colour is still used if the palette can't be saved
+        data.live_paths.palette_path = palette_path.join("missing.toml");
+        assert!(data
+            .set_custom_colour(PaletteRow::Bg, colour, true)
+            .is_err());
+        assert_eq!(data.palette.bg.len(), len + 1);
+        assert!(matches!(
+            data.drawing_state.palette_indices.bg,
+            Some(PaletteIndex::Custom)
+        ));
+    }
+
+    #[test]
+    fn characters_are_found_by_name_or_codepoint() {
+        let chars = |query| CharPage::search(query).unwrap().chars;
+        assert_eq!(chars("u+2588"), ['█']);
+        assert_eq!(chars("0x41"), ['A']);
+        assert_eq!(chars(" full  BLOCK "), ['█']);
+        let arrows = chars("double arrow");
+        assert!(arrows.contains(&'⇒'));
+        assert!(arrows.len() <= CharPickerComponent::COLUMNS * CharPickerComponent::ROWS);
+        // combining marks take up no space so they aren't found
+        assert!(CharPage::search("combining acute").is_err());
+        assert!(CharPage::search("u+110000").is_err());
+        assert!(CharPage::search("  ").is_err());
+        assert!(CharPage::search("no such character").is_err());
+    }
+
+    #[test]
+    fn default_keys_are_bound_to_one_action_each() {
+        let mut actions = HashMap::new();
+        for action in Action::all() {
+            for key in action.default_keys() {
+                if let Some(other) = actions.insert(key, action) {
+                    panic!(
+                        "{:?} is bound to {} and {}",
+                        key,
+                        other.name(),
+                        action.name()
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn pan_keys_move_the_keyboard_cursor_while_it_is_shown() {
+        let mut data = app_data(Size::new(20, 10));
+        data.set_view_size(Size::new(8, 4));
+        data.perform_action(Action::Pan(Coord::new(1, 0)));
+        assert_eq!(data.drawing_state.camera, Coord::new(1, 0));
+        assert_eq!(data.drawing_state.cursor, None);
+        // the cursor starts in the middle of the view
+        data.perform_action(Action::MoveCursor(Coord::new(0, 1)));
+        assert_eq!(data.drawing_state.cursor, Some(Coord::new(5, 2)));
+        data.perform_action(Action::Pan(Coord::new(0, 1)));
+        assert_eq!(data.drawing_state.cursor, Some(Coord::new(5, 3)));
+        assert_eq!(data.drawing_state.camera, Coord::new(1, 0));
+        // the view scrolls to keep the cursor in view, and the cursor stays on the canvas
+        for _ in 0..20 {
+            data.perform_action(Action::Pan(Coord::new(0, 1)));
+        }
+        assert_eq!(data.drawing_state.cursor, Some(Coord::new(5, 9)));
+        assert_eq!(data.drawing_state.camera, Coord::new(1, 6));
+    }
+
+    #[test]
+    fn unsaved_changes_are_autosaved_to_a_recovery_file() {
+        let dir = std::env::temp_dir().join(format!("text-paint-recovery-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut data = app_data(Size::new(4, 4));
+        data.live_paths.output_path = dir.join("drawing.tp");
+        let recovery_path = dir.join("drawing.tp.recovery");
+        assert_eq!(data.recovery_path(), recovery_path);
+        data.mark_modified();
+        data.check_autosave(Autosave::PERIOD / 2).unwrap();
+        assert!(!data.recovery_available());
+        data.check_autosave(Autosave::PERIOD / 2).unwrap();
+        assert!(data.recovery_available());
+        // the recovery file is restored as unsaved changes
+        data.drawing_state.modified = false;
+        data.restore_recovery_file().unwrap();
+        assert!(data.drawing_state.modified);
+        data.save().unwrap();
+        assert!(!recovery_path.exists());
+        assert!(!data.recovery_available());
+        assert!(!data.drawing_state.modified);
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
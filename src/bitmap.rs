@@ -0,0 +1,512 @@
+use crate::{drawing::Raster, fonts, palette::Palette};
+use ab_glyph::{point, Font, FontRef, PxScale, ScaleFont};
+use chargrid::prelude::*;
+use grid_2d::Grid;
+use rgb_int::Rgb24;
+use std::{fmt, str::FromStr};
+
+// The cell size used when none is specified. The CGA fonts are 8px high so multiples of 8 give
+// the crispest results.
+pub const DEFAULT_CELL_SIZE_PX: u32 = 16;
+
+struct Image {
+    pixels: Grid<Rgba32>,
+}
+
+impl Image {
+    fn new(size: Size) -> Self {
+        Self {
+            pixels: Grid::new_copy(size, Rgba32::new(0, 0, 0, 0)),
+        }
+    }
+
+    // Draws `colour` over the pixel at `coord`, scaling its alpha by `coverage`
+    fn blend(&mut self, coord: Coord, colour: Rgba32, coverage: f32) {
+        let colour = colour.with_a((colour.a as f32 * coverage.clamp(0., 1.)).round() as u8);
+        if colour.a == 0 {
+            return;
+        }
+        if let Some(pixel) = self.pixels.get_mut(coord) {
+            *pixel = colour.alpha_composite(*pixel);
+        }
+    }
+
+    fn fill(&mut self, top_left: Coord, size: Size, colour: Rgba32) {
+        for offset in size.coord_iter_row_major() {
+            self.blend(top_left + offset, colour, 1.);
+        }
+    }
+
+    fn encode(&self) -> Result<Vec<u8>, String> {
+        let mut data = Vec::new();
+        let mut encoder = png::Encoder::new(&mut data, self.pixels.width(), self.pixels.height());
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder
+            .write_header()
+            .map_err(|e| format!("failed to encode png ({})", e))?;
+        let bytes = self
+            .pixels
+            .iter()
+            .flat_map(|&Rgba32 { r, g, b, a }| [r, g, b, a])
+            .collect::<Vec<_>>();
+        writer
+            .write_image_data(&bytes)
+            .map_err(|e| format!("failed to encode png ({})", e))?;
+        writer
+            .finish()
+            .map_err(|e| format!("failed to encode png ({})", e))?;
+        Ok(data)
+    }
+}
+
+// Calls `f` with the coverage of each pixel of `character` drawn in a cell whose top-left pixel
+// is `top_left`. Pixels outside the cell are skipped so glyphs don't spill into neighbouring
+// cells.
+fn draw_character(
+    font: &FontRef,
+    character: char,
+    top_left: Coord,
+    cell_size_px: Size,
+    mut f: impl FnMut(Coord, f32),
+) {
+    let scale = PxScale {
+        x: cell_size_px.width() as f32,
+        y: cell_size_px.height() as f32,
+    };
+    let ascent = font.as_scaled(scale).ascent();
+    let glyph = font
+        .glyph_id(character)
+        .with_scale_and_position(scale, point(top_left.x as f32, top_left.y as f32 + ascent));
+    if let Some(outlined) = font.outline_glyph(glyph) {
+        let bounds = outlined.px_bounds();
+        let offset = Coord::new(bounds.min.x as i32, bounds.min.y as i32);
+        outlined.draw(|x, y, coverage| {
+            let pixel_coord = offset + Coord::new(x as i32, y as i32);
+            if cell_size_px.is_valid(pixel_coord - top_left) {
+                f(pixel_coord, coverage);
+            }
+        });
+    }
+}
+
+// Renders a grid of cells to a png image without using the gpu. Each cell is drawn as a
+// rectangle of `cell_size_px` pixels.
+pub fn export(grid: &Grid<RenderCell>, cell_size_px: Size) -> Result<Vec<u8>, String> {
+    let normal = FontRef::try_from_slice(fonts::NORMAL).expect("invalid font");
+    let bold = FontRef::try_from_slice(fonts::BOLD).expect("invalid font");
+    let mut image = Image::new(Size::new(
+        grid.width() * cell_size_px.width(),
+        grid.height() * cell_size_px.height(),
+    ));
+    for (coord, cell) in grid.enumerate() {
+        let top_left = Coord::new(
+            coord.x * cell_size_px.width() as i32,
+            coord.y * cell_size_px.height() as i32,
+        );
+        if let Some(background) = cell.style.background {
+            image.fill(top_left, cell_size_px, background);
+        }
+        let Some(foreground) = cell.style.foreground else {
+            continue;
+        };
+        if let Some(character) = cell.character {
+            let font = if cell.style.bold == Some(true) {
+                &bold
+            } else {
+                &normal
+            };
+            draw_character(
+                font,
+                character,
+                top_left,
+                cell_size_px,
+                |coord, coverage| image.blend(coord, foreground, coverage),
+            );
+        }
+        if cell.style.underline == Some(true) {
+            let height = cell_size_px.height() as f32;
+            let top = (height * fonts::UNDERLINE_TOP_OFFSET_CELL_RATIO as f32) as i32;
+            let thickness = ((height * fonts::UNDERLINE_WIDTH_CELL_RATIO as f32) as u32).max(1);
+            image.fill(
+                top_left + Coord::new(0, top),
+                Size::new(cell_size_px.width(), thickness),
+                foreground,
+            );
+        }
+    }
+    image.encode()
+}
+
+// How an image is turned into cells when importing
+#[derive(Clone, Copy, Debug)]
+pub enum ImportMode {
+    // Each cell shows two pixels using '▀' with the top pixel as the foreground colour and the
+    // bottom pixel as the background colour
+    HalfBlock,
+    // Each cell shows one pixel as a shade character picked by brightness
+    Shade,
+    // Each cell shows one pixel using the closest combination of palette colours and characters
+    Palette,
+}
+
+impl FromStr for ImportMode {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "half-block" => Ok(Self::HalfBlock),
+            "shade" => Ok(Self::Shade),
+            "palette" => Ok(Self::Palette),
+            _ => Err(format!(
+                "unknown import mode \"{}\" (expected half-block, shade or palette)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ImportMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Self::HalfBlock => "half-block",
+            Self::Shade => "shade",
+            Self::Palette => "palette",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+// The width in cells of imported images when no size is specified, unless the image is
+// narrower than this
+const DEFAULT_IMPORT_WIDTH: u32 = 80;
+
+const HALF_BLOCK: char = '▀';
+
+// Ordered from least to most coverage
+const SHADES: [Option<char>; 5] = [None, Some('░'), Some('▒'), Some('▓'), Some('█')];
+
+fn decode(data: &[u8]) -> Result<Grid<Rgba32>, String> {
+    let mut decoder = png::Decoder::new(data);
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder
+        .read_info()
+        .map_err(|e| format!("failed to decode png ({})", e))?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader
+        .next_frame(&mut buf)
+        .map_err(|e| format!("failed to decode png ({})", e))?;
+    let bytes = &buf[..info.buffer_size()];
+    let pixels: Vec<Rgba32> = match info.color_type {
+        png::ColorType::Rgba => bytes
+            .chunks_exact(4)
+            .map(|p| Rgba32::new(p[0], p[1], p[2], p[3]))
+            .collect(),
+        png::ColorType::Rgb => bytes
+            .chunks_exact(3)
+            .map(|p| Rgba32::new(p[0], p[1], p[2], 255))
+            .collect(),
+        png::ColorType::GrayscaleAlpha => bytes
+            .chunks_exact(2)
+            .map(|p| Rgba32::new(p[0], p[0], p[0], p[1]))
+            .collect(),
+        png::ColorType::Grayscale => bytes.iter().map(|&p| Rgba32::new(p, p, p, 255)).collect(),
+        png::ColorType::Indexed => {
+            return Err("failed to decode png (unexpanded palette)".to_string())
+        }
+    };
+    Ok(Grid::new_iterator(
+        Size::new(info.width, info.height),
+        pixels.into_iter(),
+    ))
+}
+
+// Scales an image to `size` by averaging the pixels that land on each output pixel
+fn resample(image: &Grid<Rgba32>, size: Size) -> Grid<Rgba32> {
+    // the range of source pixels covered by output pixel `i`
+    let range = |i: i32, from: u32, to: u32| {
+        let start = i as u64 * from as u64 / to as u64;
+        let end = ((i as u64 + 1) * from as u64 / to as u64).max(start + 1);
+        start as i32..end as i32
+    };
+    Grid::new_fn(size, |coord| {
+        let mut sum = [0u64; 4];
+        let mut count = 0;
+        for y in range(coord.y, image.height(), size.height()) {
+            for x in range(coord.x, image.width(), size.width()) {
+                let Rgba32 { r, g, b, a } = *image.get_checked(Coord::new(x, y));
+                // weight channels by alpha so transparent pixels don't darken their neighbours
+                sum[0] += r as u64 * a as u64;
+                sum[1] += g as u64 * a as u64;
+                sum[2] += b as u64 * a as u64;
+                sum[3] += a as u64;
+                count += 1;
+            }
+        }
+        if sum[3] == 0 {
+            return Rgba32::new(0, 0, 0, 0);
+        }
+        Rgba32::new(
+            (sum[0] / sum[3]) as u8,
+            (sum[1] / sum[3]) as u8,
+            (sum[2] / sum[3]) as u8,
+            (sum[3] / count) as u8,
+        )
+    })
+}
+
+fn luminance(Rgb24 { r, g, b }: Rgb24) -> f64 {
+    (0.299 * r as f64 + 0.587 * g as f64 + 0.114 * b as f64) / 255.
+}
+
+fn colour_distance_squared(a: [f64; 3], b: [f64; 3]) -> f64 {
+    (0..3).map(|i| (a[i] - b[i]).powi(2)).sum()
+}
+
+fn rgb24_to_array(Rgb24 { r, g, b }: Rgb24) -> [f64; 3] {
+    [r as f64, g as f64, b as f64]
+}
+
+// Picks cells by comparing pixels with each combination of palette colours and characters,
+// treating a cell as the mix of its foreground and background colours weighted by the fraction
+// of the cell covered by its character
+struct PaletteMatcher<'a> {
+    palette: &'a Palette,
+    coverages: Vec<f64>,
+}
+
+impl<'a> PaletteMatcher<'a> {
+    fn new(palette: &'a Palette) -> Self {
+        let font = FontRef::try_from_slice(fonts::NORMAL).expect("invalid font");
+        let cell_size_px = Size::new(DEFAULT_CELL_SIZE_PX, DEFAULT_CELL_SIZE_PX);
+        let coverages = palette
+            .ch
+            .iter()
+            .map(|&character| {
+                let mut total = 0.;
+                draw_character(&font, character, Coord::new(0, 0), cell_size_px, |_, c| {
+                    total += c.clamp(0., 1.) as f64
+                });
+                total / cell_size_px.count() as f64
+            })
+            .collect();
+        Self { palette, coverages }
+    }
+
+    fn cell(&self, pixel: Rgba32) -> RenderCell {
+        if pixel.a == 0 || self.palette.ch.is_empty() {
+            return RenderCell::BLANK;
+        }
+        let target = rgb24_to_array(pixel.to_rgb24());
+        let mut best: Option<(f64, RenderCell)> = None;
+        for &fg in &self.palette.fg {
+            for &bg in &self.palette.bg {
+                let fg_array = rgb24_to_array(fg);
+                let bg_array = rgb24_to_array(bg);
+                for (&character, &coverage) in self.palette.ch.iter().zip(&self.coverages) {
+                    let mixed =
+                        [0, 1, 2].map(|i| bg_array[i] + (fg_array[i] - bg_array[i]) * coverage);
+                    let distance = colour_distance_squared(mixed, target);
+                    if best.is_none_or(|(best_distance, _)| distance < best_distance) {
+                        let cell = RenderCell::BLANK
+                            .with_character(character)
+                            .with_foreground(fg.to_rgba32(255))
+                            .with_background(bg.to_rgba32(255));
+                        best = Some((distance, cell));
+                    }
+                }
+            }
+        }
+        best.map_or(RenderCell::BLANK, |(_, cell)| cell)
+    }
+}
+
+fn shade_cell(pixel: Rgba32) -> RenderCell {
+    let rgb24 = pixel.to_rgb24();
+    let brightness = luminance(rgb24) * (pixel.a as f64 / 255.);
+    let index = ((brightness * SHADES.len() as f64) as usize).min(SHADES.len() - 1);
+    let Some(character) = SHADES[index] else {
+        return RenderCell::BLANK;
+    };
+    // The shade character provides the brightness so the colour is scaled up to full intensity
+    // to keep just its hue
+    let max = rgb24.r.max(rgb24.g).max(rgb24.b).max(1) as u32;
+    let scale = |c: u8| (c as u32 * 255 / max) as u8;
+    RenderCell::BLANK
+        .with_character(character)
+        .with_foreground(Rgba32::new(
+            scale(rgb24.r),
+            scale(rgb24.g),
+            scale(rgb24.b),
+            255,
+        ))
+}
+
+// Converts a png image into cells. When `size` is `None` the image is scaled to at most
+// `DEFAULT_IMPORT_WIDTH` cells wide, preserving its aspect ratio given the aspect ratio of
+// cells.
+pub fn import(
+    data: &[u8],
+    mode: ImportMode,
+    size: Option<Size>,
+    cell_aspect_ratio: f64,
+    palette: &Palette,
+) -> Result<Raster, String> {
+    let image = decode(data)?;
+    if image.width() == 0 || image.height() == 0 {
+        return Err("image is empty".to_string());
+    }
+    let size = size.unwrap_or_else(|| {
+        let width = image.width().min(DEFAULT_IMPORT_WIDTH);
+        let height = (width as f64 * image.height() as f64
+            / (image.width() as f64 * cell_aspect_ratio))
+            .round() as u32;
+        Size::new(width, height.max(1))
+    });
+    if size.width() == 0 || size.height() == 0 {
+        return Err("size must be at least 1x1".to_string());
+    }
+    let grid = match mode {
+        ImportMode::HalfBlock => {
+            let pixels = resample(&image, Size::new(size.width(), size.height() * 2));
+            Grid::new_fn(size, |Coord { x, y }| {
+                let top = *pixels.get_checked(Coord::new(x, y * 2));
+                let bottom = *pixels.get_checked(Coord::new(x, y * 2 + 1));
+                if top.a == 0 && bottom.a == 0 {
+                    return RenderCell::BLANK;
+                }
+                RenderCell::BLANK
+                    .with_character(HALF_BLOCK)
+                    .with_foreground(top)
+                    .with_background(bottom)
+            })
+        }
+        ImportMode::Shade => {
+            let pixels = resample(&image, size);
+            Grid::new_fn(size, |coord| shade_cell(*pixels.get_checked(coord)))
+        }
+        ImportMode::Palette => {
+            let pixels = resample(&image, size);
+            let matcher = PaletteMatcher::new(palette);
+            Grid::new_fn(size, |coord| matcher.cell(*pixels.get_checked(coord)))
+        }
+    };
+    Ok(Raster { grid })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn export_draws_each_cell_as_a_block_of_pixels() {
+        let red = Rgba32::new(255, 0, 0, 255);
+        let blue = Rgba32::new(0, 0, 255, 255);
+        let cells = [
+            RenderCell::BLANK.with_background(red),
+            RenderCell::BLANK.with_character('█').with_foreground(blue),
+            RenderCell::BLANK,
+        ];
+        let grid = Grid::new_iterator(Size::new(3, 1), cells.into_iter());
+        let image = decode(&export(&grid, Size::new(8, 16)).unwrap()).unwrap();
+        assert_eq!(image.size(), Size::new(24, 16));
+        for y in 0..16 {
+            assert_eq!(*image.get_checked(Coord::new(0, y)), red);
+            assert_eq!(*image.get_checked(Coord::new(7, y)), red);
+            assert_eq!(*image.get_checked(Coord::new(12, y)), blue);
+            assert_eq!(image.get_checked(Coord::new(20, y)).a, 0);
+        }
+    }
+
+    fn png(size: Size, pixel: impl FnMut(Coord) -> Rgba32) -> Vec<u8> {
+        Image {
+            pixels: Grid::new_fn(size, pixel),
+        }
+        .encode()
+        .unwrap()
+    }
+
+    fn no_palette() -> Palette {
+        Palette {
+            fg: Vec::new(),
+            bg: Vec::new(),
+            ch: Vec::new(),
+        }
+    }
+
+    fn import_grid(data: &[u8], mode: ImportMode, size: Option<Size>) -> Grid<RenderCell> {
+        import(data, mode, size, 2., &no_palette()).unwrap().grid
+    }
+
+    #[test]
+    fn half_blocks_show_two_pixels_per_cell() {
+        let red = Rgba32::new(255, 0, 0, 255);
+        let blue = Rgba32::new(0, 0, 255, 255);
+        let data = png(Size::new(2, 4), |Coord { x, y }| match (x, y) {
+            (0, 0 | 1) => red,
+            (0, _) => blue,
+            _ => Rgba32::new(0, 0, 0, 0),
+        });
+        let grid = import_grid(&data, ImportMode::HalfBlock, Some(Size::new(2, 1)));
+        let cell = *grid.get_checked(Coord::new(0, 0));
+        assert_eq!(cell.character, Some(HALF_BLOCK));
+        assert_eq!(cell.foreground(), Some(red));
+        assert_eq!(cell.background(), Some(blue));
+        assert_eq!(*grid.get_checked(Coord::new(1, 0)), RenderCell::BLANK);
+    }
+
+    #[test]
+    fn shades_are_picked_by_brightness() {
+        let data = png(Size::new(3, 1), |Coord { x, .. }| match x {
+            0 => Rgba32::new(0, 0, 0, 255),
+            1 => Rgba32::new(64, 64, 0, 255),
+            _ => Rgba32::new(255, 255, 255, 255),
+        });
+        let grid = import_grid(&data, ImportMode::Shade, Some(Size::new(3, 1)));
+        let cells = grid.iter().copied().collect::<Vec<_>>();
+        assert_eq!(cells[0], RenderCell::BLANK);
+        assert_eq!(cells[1].character, Some('░'));
+        // the shade provides the brightness so the colour is scaled up
+        assert_eq!(cells[1].foreground(), Some(Rgba32::new(255, 255, 0, 255)));
+        assert_eq!(cells[2].character, Some('█'));
+    }
+
+    #[test]
+    fn palette_mode_picks_the_closest_combination() {
+        let palette = Palette {
+            fg: vec![Rgb24::new(255, 255, 255)],
+            bg: vec![Rgb24::new(0, 0, 0), Rgb24::new(0, 0, 255)],
+            ch: vec!['█', ' '],
+        };
+        let data = png(Size::new(3, 1), |Coord { x, .. }| match x {
+            0 => Rgba32::new(250, 250, 250, 255),
+            1 => Rgba32::new(0, 0, 200, 255),
+            _ => Rgba32::new(0, 0, 0, 0),
+        });
+        let grid = import(&data, ImportMode::Palette, None, 1., &palette)
+            .unwrap()
+            .grid;
+        let cells = grid.iter().copied().collect::<Vec<_>>();
+        assert_eq!(cells[0].character, Some('█'));
+        assert_eq!(cells[1].character, Some(' '));
+        assert_eq!(cells[1].background(), Some(Rgba32::new(0, 0, 255, 255)));
+        assert_eq!(cells[2], RenderCell::BLANK);
+    }
+
+    #[test]
+    fn default_import_size_keeps_the_aspect_ratio() {
+        let data = png(Size::new(200, 50), |_| Rgba32::new(255, 255, 255, 255));
+        let grid = import_grid(&data, ImportMode::Shade, None);
+        assert_eq!(grid.size(), Size::new(80, 10));
+        let data = png(Size::new(4, 3), |_| Rgba32::new(255, 255, 255, 255));
+        let grid = import_grid(&data, ImportMode::Shade, None);
+        assert_eq!(grid.size(), Size::new(4, 2));
+        assert!(import(
+            &data,
+            ImportMode::Shade,
+            Some(Size::new(0, 1)),
+            2.,
+            &no_palette()
+        )
+        .is_err());
+    }
+}
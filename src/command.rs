@@ -0,0 +1,196 @@
+use crate::{
+    document::Document,
+    export::{self, ExportTarget},
+};
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+// Commands which operate on a saved drawing without starting the ui
+#[derive(Clone, Copy, Debug)]
+pub enum Command {
+    // Renders a frame to the export path
+    Export,
+    // Saves the input in the format of the output path
+    Convert,
+    // Prints metadata about the input
+    Info,
+}
+
+impl FromStr for Command {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "export" => Ok(Self::Export),
+            "convert" => Ok(Self::Convert),
+            "info" => Ok(Self::Info),
+            _ => Err(format!(
+                "unknown command \"{}\" (expected export, convert or info)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Self::Export => "export",
+            Self::Convert => "convert",
+            Self::Info => "info",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+fn required<'a>(
+    path: &'a Option<PathBuf>,
+    command: Command,
+    name: &str,
+) -> Result<&'a Path, String> {
+    path.as_deref()
+        .ok_or_else(|| format!("the {} command requires --{}", command, name))
+}
+
+fn check_frame_index(document: &Document, frame_index: usize) -> Result<(), String> {
+    if frame_index < document.frames.len() {
+        Ok(())
+    } else {
+        Err(format!(
+            "frame {} doesn't exist (the drawing has {} frames)",
+            frame_index,
+            document.frames.len()
+        ))
+    }
+}
+
+fn info(document: &Document) {
+    let size = document.frames[0].canvas.size();
+    println!("size: {}x{}", size.width(), size.height());
+    match &document.palette_path {
+        Some(palette_path) => println!("palette: {}", palette_path.display()),
+        None => println!("palette: none"),
+    }
+    println!("frames: {}", document.frames.len());
+    println!("fps: {}", document.fps);
+    for (i, frame) in document.frames.iter().enumerate() {
+        println!(
+            "frame {}: {} layers, {} undo steps, {} redo steps",
+            i,
+            frame.canvas.layers.len(),
+            frame.undo_buffer.num_undo_steps(),
+            frame.undo_buffer.num_redo_steps(),
+        );
+    }
+}
+
+pub fn run(
+    command: Command,
+    palette_path: Option<PathBuf>,
+    input_path: Option<PathBuf>,
+    output_path: Option<PathBuf>,
+    export_target: ExportTarget,
+    frame_index: usize,
+) -> Result<(), String> {
+    let input_path = required(&input_path, command, "input")?;
+    let mut document = Document::load(input_path)?;
+    match command {
+        Command::Export => {
+            let export_path = required(&export_target.path, command, "export")?;
+            check_frame_index(&document, frame_index)?;
+            let grid = document.frames[frame_index].canvas.flatten().grid;
+            export::export_to_path(&grid, export_path, export_target.cell_size_px)
+        }
+        Command::Convert => {
+            let output_path = required(&output_path, command, "output")?;
+            check_frame_index(&document, frame_index)?;
+            if let Some(palette_path) = palette_path {
+                document.palette_path =
+                    Some(fs::canonicalize(&palette_path).unwrap_or(palette_path));
+            }
+            document.save(output_path, frame_index)
+        }
+        Command::Info => {
+            info(&document);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drawing::{Canvas, Frame};
+    use chargrid::prelude::*;
+
+    fn export_target(path: Option<PathBuf>) -> ExportTarget {
+        ExportTarget {
+            path,
+            cell_size_px: Size::new(8, 8),
+        }
+    }
+
+    #[test]
+    fn commands_are_parsed_from_their_names() {
+        for command in [Command::Export, Command::Convert, Command::Info] {
+            let parsed = command.to_string().parse::<Command>().unwrap();
+            assert_eq!(parsed.to_string(), command.to_string());
+        }
+        assert!("import".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn convert_writes_the_chosen_frame() {
+        let dir = std::env::temp_dir().join(format!("text-paint-command-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let input_path = dir.join("input.tp");
+        let output_path = dir.join("output.xp");
+        let mut document = Document::new(Size::new(3, 2));
+        document
+            .frames
+            .push(Frame::new(Canvas::new(Size::new(3, 2))));
+        let cell = RenderCell::BLANK
+            .with_character('x')
+            .with_foreground(Rgba32::new(255, 0, 0, 255))
+            .with_background(Rgba32::new(0, 0, 255, 255));
+        *document.frames[1].canvas.layers[0]
+            .raster
+            .grid
+            .get_checked_mut(Coord::new(1, 1)) = cell;
+        document.save(&input_path, 0).unwrap();
+        let convert = |frame_index| {
+            run(
+                Command::Convert,
+                None,
+                Some(input_path.clone()),
+                Some(output_path.clone()),
+                export_target(None),
+                frame_index,
+            )
+        };
+        assert_eq!(
+            convert(2).err().unwrap(),
+            "frame 2 doesn't exist (the drawing has 2 frames)"
+        );
+        convert(1).unwrap();
+        let converted = Document::load(&output_path).unwrap();
+        let grid = &converted.frames[0].canvas.layers[0].raster.grid;
+        assert_eq!(*grid.get_checked(Coord::new(1, 1)), cell);
+        assert_eq!(*grid.get_checked(Coord::new(0, 0)), RenderCell::BLANK);
+        let result = run(
+            Command::Export,
+            None,
+            Some(input_path),
+            None,
+            export_target(None),
+            0,
+        );
+        assert_eq!(
+            result.err().unwrap(),
+            "the export command requires --export"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
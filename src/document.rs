@@ -0,0 +1,420 @@
+use crate::{
+    ansi, atomic,
+    drawing::{Canvas, DrawingEventWithRenderCell, Frame, HistoryEvent, UndoBuffer},
+    xp,
+};
+use bincode::Options;
+use chargrid::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+// Every document file starts with this, followed by the format version as a little-endian u32
+const MAGIC: &[u8; 8] = b"TXTPAINT";
+
+// Increment this when changing anything that gets serialized into a document, and add a case
+// to `Document::from_bytes` which migrates the previous version
+const VERSION: u32 = 1;
+
+// The parts of the editor's state which are saved to files. Everything else (the selected tool,
+// hover state, clipboard, etc.) belongs to the editor session.
+#[derive(Serialize, Deserialize)]
+pub struct Document {
+    pub palette_path: Option<PathBuf>,
+    pub frames: Vec<Frame>,
+    pub fps: u32,
+}
+
+// Formats other than the document format which can be opened and saved directly
+enum ForeignFormat {
+    Xp,
+    Ansi,
+}
+
+impl ForeignFormat {
+    fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_lowercase();
+        match extension.as_str() {
+            "xp" => Some(Self::Xp),
+            "ans" => Some(Self::Ansi),
+            _ => None,
+        }
+    }
+}
+
+fn bincode_options() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .reject_trailing_bytes()
+}
+
+impl Document {
+    pub const DEFAULT_FPS: u32 = 8;
+
+    pub fn new(size: Size) -> Self {
+        Self::from_canvas(Canvas::new(size))
+    }
+
+    // A document with a single frame and no history
+    pub fn from_canvas(canvas: Canvas) -> Self {
+        Self {
+            palette_path: None,
+            frames: vec![Frame::new(canvas)],
+            fps: Self::DEFAULT_FPS,
+        }
+    }
+
+    // Files with the extension of a foreign format are converted into a document
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let data =
+            fs::read(path).map_err(|e| format!("failed to read \"{}\" ({})", path.display(), e))?;
+        let document = match ForeignFormat::from_path(path) {
+            Some(ForeignFormat::Xp) => xp::import(&data).map(Self::from_canvas),
+            Some(ForeignFormat::Ansi) => {
+                ansi::import(&data).map(|raster| Self::from_canvas(Canvas::from_raster(raster)))
+            }
+            None => Self::from_bytes(&data),
+        };
+        document.map_err(|e| format!("failed to load \"{}\" ({})", path.display(), e))
+    }
+
+    // Files with the extension of a foreign format are written in that format, which can only
+    // contain the frame at `frame_index`
+    pub fn save<P: AsRef<Path>>(&self, path: P, frame_index: usize) -> Result<(), String> {
+        let path = path.as_ref();
+        let canvas = &self.frames[frame_index].canvas;
+        let data = match ForeignFormat::from_path(path) {
+            Some(ForeignFormat::Xp) => xp::export(canvas)?,
+            Some(ForeignFormat::Ansi) => ansi::export_with_sauce(&canvas.flatten().grid),
+            None => self.to_bytes(),
+        };
+        atomic::write(path, data)
+            .map_err(|e| format!("failed to write \"{}\" ({})", path.display(), e))
+    }
+
+    // Foreign formats can't store everything in a document. This lists what saving to `path`
+    // would discard, apart from the undo history which is never saved in foreign formats.
+    pub fn discarded_by_saving<P: AsRef<Path>>(&self, path: P) -> Vec<&'static str> {
+        let mut ret = Vec::new();
+        let Some(format) = ForeignFormat::from_path(path.as_ref()) else {
+            return ret;
+        };
+        if self.frames.len() > 1 {
+            ret.push("all but the current frame");
+        }
+        let layers = || self.frames.iter().flat_map(|frame| &frame.canvas.layers);
+        if layers().any(|layer| !layer.visible) {
+            ret.push("layer visibility");
+        }
+        if layers().any(|layer| layer.opacity != 255) {
+            ret.push("layer opacity");
+        }
+        // ANSI art has a single layer so the layers are flattened
+        let multiple_layers = || {
+            self.frames
+                .iter()
+                .any(|frame| frame.canvas.layers.len() > 1)
+        };
+        if matches!(format, ForeignFormat::Ansi) && multiple_layers() {
+            ret.push("separate layers");
+        }
+        ret
+    }
+
+    // Only the document format keeps the undo history
+    pub fn saves_history<P: AsRef<Path>>(path: P) -> bool {
+        ForeignFormat::from_path(path.as_ref()).is_none()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&VERSION.to_le_bytes());
+        data.extend(
+            bincode_options()
+                .serialize(self)
+                .expect("failed to serialize document"),
+        );
+        data
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, String> {
+        let document = match data.strip_prefix(MAGIC) {
+            None => bincode_options()
+                .deserialize::<legacy::DrawingState>(data)
+                .map_err(|_| "not a text-paint document".to_string())?
+                .into(),
+            Some(data) => {
+                let (version, body) = data
+                    .split_first_chunk::<4>()
+                    .ok_or_else(|| "file is truncated".to_string())?;
+                match u32::from_le_bytes(*version) {
+                    VERSION => bincode_options()
+                        .deserialize(body)
+                        .map_err(|e| format!("file is corrupt: {}", e))?,
+                    version if version > VERSION => {
+                        return Err(format!(
+                        "file format version {} is newer than the latest supported version ({})",
+                        version, VERSION
+                    ))
+                    }
+                    version => return Err(format!("unknown file format version {}", version)),
+                }
+            }
+        };
+        Self::validate(document)
+    }
+
+    // Checks for anything that would otherwise cause a panic while editing
+    // Canvases can differ in size as the history may contain resizes, but the layers within a
+    // canvas must all be the same size
+    fn validate(self) -> Result<Self, String> {
+        if self.frames.is_empty() {
+            return Err("document has no frames".to_string());
+        }
+        let canvases = self
+            .frames
+            .iter()
+            .flat_map(|frame| [&frame.canvas, frame.undo_buffer.initial()]);
+        for canvas in canvases {
+            let Some(first_layer) = canvas.layers.first() else {
+                return Err("frame has no layers".to_string());
+            };
+            let size = first_layer.raster.grid.size();
+            for layer in &canvas.layers {
+                let grid = &layer.raster.grid;
+                if grid.size() != size || grid.len() != size.count() {
+                    return Err(format!("layer \"{}\" has the wrong size", layer.name));
+                }
+            }
+        }
+        Ok(self)
+    }
+}
+
+// Before documents had a header, files contained the editor's entire state serialized with
+// bincode. These types mirror the parts of that state which aren't shared with the current
+// drawing types, so that they can be decoded and converted. Most fields are only decoded in order
+// to skip over them.
+#[allow(dead_code)]
+mod legacy {
+    use crate::drawing::{DrawingEvent, DrawingEventWithRenderCell, Raster};
+    use chargrid::prelude::*;
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    enum PaletteIndex {
+        None,
+        Index(usize),
+    }
+
+    #[derive(Deserialize)]
+    struct PaletteIndices {
+        ch: Option<PaletteIndex>,
+        fg: Option<PaletteIndex>,
+        bg: Option<PaletteIndex>,
+    }
+
+    #[derive(Deserialize)]
+    enum Tool {
+        Pencil,
+        Line,
+        Fill,
+        Erase,
+        Eyedrop,
+    }
+
+    #[derive(Deserialize)]
+    pub struct UndoBuffer {
+        pub initial: Raster,
+        pub events: Vec<DrawingEventWithRenderCell>,
+        pub redo_buffer: Vec<DrawingEventWithRenderCell>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct DrawingState {
+        palette_indices: PaletteIndices,
+        tools: Vec<Tool>,
+        tool_index: usize,
+        pub canvas_state: Raster,
+        current_event: Option<DrawingEvent>,
+        pub undo_buffer: UndoBuffer,
+        eyedrop_render_cell: Option<RenderCell>,
+        fg_opacity: u8,
+        bg_opacity: u8,
+        palette_hover: PaletteIndices,
+        tool_hover: Option<usize>,
+        canvas_hover: Option<Coord>,
+    }
+}
+
+// Legacy documents had a single frame with a single layer
+impl From<legacy::DrawingState> for Document {
+    fn from(state: legacy::DrawingState) -> Self {
+        let legacy::UndoBuffer {
+            initial,
+            events,
+            redo_buffer,
+        } = state.undo_buffer;
+        let history = |events: Vec<DrawingEventWithRenderCell>| {
+            events
+                .into_iter()
+                .map(|event| HistoryEvent::Drawing {
+                    layer_index: 0,
+                    event: DrawingEventWithRenderCell {
+                        drawing_event: event.drawing_event.into_legacy(),
+                        ..event
+                    },
+                })
+                .collect()
+        };
+        let undo_buffer = UndoBuffer::from_history(
+            Canvas::from_raster(initial),
+            history(events),
+            history(redo_buffer),
+        );
+        Self {
+            palette_path: None,
+            frames: vec![Frame {
+                canvas: Canvas::from_raster(state.canvas_state),
+                undo_buffer,
+            }],
+            fps: Self::DEFAULT_FPS,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drawing::{DrawingEvent, Layer, Raster};
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    // Saved (then gzipped) by the editor before documents had a header. It has a pencil stroke,
+    // an erase and a fill which covers the erased cells, followed by a line which was undone.
+    const LEGACY_DOCUMENT: &[u8] = include_bytes!("../resources/test_legacy_document.tp.gz");
+
+    fn legacy_document() -> Document {
+        let mut data = Vec::new();
+        GzDecoder::new(LEGACY_DOCUMENT)
+            .read_to_end(&mut data)
+            .unwrap();
+        Document::from_bytes(&data).unwrap()
+    }
+
+    fn error(data: &[u8]) -> String {
+        Document::from_bytes(data).err().expect("expected an error")
+    }
+
+    #[test]
+    fn legacy_documents_are_migrated_with_their_history() {
+        let mut document = legacy_document();
+        assert_eq!(document.frames.len(), 1);
+        let Frame {
+            canvas,
+            undo_buffer,
+        } = &mut document.frames[0];
+        assert_eq!(canvas.layers.len(), 1);
+        assert_eq!(canvas.layers[0].raster.grid.size(), Size::new(80, 60));
+        assert_eq!(undo_buffer.num_undo_steps(), 3);
+        assert_eq!(undo_buffer.num_redo_steps(), 1);
+        let saved = canvas.clone();
+        let erased = |canvas: &Canvas| *canvas.layers[0].raster.grid.get_checked(Coord::new(2, 0));
+        // undo the fill so the erased cell is visible
+        undo_buffer.undo(canvas);
+        let Some(HistoryEvent::Drawing { event, .. }) = undo_buffer.next_undo_event() else {
+            panic!("expected a drawing event");
+        };
+        assert!(matches!(event.drawing_event, DrawingEvent::LegacyErase(_)));
+        // erasing used to paint cells black rather than making them transparent
+        assert_eq!(erased(canvas).character, None);
+        assert_eq!(erased(canvas).background(), Some(Rgba32::new_grey(0)));
+        undo_buffer.undo(canvas);
+        assert_eq!(erased(canvas).character, Some('@'));
+        undo_buffer.undo(canvas);
+        assert!(canvas == undo_buffer.initial());
+        for _ in 0..3 {
+            undo_buffer.redo(canvas);
+        }
+        assert!(*canvas == saved);
+        let Some(HistoryEvent::Drawing { event, .. }) = undo_buffer.next_redo_event() else {
+            panic!("expected a drawing event");
+        };
+        assert!(matches!(event.drawing_event, DrawingEvent::Line(_)));
+    }
+
+    #[test]
+    fn documents_start_with_a_header() {
+        let mut canvas = Canvas::new(Size::new(3, 2));
+        canvas.layers.push(Layer::new(
+            "top".to_string(),
+            Raster::new_transparent(Size::new(3, 2)),
+        ));
+        let data = Document::from_canvas(canvas.clone()).to_bytes();
+        assert_eq!(&data[..8], b"TXTPAINT");
+        assert_eq!(data[8..12], VERSION.to_le_bytes());
+        let document = Document::from_bytes(&data).unwrap();
+        assert!(document.frames[0].canvas == canvas);
+        assert_eq!(document.to_bytes(), data);
+    }
+
+    #[test]
+    fn unreadable_files_are_rejected() {
+        assert_eq!(error(b"hello"), "not a text-paint document");
+        assert_eq!(error(b"TXTPAINT\x01\x00"), "file is truncated");
+        let mut data = Document::new(Size::new(1, 1)).to_bytes();
+        data[8..12].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert_eq!(
+            error(&data),
+            format!(
+                "file format version {} is newer than the latest supported version ({})",
+                VERSION + 1,
+                VERSION
+            )
+        );
+    }
+
+    #[test]
+    fn invalid_documents_are_rejected() {
+        let mut document = Document::new(Size::new(2, 2));
+        document.frames.clear();
+        assert_eq!(error(&document.to_bytes()), "document has no frames");
+        let mut canvas = Canvas::new(Size::new(2, 2));
+        canvas.layers.push(Layer::new(
+            "small".to_string(),
+            Raster::new_transparent(Size::new(1, 2)),
+        ));
+        let document = Document::from_canvas(canvas);
+        assert_eq!(
+            error(&document.to_bytes()),
+            "layer \"small\" has the wrong size"
+        );
+    }
+
+    #[test]
+    fn saving_to_a_foreign_format_lists_what_would_be_discarded() {
+        let mut document = Document::new(Size::new(2, 2));
+        assert!(document.discarded_by_saving("a.xp").is_empty());
+        document
+            .frames
+            .push(Frame::new(Canvas::new(Size::new(2, 2))));
+        document.frames[0].canvas.layers[0].opacity = 128;
+        assert_eq!(
+            document.discarded_by_saving("a.XP"),
+            ["all but the current frame", "layer opacity"]
+        );
+        assert!(document.discarded_by_saving("a.tp").is_empty());
+        assert!(document.discarded_by_saving("a.xp.recovery").is_empty());
+        let mut canvas = Canvas::new(Size::new(2, 2));
+        canvas.layers.push(Layer::new(
+            "top".to_string(),
+            Raster::new_transparent(Size::new(2, 2)),
+        ));
+        let document = Document::from_canvas(canvas);
+        assert!(document.discarded_by_saving("a.xp").is_empty());
+        assert_eq!(document.discarded_by_saving("a.ans"), ["separate layers"]);
+    }
+}
@@ -0,0 +1,2387 @@
+use chargrid::{input::keys, prelude::*};
+use grid_2d::Grid;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    iter, mem,
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RectangleMode {
+    Outline,
+    Filled,
+    BoxDrawing,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EllipseMode {
+    Outline,
+    Filled,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BrushShape {
+    Square,
+    Circle,
+    Diamond,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Ch,
+    Fg,
+    Bg,
+    Bold,
+    Underline,
+}
+
+impl Channel {
+    pub const ALL: [Self; 5] = [Self::Ch, Self::Fg, Self::Bg, Self::Bold, Self::Underline];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Ch => "ch",
+            Self::Fg => "fg",
+            Self::Bg => "bg",
+            Self::Bold => "bold",
+            Self::Underline => "underline",
+        }
+    }
+}
+
+// The channels of each cell which drawing leaves untouched
+#[derive(Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChannelLocks {
+    ch: bool,
+    fg: bool,
+    bg: bool,
+    bold: bool,
+    underline: bool,
+}
+
+impl ChannelLocks {
+    pub fn get(&self, channel: Channel) -> bool {
+        match channel {
+            Channel::Ch => self.ch,
+            Channel::Fg => self.fg,
+            Channel::Bg => self.bg,
+            Channel::Bold => self.bold,
+            Channel::Underline => self.underline,
+        }
+    }
+
+    pub fn toggle(&mut self, channel: Channel) {
+        let locked = match channel {
+            Channel::Ch => &mut self.ch,
+            Channel::Fg => &mut self.fg,
+            Channel::Bg => &mut self.bg,
+            Channel::Bold => &mut self.bold,
+            Channel::Underline => &mut self.underline,
+        };
+        *locked = !*locked;
+    }
+
+    pub fn any(&self) -> bool {
+        *self != Self::default()
+    }
+
+    // Clears the locked channels of a cell that is about to be drawn, so that stacking it leaves
+    // them alone. This is only an approximation as stacking can still change the foreground.
+    pub fn mask(&self, render_cell: RenderCell) -> RenderCell {
+        self.restore(RenderCell::BLANK, render_cell)
+    }
+
+    // Takes the locked channels from `before` and the rest from `after`
+    fn restore(&self, before: RenderCell, after: RenderCell) -> RenderCell {
+        let pick = |locked: bool| if locked { before } else { after };
+        RenderCell {
+            character: pick(self.ch).character,
+            style: Style {
+                foreground: pick(self.fg).style.foreground,
+                background: pick(self.bg).style.background,
+                bold: pick(self.bold).style.bold,
+                underline: pick(self.underline).style.underline,
+            },
+        }
+    }
+}
+
+// The cells stamped around each point of a pencil or erase stroke
+#[derive(Clone, Serialize, Deserialize)]
+pub enum Brush {
+    Shape { shape: BrushShape, size: u32 },
+    // cells copied from the canvas which are drawn in place of the selected palette entries
+    Custom(Grid<RenderCell>),
+}
+
+impl Brush {
+    // Single cell brushes use the original pencil and erase events
+    pub fn is_single_cell(&self) -> bool {
+        matches!(self, Self::Shape { size: 1, .. })
+    }
+
+    // The offsets of the cells covered by the brush when it is centred on a coord. Brushes with
+    // an even size extend further to the right and down.
+    pub fn footprint(&self) -> Vec<Coord> {
+        match self {
+            Self::Shape { shape, size } => {
+                let size = *size as i32;
+                let centre = (size - 1) as f64 / 2.0;
+                let radius = size as f64 / 2.0;
+                Size::new(size as u32, size as u32)
+                    .coord_iter_row_major()
+                    .filter(|coord| {
+                        let dx = (coord.x as f64 - centre).abs();
+                        let dy = (coord.y as f64 - centre).abs();
+                        match shape {
+                            BrushShape::Square => true,
+                            // shrink the radius slightly so small circles aren't square
+                            BrushShape::Circle => {
+                                dx * dx + dy * dy <= (radius - 0.25) * (radius - 0.25)
+                            }
+                            BrushShape::Diamond => dx + dy <= radius,
+                        }
+                    })
+                    .map(|coord| coord - Coord::new((size - 1) / 2, (size - 1) / 2))
+                    .collect()
+            }
+            Self::Custom(cells) => Self::custom_cells(cells).map(|(coord, _)| coord).collect(),
+        }
+    }
+
+    // Transparent cells of custom brushes are skipped so they don't erase anything
+    fn custom_cells(cells: &Grid<RenderCell>) -> impl '_ + Iterator<Item = (Coord, RenderCell)> {
+        let centre = (cells.size().to_coord().unwrap() - Coord::new(1, 1)) / 2;
+        cells
+            .enumerate()
+            .filter(|&(_, &cell)| cell != RenderCell::BLANK)
+            .map(move |(coord, &cell)| (coord - centre, cell))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PencilEvent {
+    coords: HashMap<Coord, u32>,
+    last_coord: Coord,
+}
+
+impl PencilEvent {
+    fn mouse_press(coord: Coord) -> Self {
+        Self {
+            coords: iter::once((coord, 1)).collect(),
+            last_coord: coord,
+        }
+    }
+    fn mouse_move(&mut self, coord: Coord) {
+        if coord != self.last_coord {
+            let iter =
+                line_2d::LineSegment::new(self.last_coord, coord).config_iter(line_2d::Config {
+                    exclude_start: true,
+                    exclude_end: false,
+                });
+            for coord in iter {
+                *self.coords.entry(coord).or_insert(0) += 1;
+            }
+            self.last_coord = coord;
+        }
+    }
+    fn commit(&self, render_cell: RenderCell, raster: &mut Raster) {
+        for (&coord, &count) in self.coords.iter() {
+            for _ in 0..count {
+                raster.set_coord(coord, render_cell);
+            }
+        }
+    }
+    fn preview(
+        &self,
+        raster: &Raster,
+        render_cell: RenderCell,
+        view: CanvasView,
+        fb: &mut FrameBuffer,
+    ) {
+        for (&coord, &count) in self.coords.iter() {
+            // chargrid's alpha compositing doesn't blend foreground colours so fake it here
+            if let Some(&stacked_render_cell) = raster.grid.get(coord) {
+                let mut stacked_render_cell = stacked_render_cell;
+                for _ in 0..count {
+                    stacked_render_cell =
+                        Raster::stack_render_cells(stacked_render_cell, render_cell);
+                }
+                view.set_cell(fb, coord, 0, stacked_render_cell);
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct FillEvent {
+    start: Coord,
+}
+
+impl FillEvent {
+    fn mouse_press(coord: Coord) -> Self {
+        Self { start: coord }
+    }
+    fn mouse_move(&mut self, coord: Coord) {
+        self.start = coord;
+    }
+    fn commit(&self, render_cell: RenderCell, raster: &mut Raster) {
+        for coord in raster.flood_fill(self.start) {
+            raster.set_coord(coord, render_cell);
+        }
+    }
+    // The region to fill is found in the current layer but shown on top of all visible layers
+    fn preview(
+        &self,
+        layer: &Raster,
+        composite: &Raster,
+        render_cell: RenderCell,
+        view: CanvasView,
+        fb: &mut FrameBuffer,
+    ) {
+        for coord in layer.flood_fill(self.start) {
+            if let Some(&current_cell) = composite.grid.get(coord) {
+                let stacked_render_cell = Raster::stack_render_cells(current_cell, render_cell);
+                view.set_cell(fb, coord, 0, stacked_render_cell);
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LineEvent {
+    start: Coord,
+    end: Coord,
+}
+
+impl LineEvent {
+    fn mouse_press(coord: Coord) -> Self {
+        Self {
+            start: coord,
+            end: coord,
+        }
+    }
+    fn mouse_move(&mut self, coord: Coord) {
+        self.end = coord;
+    }
+    fn commit(&self, render_cell: RenderCell, raster: &mut Raster) {
+        for coord in line_2d::coords_between(self.start, self.end) {
+            raster.set_coord(coord, render_cell);
+        }
+    }
+    fn preview(
+        &self,
+        raster: &Raster,
+        render_cell: RenderCell,
+        view: CanvasView,
+        fb: &mut FrameBuffer,
+    ) {
+        for coord in line_2d::coords_between(self.start, self.end) {
+            if let Some(&current_cell) = raster.grid.get(coord) {
+                let stacked_render_cell = Raster::stack_render_cells(current_cell, render_cell);
+                view.set_cell(fb, coord, 0, stacked_render_cell);
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RectangleEvent {
+    start: Coord,
+    end: Coord,
+    mode: RectangleMode,
+}
+
+impl RectangleEvent {
+    fn mouse_press(coord: Coord, mode: RectangleMode) -> Self {
+        Self {
+            start: coord,
+            end: coord,
+            mode,
+        }
+    }
+    fn mouse_move(&mut self, coord: Coord) {
+        self.end = coord;
+    }
+    // Returns each coord covered by the rectangle along with a character which overrides the
+    // character of the current render cell (only used in box drawing mode)
+    fn coords(&self) -> Vec<(Coord, Option<char>)> {
+        let left = self.start.x.min(self.end.x);
+        let right = self.start.x.max(self.end.x);
+        let top = self.start.y.min(self.end.y);
+        let bottom = self.start.y.max(self.end.y);
+        let mut ret = Vec::new();
+        for y in top..=bottom {
+            for x in left..=right {
+                let on_x_edge = x == left || x == right;
+                let on_y_edge = y == top || y == bottom;
+                if !(on_x_edge || on_y_edge) && self.mode != RectangleMode::Filled {
+                    continue;
+                }
+                let ch = if self.mode == RectangleMode::BoxDrawing {
+                    let ch = if left == right && top == bottom {
+                        '□'
+                    } else if top == bottom {
+                        '─'
+                    } else if left == right {
+                        '│'
+                    } else if (x, y) == (left, top) {
+                        '┌'
+                    } else if (x, y) == (right, top) {
+                        '┐'
+                    } else if (x, y) == (left, bottom) {
+                        '└'
+                    } else if (x, y) == (right, bottom) {
+                        '┘'
+                    } else if on_y_edge {
+                        '─'
+                    } else {
+                        '│'
+                    };
+                    Some(ch)
+                } else {
+                    None
+                };
+                ret.push((Coord::new(x, y), ch));
+            }
+        }
+        ret
+    }
+    fn commit(&self, render_cell: RenderCell, raster: &mut Raster) {
+        for (coord, ch) in self.coords() {
+            let render_cell = RenderCell {
+                character: ch.or(render_cell.character),
+                ..render_cell
+            };
+            raster.set_coord(coord, render_cell);
+        }
+    }
+    fn preview(
+        &self,
+        raster: &Raster,
+        render_cell: RenderCell,
+        view: CanvasView,
+        fb: &mut FrameBuffer,
+    ) {
+        for (coord, ch) in self.coords() {
+            if let Some(&current_cell) = raster.grid.get(coord) {
+                let render_cell = RenderCell {
+                    character: ch.or(render_cell.character),
+                    ..render_cell
+                };
+                let stacked_render_cell = Raster::stack_render_cells(current_cell, render_cell);
+                view.set_cell(fb, coord, 0, stacked_render_cell);
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct EllipseEvent {
+    start: Coord,
+    end: Coord,
+    mode: EllipseMode,
+    // When present, the height of the ellipse is derived from its width such that it appears
+    // circular on a backend whose cells have this aspect ratio (height / width)
+    aspect_ratio: Option<f64>,
+}
+
+impl EllipseEvent {
+    fn mouse_press(coord: Coord, mode: EllipseMode, aspect_ratio: Option<f64>) -> Self {
+        Self {
+            start: coord,
+            end: coord,
+            mode,
+            aspect_ratio,
+        }
+    }
+    fn mouse_move(&mut self, coord: Coord) {
+        self.end = coord;
+    }
+    fn corrected_end(&self) -> Coord {
+        if let Some(aspect_ratio) = self.aspect_ratio {
+            let width = (self.end.x - self.start.x).abs() + 1;
+            let height = ((width as f64 / aspect_ratio).round() as i32).max(1);
+            let dy = if self.end.y < self.start.y {
+                -(height - 1)
+            } else {
+                height - 1
+            };
+            Coord::new(self.end.x, self.start.y + dy)
+        } else {
+            self.end
+        }
+    }
+    fn coords(&self) -> Vec<Coord> {
+        let end = self.corrected_end();
+        let left = self.start.x.min(end.x);
+        let right = self.start.x.max(end.x);
+        let top = self.start.y.min(end.y);
+        let bottom = self.start.y.max(end.y);
+        let centre_x = (left + right + 1) as f64 / 2.;
+        let centre_y = (top + bottom + 1) as f64 / 2.;
+        let radius_x = (right - left + 1) as f64 / 2.;
+        let radius_y = (bottom - top + 1) as f64 / 2.;
+        // a cell is inside the ellipse if its centre is inside the ellipse
+        let inside = |Coord { x, y }: Coord| {
+            let dx = (x as f64 + 0.5 - centre_x) / radius_x;
+            let dy = (y as f64 + 0.5 - centre_y) / radius_y;
+            dx * dx + dy * dy <= 1.
+        };
+        let mut ret = Vec::new();
+        for y in top..=bottom {
+            for x in left..=right {
+                let coord = Coord::new(x, y);
+                if !inside(coord) {
+                    continue;
+                }
+                let on_outline =
+                    direction::CardinalDirection::all().any(|d| !inside(coord + d.coord()));
+                if on_outline || self.mode == EllipseMode::Filled {
+                    ret.push(coord);
+                }
+            }
+        }
+        ret
+    }
+    fn commit(&self, render_cell: RenderCell, raster: &mut Raster) {
+        for coord in self.coords() {
+            raster.set_coord(coord, render_cell);
+        }
+    }
+    fn preview(
+        &self,
+        raster: &Raster,
+        render_cell: RenderCell,
+        view: CanvasView,
+        fb: &mut FrameBuffer,
+    ) {
+        for coord in self.coords() {
+            if let Some(&current_cell) = raster.grid.get(coord) {
+                let stacked_render_cell = Raster::stack_render_cells(current_cell, render_cell);
+                view.set_cell(fb, coord, 0, stacked_render_cell);
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TextEvent {
+    origin: Coord,
+    lines: Vec<Vec<char>>,
+    // position of the caret relative to the origin, where x is the column and y is the line
+    caret: Coord,
+}
+
+impl TextEvent {
+    fn mouse_press(coord: Coord) -> Self {
+        Self {
+            origin: coord,
+            lines: vec![Vec::new()],
+            caret: Coord::new(0, 0),
+        }
+    }
+    fn is_empty(&self) -> bool {
+        self.lines.iter().all(|line| line.is_empty())
+    }
+    pub fn keyboard_input(&mut self, keyboard_input: KeyboardInput) {
+        let row = self.caret.y as usize;
+        let col = self.caret.x as usize;
+        match keyboard_input {
+            keys::RETURN => {
+                let rest = self.lines[row].split_off(col);
+                self.lines.insert(row + 1, rest);
+                self.caret = Coord::new(0, row as i32 + 1);
+            }
+            keys::BACKSPACE => {
+                if col > 0 {
+                    self.lines[row].remove(col - 1);
+                    self.caret.x -= 1;
+                } else if row > 0 {
+                    let line = self.lines.remove(row);
+                    let prev_len = self.lines[row - 1].len();
+                    self.lines[row - 1].extend(line);
+                    self.caret = Coord::new(prev_len as i32, row as i32 - 1);
+                }
+            }
+            KeyboardInput::Delete => {
+                if col < self.lines[row].len() {
+                    self.lines[row].remove(col);
+                } else if row + 1 < self.lines.len() {
+                    let line = self.lines.remove(row + 1);
+                    self.lines[row].extend(line);
+                }
+            }
+            KeyboardInput::Left => {
+                if col > 0 {
+                    self.caret.x -= 1;
+                } else if row > 0 {
+                    self.caret = Coord::new(self.lines[row - 1].len() as i32, row as i32 - 1);
+                }
+            }
+            KeyboardInput::Right => {
+                if col < self.lines[row].len() {
+                    self.caret.x += 1;
+                } else if row + 1 < self.lines.len() {
+                    self.caret = Coord::new(0, row as i32 + 1);
+                }
+            }
+            KeyboardInput::Up if row > 0 => {
+                self.caret.y -= 1;
+                self.caret.x = self.caret.x.min(self.lines[row - 1].len() as i32);
+            }
+            KeyboardInput::Down if row + 1 < self.lines.len() => {
+                self.caret.y += 1;
+                self.caret.x = self.caret.x.min(self.lines[row + 1].len() as i32);
+            }
+            KeyboardInput::Home => self.caret.x = 0,
+            KeyboardInput::End => self.caret.x = self.lines[row].len() as i32,
+            KeyboardInput::Char(ch) if !ch.is_control() => {
+                self.lines[row].insert(col, ch);
+                self.caret.x += 1;
+            }
+            _ => (),
+        }
+    }
+    fn coords(&self) -> impl '_ + Iterator<Item = (Coord, char)> {
+        self.lines.iter().enumerate().flat_map(move |(y, line)| {
+            line.iter()
+                .enumerate()
+                .map(move |(x, &ch)| (self.origin + Coord::new(x as i32, y as i32), ch))
+        })
+    }
+    fn commit(&self, render_cell: RenderCell, raster: &mut Raster) {
+        for (coord, ch) in self.coords() {
+            raster.set_coord(coord, render_cell.with_character(ch));
+        }
+    }
+    fn preview(
+        &self,
+        raster: &Raster,
+        render_cell: RenderCell,
+        view: CanvasView,
+        fb: &mut FrameBuffer,
+    ) {
+        for (coord, ch) in self.coords() {
+            if let Some(&current_cell) = raster.grid.get(coord) {
+                let stacked_render_cell =
+                    Raster::stack_render_cells(current_cell, render_cell.with_character(ch));
+                view.set_cell(fb, coord, 0, stacked_render_cell);
+            }
+        }
+        let caret_coord = self.origin + self.caret;
+        let caret_character = self.lines[self.caret.y as usize]
+            .get(self.caret.x as usize)
+            .copied();
+        let caret_render_cell = RenderCell {
+            character: caret_character,
+            style: Style::default()
+                .with_foreground(Rgba32::new_grey(0))
+                .with_background(Rgba32::new_grey(255)),
+        };
+        view.set_cell(fb, caret_coord, 1, caret_render_cell);
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Selection {
+    pub top_left: Coord,
+    pub size: Size,
+}
+
+impl Selection {
+    fn from_corners(a: Coord, b: Coord) -> Self {
+        let top_left = a.pairwise_min(b);
+        let bottom_right = a.pairwise_max(b);
+        let size = Size::new(
+            (bottom_right.x - top_left.x + 1) as u32,
+            (bottom_right.y - top_left.y + 1) as u32,
+        );
+        Self { top_left, size }
+    }
+    pub fn contains(&self, coord: Coord) -> bool {
+        self.size.is_valid(coord - self.top_left)
+    }
+    fn coords(&self) -> impl Iterator<Item = Coord> {
+        let top_left = self.top_left;
+        self.size
+            .coord_iter_row_major()
+            .map(move |coord| coord + top_left)
+    }
+    pub fn highlight(render_cell: RenderCell) -> RenderCell {
+        let tint = Rgba32::new(0, 127, 255, 127);
+        let background = match render_cell.background() {
+            Some(background) => tint.alpha_composite(background.with_a(255)),
+            None => tint.with_a(255),
+        };
+        render_cell.with_background(background)
+    }
+}
+
+// Selecting a region doesn't change the raster so these events are never added to the undo
+// buffer. Committing one replaces the current selection.
+#[derive(Serialize, Deserialize)]
+pub struct SelectEvent {
+    start: Coord,
+    end: Coord,
+}
+
+impl SelectEvent {
+    fn mouse_press(coord: Coord) -> Self {
+        Self {
+            start: coord,
+            end: coord,
+        }
+    }
+    fn mouse_move(&mut self, coord: Coord) {
+        self.end = coord;
+    }
+    pub fn selection(&self) -> Selection {
+        Selection::from_corners(self.start, self.end)
+    }
+    fn preview(&self, raster: &Raster, view: CanvasView, fb: &mut FrameBuffer) {
+        for coord in self.selection().coords() {
+            if let Some(&current_cell) = raster.grid.get(coord) {
+                view.set_cell(fb, coord, 0, Selection::highlight(current_cell));
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MoveEvent {
+    selection: Selection,
+    start: Coord,
+    end: Coord,
+}
+
+impl MoveEvent {
+    fn mouse_press(selection: Selection, coord: Coord) -> Self {
+        Self {
+            selection,
+            start: coord,
+            end: coord,
+        }
+    }
+    fn mouse_move(&mut self, coord: Coord) {
+        self.end = coord;
+    }
+    pub fn destination(&self) -> Selection {
+        Selection {
+            top_left: self.selection.top_left + self.end - self.start,
+            ..self.selection
+        }
+    }
+    fn commit(&self, raster: &mut Raster) {
+        let cells = raster.copy(self.selection);
+        for coord in self.selection.coords() {
+            raster.clear_coord(coord);
+        }
+        raster.paste(self.destination().top_left, &cells);
+    }
+    // Shows the canvas as it will be after the move, so the layers below show through the
+    // vacated cells
+    fn preview(&self, canvas: &Canvas, layer_index: usize, view: CanvasView, fb: &mut FrameBuffer) {
+        let mut moved = canvas.clone();
+        if let Some(layer) = moved.layers.get_mut(layer_index) {
+            self.commit(&mut layer.raster);
+        }
+        let composite = moved.flatten();
+        let destination = self.destination();
+        for coord in self.selection.coords().chain(destination.coords()) {
+            if let Some(&cell) = composite.grid.get(coord) {
+                let cell = if destination.contains(coord) {
+                    Selection::highlight(cell)
+                } else {
+                    cell
+                };
+                view.set_cell(fb, coord, 0, cell);
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CutEvent {
+    selection: Selection,
+}
+
+impl CutEvent {
+    fn commit(&self, raster: &mut Raster) {
+        for coord in self.selection.coords() {
+            raster.clear_coord(coord);
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PasteEvent {
+    top_left: Coord,
+    cells: Grid<RenderCell>,
+}
+
+impl PasteEvent {
+    fn commit(&self, raster: &mut Raster) {
+        raster.paste(self.top_left, &self.cells);
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct EraseEvent {
+    coords: HashSet<Coord>,
+    last_coord: Coord,
+}
+
+impl EraseEvent {
+    const PREVIEW_CELL: RenderCell = RenderCell {
+        character: Some('█'),
+        style: Style::DEFAULT.with_foreground(Rgba32::new_grey(0)),
+    };
+
+    fn mouse_press(coord: Coord) -> Self {
+        Self {
+            coords: iter::once(coord).collect(),
+            last_coord: coord,
+        }
+    }
+    fn mouse_move(&mut self, coord: Coord) {
+        for coord in line_2d::coords_between(self.last_coord, coord) {
+            self.coords.insert(coord);
+        }
+        self.last_coord = coord;
+    }
+    fn commit(&self, raster: &mut Raster) {
+        for &coord in self.coords.iter() {
+            raster.clear_coord(coord);
+        }
+    }
+    // Before layers were added, erasing made cells black rather than transparent
+    fn commit_legacy(&self, raster: &mut Raster) {
+        for &coord in self.coords.iter() {
+            if let Some(cell) = raster.grid.get_mut(coord) {
+                *cell = Raster::BLANK_CELL;
+            }
+        }
+    }
+    fn preview(&self, view: CanvasView, fb: &mut FrameBuffer) {
+        for &coord in self.coords.iter() {
+            view.set_cell(fb, coord, 0, Self::PREVIEW_CELL);
+        }
+    }
+}
+
+// Pencil and erase strokes made with a brush larger than a single cell
+#[derive(Serialize, Deserialize)]
+pub struct StrokeEvent {
+    brush: Brush,
+    erase: bool,
+    points: Vec<Coord>,
+}
+
+impl StrokeEvent {
+    fn mouse_press(coord: Coord, brush: Brush, erase: bool) -> Self {
+        Self {
+            brush,
+            erase,
+            points: vec![coord],
+        }
+    }
+    fn mouse_move(&mut self, coord: Coord) {
+        let last_coord = *self.points.last().unwrap();
+        if coord != last_coord {
+            let iter = line_2d::LineSegment::new(last_coord, coord).config_iter(line_2d::Config {
+                exclude_start: true,
+                exclude_end: false,
+            });
+            self.points.extend(iter);
+        }
+    }
+    // Like the pencil, shaped brushes paint a cell each time the stroke moves onto it, so cells
+    // which stay under the brush between neighbouring points aren't painted again but going back
+    // over part of the stroke builds up translucent colours. Custom brushes are stamped in order
+    // along the stroke.
+    fn cells(&self, render_cell: RenderCell) -> Vec<(Coord, RenderCell)> {
+        match &self.brush {
+            Brush::Shape { .. } => {
+                let footprint = self.brush.footprint();
+                let mut previous = HashSet::new();
+                let mut cells = Vec::new();
+                for &point in self.points.iter() {
+                    let current = footprint
+                        .iter()
+                        .map(|&offset| point + offset)
+                        .collect::<Vec<_>>();
+                    cells.extend(
+                        current
+                            .iter()
+                            .filter(|coord| !previous.contains(*coord))
+                            .map(|&coord| (coord, render_cell)),
+                    );
+                    previous = current.into_iter().collect();
+                }
+                cells
+            }
+            Brush::Custom(cells) => self
+                .points
+                .iter()
+                .flat_map(|&point| {
+                    Brush::custom_cells(cells).map(move |(offset, cell)| (point + offset, cell))
+                })
+                .collect(),
+        }
+    }
+    fn commit(&self, render_cell: RenderCell, raster: &mut Raster) {
+        for (coord, cell) in self.cells(render_cell) {
+            if self.erase {
+                raster.clear_coord(coord);
+            } else {
+                raster.set_coord(coord, cell);
+            }
+        }
+    }
+    fn preview(
+        &self,
+        raster: &Raster,
+        render_cell: RenderCell,
+        view: CanvasView,
+        fb: &mut FrameBuffer,
+    ) {
+        if self.erase {
+            for (coord, _) in self.cells(render_cell) {
+                view.set_cell(fb, coord, 0, EraseEvent::PREVIEW_CELL);
+            }
+            return;
+        }
+        // cells can be stamped more than once so stack them before drawing
+        let mut stacked = HashMap::new();
+        for (coord, cell) in self.cells(render_cell) {
+            if let Some(&current_cell) = raster.grid.get(coord) {
+                let current_cell = stacked.entry(coord).or_insert(current_cell);
+                *current_cell = Raster::stack_render_cells(*current_cell, cell);
+            }
+        }
+        for (coord, cell) in stacked {
+            view.set_cell(fb, coord, 0, cell);
+        }
+    }
+}
+
+// An event committed while some channels were locked. Any tool can be used with locks, so rather
+// than each event handling them, the locked channels of every cell are restored afterwards.
+#[derive(Serialize, Deserialize)]
+pub struct LockedEvent {
+    event: Box<DrawingEvent>,
+    locks: ChannelLocks,
+}
+
+impl LockedEvent {
+    fn commit(&self, render_cell: RenderCell, raster: &mut Raster) {
+        let before = raster.cells(self.event.coords(raster));
+        self.event.commit(render_cell, raster);
+        for (coord, before) in before {
+            if let Some(cell) = raster.grid.get_mut(coord) {
+                *cell = self.locks.restore(before, *cell);
+            }
+        }
+    }
+}
+
+// Documents saved before versioning was introduced are decoded with this type, so the first four
+// variants must keep their positions
+#[derive(Serialize, Deserialize)]
+pub enum DrawingEvent {
+    Pencil(PencilEvent),
+    Fill(FillEvent),
+    Line(LineEvent),
+    Erase(EraseEvent),
+    Rectangle(RectangleEvent),
+    Ellipse(EllipseEvent),
+    Text(TextEvent),
+    Select(SelectEvent),
+    Move(MoveEvent),
+    Cut(CutEvent),
+    Paste(PasteEvent),
+    Stroke(StrokeEvent),
+    Locked(LockedEvent),
+    // erase events from documents saved before versioning are converted to this when loaded
+    LegacyErase(EraseEvent),
+}
+
+impl DrawingEvent {
+    pub fn pencil(coord: Coord) -> Self {
+        Self::Pencil(PencilEvent::mouse_press(coord))
+    }
+    pub fn flood_fill(coord: Coord) -> Self {
+        Self::Fill(FillEvent::mouse_press(coord))
+    }
+    pub fn line(coord: Coord) -> Self {
+        Self::Line(LineEvent::mouse_press(coord))
+    }
+    pub fn erase(coord: Coord) -> Self {
+        Self::Erase(EraseEvent::mouse_press(coord))
+    }
+    pub fn rectangle(coord: Coord, mode: RectangleMode) -> Self {
+        Self::Rectangle(RectangleEvent::mouse_press(coord, mode))
+    }
+    pub fn ellipse(coord: Coord, mode: EllipseMode, aspect_ratio: Option<f64>) -> Self {
+        Self::Ellipse(EllipseEvent::mouse_press(coord, mode, aspect_ratio))
+    }
+    pub fn text(coord: Coord) -> Self {
+        Self::Text(TextEvent::mouse_press(coord))
+    }
+    pub fn select(coord: Coord) -> Self {
+        Self::Select(SelectEvent::mouse_press(coord))
+    }
+    pub fn move_selection(selection: Selection, coord: Coord) -> Self {
+        Self::Move(MoveEvent::mouse_press(selection, coord))
+    }
+    pub fn cut(selection: Selection) -> Self {
+        Self::Cut(CutEvent { selection })
+    }
+    pub fn paste(top_left: Coord, cells: Grid<RenderCell>) -> Self {
+        Self::Paste(PasteEvent { top_left, cells })
+    }
+    pub fn stroke(coord: Coord, brush: Brush, erase: bool) -> Self {
+        Self::Stroke(StrokeEvent::mouse_press(coord, brush, erase))
+    }
+    // Events are only wrapped when they are committed so tools can treat them as normal while
+    // they are in progress
+    pub fn locked(self, locks: ChannelLocks) -> Self {
+        Self::Locked(LockedEvent {
+            event: Box::new(self),
+            locks,
+        })
+    }
+    // Converts an event decoded from a document saved before versioning so it has the same
+    // effect as it did then
+    pub fn into_legacy(self) -> Self {
+        match self {
+            Self::Erase(erase) => Self::LegacyErase(erase),
+            event => event,
+        }
+    }
+    // Text events stay active after the mouse is released so that text can be typed
+    pub fn commits_on_mouse_release(&self) -> bool {
+        !matches!(self, Self::Text(_))
+    }
+    pub fn is_empty(&self) -> bool {
+        match self {
+            Self::Text(text) => text.is_empty(),
+            _ => false,
+        }
+    }
+    pub fn mouse_move(&mut self, coord: Coord) {
+        match self {
+            Self::Pencil(pencil) => pencil.mouse_move(coord),
+            Self::Fill(flood_fill) => flood_fill.mouse_move(coord),
+            Self::Line(line) => line.mouse_move(coord),
+            Self::Erase(erase) | Self::LegacyErase(erase) => erase.mouse_move(coord),
+            Self::Rectangle(rectangle) => rectangle.mouse_move(coord),
+            Self::Ellipse(ellipse) => ellipse.mouse_move(coord),
+            Self::Select(select) => select.mouse_move(coord),
+            Self::Move(move_) => move_.mouse_move(coord),
+            Self::Stroke(stroke) => stroke.mouse_move(coord),
+            Self::Locked(locked) => locked.event.mouse_move(coord),
+            Self::Text(_) | Self::Cut(_) | Self::Paste(_) => (),
+        }
+    }
+    // The cells which committing the event to `raster` may change
+    fn coords(&self, raster: &Raster) -> HashSet<Coord> {
+        match self {
+            Self::Pencil(pencil) => pencil.coords.keys().copied().collect(),
+            Self::Fill(flood_fill) => raster.flood_fill(flood_fill.start),
+            Self::Line(line) => line_2d::coords_between(line.start, line.end).collect(),
+            Self::Erase(erase) | Self::LegacyErase(erase) => erase.coords.clone(),
+            Self::Rectangle(rectangle) => rectangle
+                .coords()
+                .into_iter()
+                .map(|(coord, _)| coord)
+                .collect(),
+            Self::Ellipse(ellipse) => ellipse.coords().into_iter().collect(),
+            Self::Text(text) => text.coords().map(|(coord, _)| coord).collect(),
+            Self::Select(_) => HashSet::new(),
+            Self::Move(move_) => move_
+                .selection
+                .coords()
+                .chain(move_.destination().coords())
+                .collect(),
+            Self::Cut(cut) => cut.selection.coords().collect(),
+            Self::Paste(paste) => paste
+                .cells
+                .enumerate()
+                .map(|(coord, _)| coord + paste.top_left)
+                .collect(),
+            Self::Stroke(stroke) => stroke
+                .cells(RenderCell::BLANK)
+                .into_iter()
+                .map(|(coord, _)| coord)
+                .collect(),
+            Self::Locked(locked) => locked.event.coords(raster),
+        }
+    }
+    fn commit(&self, render_cell: RenderCell, raster: &mut Raster) {
+        match self {
+            Self::Pencil(pencil) => pencil.commit(render_cell, raster),
+            Self::Fill(flood_fill) => flood_fill.commit(render_cell, raster),
+            Self::Line(line) => line.commit(render_cell, raster),
+            Self::Erase(erase) => erase.commit(raster),
+            Self::Rectangle(rectangle) => rectangle.commit(render_cell, raster),
+            Self::Ellipse(ellipse) => ellipse.commit(render_cell, raster),
+            Self::Text(text) => text.commit(render_cell, raster),
+            Self::Select(_) => (),
+            Self::Move(move_) => move_.commit(raster),
+            Self::Cut(cut) => cut.commit(raster),
+            Self::Paste(paste) => paste.commit(raster),
+            Self::Stroke(stroke) => stroke.commit(render_cell, raster),
+            Self::Locked(locked) => locked.commit(render_cell, raster),
+            Self::LegacyErase(erase) => erase.commit_legacy(raster),
+        }
+    }
+    // The event will be committed to the layer at `layer_index` and `composite` is the result of
+    // stacking all the visible layers
+    pub fn preview(
+        &self,
+        canvas: &Canvas,
+        layer_index: usize,
+        composite: &Raster,
+        render_cell: RenderCell,
+        view: CanvasView,
+        fb: &mut FrameBuffer,
+    ) {
+        let layer = &canvas.layers[layer_index].raster;
+        match self {
+            Self::Pencil(pencil) => pencil.preview(composite, render_cell, view, fb),
+            Self::Fill(flood_fill) => flood_fill.preview(layer, composite, render_cell, view, fb),
+            Self::Line(line) => line.preview(composite, render_cell, view, fb),
+            Self::Erase(erase) | Self::LegacyErase(erase) => erase.preview(view, fb),
+            Self::Rectangle(rectangle) => rectangle.preview(composite, render_cell, view, fb),
+            Self::Ellipse(ellipse) => ellipse.preview(composite, render_cell, view, fb),
+            Self::Text(text) => text.preview(composite, render_cell, view, fb),
+            Self::Select(select) => select.preview(composite, view, fb),
+            Self::Move(move_) => move_.preview(canvas, layer_index, view, fb),
+            Self::Stroke(stroke) => stroke.preview(composite, render_cell, view, fb),
+            Self::Locked(locked) => {
+                let render_cell = locked.locks.mask(render_cell);
+                locked
+                    .event
+                    .preview(canvas, layer_index, composite, render_cell, view, fb)
+            }
+            Self::Cut(_) | Self::Paste(_) => (),
+        }
+    }
+}
+
+// The part of the canvas shown on screen. Cells are drawn relative to `offset`, the canvas coord
+// shown at the top-left of `ctx`, and cells outside of `ctx` are clipped.
+#[derive(Clone, Copy)]
+pub struct CanvasView<'a> {
+    pub ctx: Ctx<'a>,
+    pub offset: Coord,
+}
+
+impl CanvasView<'_> {
+    fn set_cell(self, fb: &mut FrameBuffer, coord: Coord, depth: i8, render_cell: RenderCell) {
+        fb.set_cell_relative_to_ctx(self.ctx, coord - self.offset, depth, render_cell);
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Raster {
+    pub grid: Grid<RenderCell>,
+}
+
+impl Raster {
+    const BLANK_CELL: RenderCell = RenderCell {
+        character: None,
+        style: Style::DEFAULT.with_background(Rgba32::new_grey(0)),
+    };
+
+    fn new(size: Size) -> Self {
+        Self {
+            grid: Grid::new_clone(size, Self::BLANK_CELL),
+        }
+    }
+
+    pub fn new_transparent(size: Size) -> Self {
+        Self {
+            grid: Grid::new_clone(size, RenderCell::BLANK),
+        }
+    }
+
+    pub fn stack_render_cells(bottom: RenderCell, top: RenderCell) -> RenderCell {
+        fn blend(a: Option<Rgba32>, b: Option<Rgba32>) -> Option<Rgba32> {
+            match (a, b) {
+                (None, None) => None,
+                (Some(x), None) | (None, Some(x)) => Some(x),
+                (Some(a), Some(b)) => Some(a.alpha_composite(b)),
+            }
+        }
+        let mut ret = bottom;
+        ret.character = top.character.or(bottom.character);
+        ret.style.background = blend(top.style.background, bottom.style.background);
+        // blend the foreground with the background if there is currently no character present
+        let bottom_foreground = if bottom.character.is_none() {
+            bottom.style.background
+        } else {
+            bottom.style.foreground
+        };
+        ret.style.foreground = blend(top.style.foreground, bottom_foreground);
+        ret.style.bold = top.style.bold.or(bottom.style.bold);
+        ret.style.underline = top.style.underline.or(bottom.style.underline);
+        ret
+    }
+
+    // Multiplies the alpha of the foreground and background colours by `opacity` / 255
+    pub fn scale_alpha(render_cell: RenderCell, opacity: u8) -> RenderCell {
+        let scale = |c: Rgba32| c.with_a((c.a as u32 * opacity as u32 / 255) as u8);
+        RenderCell {
+            character: render_cell.character,
+            style: render_cell
+                .style
+                .with_foreground_option(render_cell.foreground().map(scale))
+                .with_background_option(render_cell.background().map(scale)),
+        }
+    }
+
+    // The current value of each coord which is within the raster
+    fn cells(&self, coords: impl IntoIterator<Item = Coord>) -> Vec<(Coord, RenderCell)> {
+        coords
+            .into_iter()
+            .filter_map(|coord| self.grid.get(coord).map(|&cell| (coord, cell)))
+            .collect()
+    }
+
+    fn set_coord(&mut self, coord: Coord, cell: RenderCell) {
+        if let Some(raster_cell) = self.grid.get_mut(coord) {
+            *raster_cell = Self::stack_render_cells(*raster_cell, cell);
+        }
+    }
+
+    fn clear_coord(&mut self, coord: Coord) {
+        if let Some(raster_cell) = self.grid.get_mut(coord) {
+            *raster_cell = RenderCell::BLANK;
+        }
+    }
+
+    pub fn copy(&self, selection: Selection) -> Grid<RenderCell> {
+        Grid::new_fn(selection.size, |coord| {
+            self.grid
+                .get(coord + selection.top_left)
+                .copied()
+                .unwrap_or(Self::BLANK_CELL)
+        })
+    }
+
+    fn paste(&mut self, top_left: Coord, cells: &Grid<RenderCell>) {
+        for (coord, &cell) in cells.enumerate() {
+            if let Some(raster_cell) = self.grid.get_mut(coord + top_left) {
+                *raster_cell = cell;
+            }
+        }
+    }
+    fn flood_fill(&self, coord: Coord) -> HashSet<Coord> {
+        use direction::CardinalDirection;
+        use std::collections::VecDeque;
+        let mut queue = VecDeque::new();
+        let mut seen = HashSet::new();
+        let initial_cell = self.grid.get_checked(coord);
+        queue.push_front(coord);
+        seen.insert(coord);
+        while let Some(coord) = queue.pop_back() {
+            for d in CardinalDirection::all() {
+                let nei_coord = coord + d.coord();
+                if !seen.contains(&nei_coord) {
+                    if let Some(nei_cell) = self.grid.get(nei_coord) {
+                        if nei_cell == initial_cell {
+                            seen.insert(nei_coord);
+                            queue.push_front(nei_coord);
+                        }
+                    }
+                }
+            }
+        }
+        seen
+    }
+
+    fn commit_event(&mut self, event: &DrawingEventWithRenderCell) {
+        event.drawing_event.commit(event.render_cell, self);
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DrawingEventWithRenderCell {
+    pub drawing_event: DrawingEvent,
+    pub render_cell: RenderCell,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Layer {
+    pub name: String,
+    pub raster: Raster,
+    pub visible: bool,
+    pub opacity: u8,
+    pub locked: bool,
+}
+
+impl Layer {
+    pub fn new(name: String, raster: Raster) -> Self {
+        Self {
+            name,
+            raster,
+            visible: true,
+            opacity: 255,
+            locked: false,
+        }
+    }
+
+    fn apply_opacity(&self, render_cell: RenderCell) -> RenderCell {
+        Raster::scale_alpha(render_cell, self.opacity)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum LayerEvent {
+    Add { index: usize, name: String },
+    Delete { index: usize },
+    Move { from: usize, to: usize },
+    SetVisible { index: usize, visible: bool },
+    SetLocked { index: usize, locked: bool },
+    SetOpacity { index: usize, opacity: u8 },
+    Rename { index: usize, name: String },
+}
+
+// The part of the canvas which stays in place when the canvas is resized
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Anchor {
+    TopLeft,
+    Top,
+    TopRight,
+    Left,
+    Centre,
+    Right,
+    BottomLeft,
+    Bottom,
+    BottomRight,
+}
+
+impl Anchor {
+    // In row-major order, so each anchor's index gives its position in a 3x3 grid
+    pub const ALL: [Self; 9] = [
+        Self::TopLeft,
+        Self::Top,
+        Self::TopRight,
+        Self::Left,
+        Self::Centre,
+        Self::Right,
+        Self::BottomLeft,
+        Self::Bottom,
+        Self::BottomRight,
+    ];
+
+    // The anchor's position in a 3x3 grid
+    pub fn coord(self) -> Coord {
+        let index = Self::ALL.iter().position(|&a| a == self).unwrap() as i32;
+        Coord::new(index % 3, index / 3)
+    }
+
+    // How far the existing contents move when resizing from `from` to `to`
+    fn offset(self, from: Size, to: Size) -> Coord {
+        let Coord { x, y } = self.coord();
+        Coord::new(
+            (to.width() as i32 - from.width() as i32) * x / 2,
+            (to.height() as i32 - from.height() as i32) * y / 2,
+        )
+    }
+}
+
+// New variants must be added at the end so existing files can still be decoded
+#[derive(Serialize, Deserialize)]
+pub enum HistoryEvent {
+    Drawing {
+        layer_index: usize,
+        event: DrawingEventWithRenderCell,
+    },
+    Layer(LayerEvent),
+    Resize {
+        size: Size,
+        anchor: Anchor,
+    },
+}
+
+// A stack of layers where the first layer is at the bottom
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Canvas {
+    pub layers: Vec<Layer>,
+}
+
+impl Canvas {
+    pub fn new(size: Size) -> Self {
+        Self::from_raster(Raster::new_transparent(size))
+    }
+
+    pub fn from_raster(raster: Raster) -> Self {
+        Self {
+            layers: vec![Layer::new("Background".to_string(), raster)],
+        }
+    }
+
+    pub fn size(&self) -> Size {
+        self.layers[0].raster.grid.size()
+    }
+
+    // Stacks the visible layers from bottom to top onto a blank raster
+    pub fn flatten(&self) -> Raster {
+        self.flatten_onto(Raster::new(self.size()))
+    }
+
+    // Cells are matched up by coordinate so a base of a different size is still lined up with
+    // the top-left corner of the canvas
+    pub fn flatten_onto(&self, base: Raster) -> Raster {
+        let mut ret = base;
+        for layer in self.layers.iter().filter(|layer| layer.visible) {
+            for (coord, cell) in ret.grid.enumerate_mut() {
+                if let Some(&layer_cell) = layer.raster.grid.get(coord) {
+                    *cell = Raster::stack_render_cells(*cell, layer.apply_opacity(layer_cell));
+                }
+            }
+        }
+        ret
+    }
+
+    // Changes the size of every layer, padding with blank cells or cropping around `anchor`
+    pub fn resize(&mut self, size: Size, anchor: Anchor) {
+        let offset = anchor.offset(self.size(), size);
+        for layer in self.layers.iter_mut() {
+            let grid = &layer.raster.grid;
+            let resized = Grid::new_fn(size, |coord| {
+                grid.get(coord - offset)
+                    .copied()
+                    .unwrap_or(RenderCell::BLANK)
+            });
+            layer.raster.grid = resized;
+        }
+    }
+
+    fn commit_event(&mut self, event: &HistoryEvent) {
+        match event {
+            HistoryEvent::Drawing { layer_index, event } => {
+                if let Some(layer) = self.layers.get_mut(*layer_index) {
+                    layer.raster.commit_event(event);
+                }
+            }
+            HistoryEvent::Layer(layer_event) => self.commit_layer_event(layer_event),
+            HistoryEvent::Resize { size, anchor } => self.resize(*size, *anchor),
+        }
+    }
+
+    fn commit_layer_event(&mut self, event: &LayerEvent) {
+        match event {
+            LayerEvent::Add { index, name } => {
+                let raster = Raster::new_transparent(self.size());
+                let index = (*index).min(self.layers.len());
+                self.layers.insert(index, Layer::new(name.clone(), raster));
+            }
+            LayerEvent::Delete { index } => {
+                // there must always be at least one layer
+                if self.layers.len() > 1 && *index < self.layers.len() {
+                    self.layers.remove(*index);
+                }
+            }
+            LayerEvent::Move { from, to } => {
+                if *from < self.layers.len() && *to < self.layers.len() {
+                    let layer = self.layers.remove(*from);
+                    self.layers.insert(*to, layer);
+                }
+            }
+            LayerEvent::SetVisible { index, visible } => {
+                if let Some(layer) = self.layers.get_mut(*index) {
+                    layer.visible = *visible;
+                }
+            }
+            LayerEvent::SetLocked { index, locked } => {
+                if let Some(layer) = self.layers.get_mut(*index) {
+                    layer.locked = *locked;
+                }
+            }
+            LayerEvent::SetOpacity { index, opacity } => {
+                if let Some(layer) = self.layers.get_mut(*index) {
+                    layer.opacity = *opacity;
+                }
+            }
+            LayerEvent::Rename { index, name } => {
+                if let Some(layer) = self.layers.get_mut(*index) {
+                    layer.name = name.clone();
+                }
+            }
+        }
+    }
+}
+
+// Everything about a layer other than its contents
+struct LayerProperties {
+    name: String,
+    visible: bool,
+    opacity: u8,
+    locked: bool,
+}
+
+impl LayerProperties {
+    fn of(layer: &Layer) -> Self {
+        Self {
+            name: layer.name.clone(),
+            visible: layer.visible,
+            opacity: layer.opacity,
+            locked: layer.locked,
+        }
+    }
+
+    // Sets the properties of the layer, returning its previous properties
+    fn replace(self, layer: &mut Layer) -> Self {
+        Self {
+            name: mem::replace(&mut layer.name, self.name),
+            visible: mem::replace(&mut layer.visible, self.visible),
+            opacity: mem::replace(&mut layer.opacity, self.opacity),
+            locked: mem::replace(&mut layer.locked, self.locked),
+        }
+    }
+}
+
+// Restores part of a canvas to an earlier state. Applying a change returns the change which
+// reverses it, so the same type describes both undo and redo.
+enum CanvasChange {
+    Cells {
+        layer_index: usize,
+        cells: Vec<(Coord, RenderCell)>,
+    },
+    Properties {
+        index: usize,
+        properties: LayerProperties,
+    },
+    Insert {
+        index: usize,
+        layer: Layer,
+    },
+    Remove {
+        index: usize,
+    },
+    Move {
+        from: usize,
+        to: usize,
+    },
+    // resizing can crop every layer so the layers from before the resize are kept in full
+    Layers(Vec<Layer>),
+    // for events which had no effect, such as those referring to a layer that doesn't exist
+    Nothing,
+}
+
+impl CanvasChange {
+    // The change which reverses a layer event that is about to be committed to `canvas`
+    fn reversing_layer_event(canvas: &Canvas, event: &LayerEvent) -> Self {
+        let num_layers = canvas.layers.len();
+        match *event {
+            LayerEvent::Add { index, .. } => Self::Remove {
+                index: index.min(num_layers),
+            },
+            LayerEvent::Delete { index } if num_layers > 1 && index < num_layers => Self::Insert {
+                index,
+                layer: canvas.layers[index].clone(),
+            },
+            LayerEvent::Move { from, to } if from < num_layers && to < num_layers => {
+                Self::Move { from: to, to: from }
+            }
+            LayerEvent::SetVisible { index, .. }
+            | LayerEvent::SetLocked { index, .. }
+            | LayerEvent::SetOpacity { index, .. }
+            | LayerEvent::Rename { index, .. } => match canvas.layers.get(index) {
+                Some(layer) => Self::Properties {
+                    index,
+                    properties: LayerProperties::of(layer),
+                },
+                None => Self::Nothing,
+            },
+            LayerEvent::Delete { .. } | LayerEvent::Move { .. } => Self::Nothing,
+        }
+    }
+
+    fn apply(self, canvas: &mut Canvas) -> Self {
+        match self {
+            Self::Cells { layer_index, cells } => {
+                let cells = match canvas.layers.get_mut(layer_index) {
+                    Some(layer) => cells
+                        .into_iter()
+                        .filter_map(|(coord, cell)| {
+                            layer
+                                .raster
+                                .grid
+                                .get_mut(coord)
+                                .map(|current| (coord, mem::replace(current, cell)))
+                        })
+                        .collect(),
+                    None => Vec::new(),
+                };
+                Self::Cells { layer_index, cells }
+            }
+            Self::Properties { index, properties } => match canvas.layers.get_mut(index) {
+                Some(layer) => Self::Properties {
+                    index,
+                    properties: properties.replace(layer),
+                },
+                None => Self::Nothing,
+            },
+            Self::Insert { index, layer } => {
+                canvas.layers.insert(index, layer);
+                Self::Remove { index }
+            }
+            Self::Remove { index } => Self::Insert {
+                index,
+                layer: canvas.layers.remove(index),
+            },
+            Self::Move { from, to } => {
+                let layer = canvas.layers.remove(from);
+                canvas.layers.insert(to, layer);
+                Self::Move { from: to, to: from }
+            }
+            Self::Layers(layers) => Self::Layers(mem::replace(&mut canvas.layers, layers)),
+            Self::Nothing => Self::Nothing,
+        }
+    }
+}
+
+struct UndoStep {
+    event: HistoryEvent,
+    change: CanvasChange,
+}
+
+// Each step stores the change that reverses it, so undo and redo only touch the cells affected
+// by a single event rather than replaying the entire history. Only the cells and layer
+// properties an event touches are recorded, so steps stay small on large canvases.
+pub struct UndoBuffer {
+    initial: Canvas,
+    undo_steps: Vec<UndoStep>,
+    redo_steps: Vec<UndoStep>,
+}
+
+// The history is saved as the initial canvas followed by the list of events, and the changes are
+// recomputed when it is loaded
+#[derive(Deserialize)]
+struct UndoHistory {
+    initial: Canvas,
+    events: Vec<HistoryEvent>,
+    redo_buffer: Vec<HistoryEvent>,
+}
+
+#[derive(Serialize)]
+struct UndoHistoryRef<'a> {
+    initial: &'a Canvas,
+    events: Vec<&'a HistoryEvent>,
+    redo_buffer: Vec<&'a HistoryEvent>,
+}
+
+impl UndoBuffer {
+    fn new(initial: Canvas) -> Self {
+        Self {
+            initial,
+            undo_steps: Vec::new(),
+            redo_steps: Vec::new(),
+        }
+    }
+
+    // Replays the history to compute the change made by each event
+    pub fn from_history(
+        initial: Canvas,
+        events: Vec<HistoryEvent>,
+        redo_buffer: Vec<HistoryEvent>,
+    ) -> Self {
+        let mut canvas = initial.clone();
+        let mut ret = Self::new(initial);
+        for event in events {
+            ret.commit_event(event, &mut canvas);
+        }
+        // the last event in the redo buffer is the next one to be redone
+        let num_redo = redo_buffer.len();
+        for event in redo_buffer.into_iter().rev() {
+            ret.commit_event(event, &mut canvas);
+        }
+        for _ in 0..num_redo {
+            ret.undo(&mut canvas);
+        }
+        ret
+    }
+
+    pub fn initial(&self) -> &Canvas {
+        &self.initial
+    }
+
+    // The event which will be reverted by the next call to `undo`
+    pub fn next_undo_event(&self) -> Option<&HistoryEvent> {
+        self.undo_steps.last().map(|step| &step.event)
+    }
+
+    // The event which will be reapplied by the next call to `redo`
+    pub fn next_redo_event(&self) -> Option<&HistoryEvent> {
+        self.redo_steps.last().map(|step| &step.event)
+    }
+
+    pub fn num_undo_steps(&self) -> usize {
+        self.undo_steps.len()
+    }
+
+    pub fn num_redo_steps(&self) -> usize {
+        self.redo_steps.len()
+    }
+
+    pub fn undo(&mut self, canvas: &mut Canvas) {
+        if let Some(UndoStep { event, change }) = self.undo_steps.pop() {
+            let change = change.apply(canvas);
+            self.redo_steps.push(UndoStep { event, change });
+        }
+    }
+
+    pub fn redo(&mut self, canvas: &mut Canvas) {
+        if let Some(UndoStep { event, change }) = self.redo_steps.pop() {
+            let change = change.apply(canvas);
+            self.undo_steps.push(UndoStep { event, change });
+        }
+    }
+
+    // Applies the event to the canvas, recording what it changed
+    pub fn commit_event(&mut self, event: HistoryEvent, canvas: &mut Canvas) {
+        let change = match &event {
+            HistoryEvent::Drawing {
+                layer_index,
+                event: drawing_event,
+            } => {
+                let layer_index = *layer_index;
+                let before = canvas.layers.get(layer_index).map(|layer| {
+                    let raster = &layer.raster;
+                    raster.cells(drawing_event.drawing_event.coords(raster))
+                });
+                canvas.commit_event(&event);
+                let cells = match (before, canvas.layers.get(layer_index)) {
+                    (Some(before), Some(layer)) => before
+                        .into_iter()
+                        .filter(|(coord, before)| layer.raster.grid.get(*coord) != Some(before))
+                        .collect(),
+                    _ => Vec::new(),
+                };
+                CanvasChange::Cells { layer_index, cells }
+            }
+            HistoryEvent::Layer(layer_event) => {
+                let change = CanvasChange::reversing_layer_event(canvas, layer_event);
+                canvas.commit_event(&event);
+                change
+            }
+            HistoryEvent::Resize { .. } => {
+                let before = canvas.layers.clone();
+                canvas.commit_event(&event);
+                CanvasChange::Layers(before)
+            }
+        };
+        self.undo_steps.push(UndoStep { event, change });
+        self.redo_steps.clear();
+    }
+}
+
+impl Serialize for UndoBuffer {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        UndoHistoryRef {
+            initial: &self.initial,
+            events: self.undo_steps.iter().map(|step| &step.event).collect(),
+            redo_buffer: self.redo_steps.iter().map(|step| &step.event).collect(),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for UndoBuffer {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let UndoHistory {
+            initial,
+            events,
+            redo_buffer,
+        } = UndoHistory::deserialize(deserializer)?;
+        Ok(Self::from_history(initial, events, redo_buffer))
+    }
+}
+
+// Each frame of an animation has its own layers and undo history
+#[derive(Serialize, Deserialize)]
+pub struct Frame {
+    pub canvas: Canvas,
+    pub undo_buffer: UndoBuffer,
+}
+
+impl Frame {
+    pub fn new(canvas: Canvas) -> Self {
+        Self {
+            undo_buffer: UndoBuffer::new(canvas.clone()),
+            canvas,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The characters of a raster in row-major order, with '.' for cells with no character
+    fn characters(raster: &Raster) -> String {
+        raster
+            .grid
+            .iter()
+            .map(|cell| cell.character.unwrap_or('.'))
+            .collect()
+    }
+
+    // A transparent raster with one row per string
+    fn raster_from_rows(rows: &[&str]) -> Raster {
+        let size = Size::new(rows[0].len() as u32, rows.len() as u32);
+        let cells = rows.iter().flat_map(|row| {
+            row.chars().map(|ch| match ch {
+                '.' => RenderCell::BLANK,
+                ch => RenderCell::BLANK.with_character(ch),
+            })
+        });
+        Raster {
+            grid: Grid::new_iterator(size, cells),
+        }
+    }
+
+    fn rectangle(start: Coord, end: Coord, mode: RectangleMode) -> Vec<(Coord, Option<char>)> {
+        let mut event = RectangleEvent::mouse_press(start, mode);
+        event.mouse_move(end);
+        event.coords()
+    }
+
+    #[test]
+    fn rectangle_outline_skips_interior() {
+        let coords = rectangle(Coord::new(3, 2), Coord::new(0, 0), RectangleMode::Outline);
+        assert_eq!(coords.len(), 10);
+        assert!(coords.iter().all(|&(coord, ch)| {
+            ch.is_none() && Size::new(4, 3).is_valid(coord) && coord != Coord::new(1, 1)
+        }));
+    }
+
+    #[test]
+    fn rectangle_filled_covers_every_cell() {
+        let coords = rectangle(Coord::new(1, 1), Coord::new(4, 3), RectangleMode::Filled);
+        assert_eq!(coords.len(), 12);
+        assert!(coords.contains(&(Coord::new(2, 2), None)));
+    }
+
+    #[test]
+    fn rectangle_box_drawing_characters() {
+        let ch = |coords: &[(Coord, Option<char>)], coord: Coord| {
+            coords
+                .iter()
+                .find(|&&(c, _)| c == coord)
+                .unwrap()
+                .1
+                .unwrap()
+        };
+        let coords = rectangle(
+            Coord::new(0, 0),
+            Coord::new(3, 2),
+            RectangleMode::BoxDrawing,
+        );
+        assert_eq!(ch(&coords, Coord::new(0, 0)), '┌');
+        assert_eq!(ch(&coords, Coord::new(3, 0)), '┐');
+        assert_eq!(ch(&coords, Coord::new(0, 2)), '└');
+        assert_eq!(ch(&coords, Coord::new(3, 2)), '┘');
+        assert_eq!(ch(&coords, Coord::new(1, 0)), '─');
+        assert_eq!(ch(&coords, Coord::new(0, 1)), '│');
+        let point = rectangle(
+            Coord::new(5, 5),
+            Coord::new(5, 5),
+            RectangleMode::BoxDrawing,
+        );
+        assert_eq!(point, vec![(Coord::new(5, 5), Some('□'))]);
+        let row = rectangle(
+            Coord::new(0, 0),
+            Coord::new(2, 0),
+            RectangleMode::BoxDrawing,
+        );
+        assert!(row.iter().all(|&(_, ch)| ch == Some('─')));
+        let column = rectangle(
+            Coord::new(0, 0),
+            Coord::new(0, 2),
+            RectangleMode::BoxDrawing,
+        );
+        assert!(column.iter().all(|&(_, ch)| ch == Some('│')));
+    }
+
+    fn ellipse(start: Coord, end: Coord, mode: EllipseMode) -> HashSet<Coord> {
+        let mut event = EllipseEvent::mouse_press(start, mode, None);
+        event.mouse_move(end);
+        event.coords().into_iter().collect()
+    }
+
+    #[test]
+    fn ellipse_single_cell() {
+        let coords = ellipse(Coord::new(2, 3), Coord::new(2, 3), EllipseMode::Outline);
+        assert_eq!(coords, iter::once(Coord::new(2, 3)).collect());
+    }
+
+    #[test]
+    fn ellipse_outline_is_edge_of_filled() {
+        let filled = ellipse(Coord::new(0, 0), Coord::new(6, 6), EllipseMode::Filled);
+        let outline = ellipse(Coord::new(6, 6), Coord::new(0, 0), EllipseMode::Outline);
+        assert!(outline.is_subset(&filled));
+        assert!(filled.contains(&Coord::new(3, 3)));
+        assert!(!outline.contains(&Coord::new(3, 3)));
+        assert!(!filled.contains(&Coord::new(0, 0)));
+        // circles are symmetric in both axes and about the diagonal
+        for &Coord { x, y } in &filled {
+            assert!(filled.contains(&Coord::new(6 - x, y)));
+            assert!(filled.contains(&Coord::new(x, 6 - y)));
+            assert!(filled.contains(&Coord::new(y, x)));
+        }
+    }
+
+    #[test]
+    fn ellipse_aspect_ratio_sets_height_from_width() {
+        let mut event = EllipseEvent::mouse_press(Coord::new(0, 0), EllipseMode::Outline, Some(2.));
+        event.mouse_move(Coord::new(4, 10));
+        assert_eq!(event.corrected_end(), Coord::new(4, 2));
+        event.mouse_move(Coord::new(4, -10));
+        assert_eq!(event.corrected_end(), Coord::new(4, -2));
+    }
+
+    fn type_text(event: &mut TextEvent, inputs: &[KeyboardInput]) {
+        for &input in inputs {
+            event.keyboard_input(input);
+        }
+    }
+
+    fn chars(text: &str) -> Vec<KeyboardInput> {
+        text.chars().map(KeyboardInput::Char).collect()
+    }
+
+    #[test]
+    fn text_return_splits_and_backspace_joins_lines() {
+        let mut event = TextEvent::mouse_press(Coord::new(1, 1));
+        type_text(&mut event, &chars("abcd"));
+        type_text(
+            &mut event,
+            &[KeyboardInput::Left, KeyboardInput::Left, keys::RETURN],
+        );
+        assert_eq!(event.lines, vec![vec!['a', 'b'], vec!['c', 'd']]);
+        assert_eq!(event.caret, Coord::new(0, 1));
+        type_text(&mut event, &[keys::BACKSPACE]);
+        assert_eq!(event.lines, vec![vec!['a', 'b', 'c', 'd']]);
+        assert_eq!(event.caret, Coord::new(2, 0));
+        type_text(&mut event, &[KeyboardInput::Delete, KeyboardInput::End]);
+        type_text(&mut event, &chars("!"));
+        assert_eq!(event.lines, vec![vec!['a', 'b', 'd', '!']]);
+    }
+
+    #[test]
+    fn text_commit_writes_each_line_below_the_origin() {
+        let mut event = TextEvent::mouse_press(Coord::new(1, 0));
+        type_text(&mut event, &chars("hi"));
+        type_text(&mut event, &[keys::RETURN]);
+        type_text(&mut event, &chars("yo"));
+        let mut raster = Raster::new_transparent(Size::new(3, 2));
+        event.commit(RenderCell::BLANK, &mut raster);
+        assert_eq!(characters(&raster), ".hi.yo");
+        assert!(!event.is_empty());
+        assert!(TextEvent::mouse_press(Coord::new(0, 0)).is_empty());
+    }
+
+    #[test]
+    fn selection_from_corners_in_any_order() {
+        let selection = Selection::from_corners(Coord::new(3, 1), Coord::new(1, 2));
+        assert_eq!(selection.top_left, Coord::new(1, 1));
+        assert_eq!(selection.size, Size::new(3, 2));
+        assert!(selection.contains(Coord::new(3, 2)));
+        assert!(!selection.contains(Coord::new(4, 2)));
+        assert_eq!(selection.coords().count(), 6);
+    }
+
+    #[test]
+    fn move_clears_the_source_and_pastes_at_the_destination() {
+        let mut raster = raster_from_rows(&["ab..", "cd.."]);
+        let selection = Selection::from_corners(Coord::new(0, 0), Coord::new(1, 1));
+        let mut event = MoveEvent::mouse_press(selection, Coord::new(0, 0));
+        event.mouse_move(Coord::new(1, 0));
+        assert_eq!(event.destination().top_left, Coord::new(1, 0));
+        event.commit(&mut raster);
+        assert_eq!(characters(&raster), ".ab..cd.");
+        assert_eq!(
+            raster.grid.get_checked(Coord::new(0, 0)),
+            &RenderCell::BLANK
+        );
+    }
+
+    #[test]
+    fn cut_copy_and_paste() {
+        let mut raster = raster_from_rows(&["abc", "def"]);
+        let selection = Selection::from_corners(Coord::new(1, 0), Coord::new(2, 1));
+        let copied = raster.copy(selection);
+        CutEvent { selection }.commit(&mut raster);
+        assert_eq!(characters(&raster), "a..d..");
+        // pasting is clipped to the raster
+        PasteEvent {
+            top_left: Coord::new(2, 1),
+            cells: copied,
+        }
+        .commit(&mut raster);
+        assert_eq!(characters(&raster), "a..d.b");
+        // copying beyond the edge of the raster gives blank cells
+        let beyond = raster.copy(Selection::from_corners(Coord::new(2, 1), Coord::new(3, 1)));
+        assert_eq!(beyond.get_checked(Coord::new(1, 0)).character, None);
+    }
+
+    fn drawing(event: DrawingEvent, render_cell: RenderCell) -> HistoryEvent {
+        drawing_on(0, event, render_cell)
+    }
+
+    fn drawing_on(
+        layer_index: usize,
+        event: DrawingEvent,
+        render_cell: RenderCell,
+    ) -> HistoryEvent {
+        HistoryEvent::Drawing {
+            layer_index,
+            event: DrawingEventWithRenderCell {
+                drawing_event: event,
+                render_cell,
+            },
+        }
+    }
+
+    #[test]
+    fn erase_is_transparent_unless_converted_from_a_legacy_document() {
+        let mut canvas = Canvas::from_raster(raster_from_rows(&["ab"]));
+        canvas.commit_event(&drawing(
+            DrawingEvent::erase(Coord::new(0, 0)),
+            RenderCell::BLANK,
+        ));
+        let legacy_erase = DrawingEvent::erase(Coord::new(1, 0)).into_legacy();
+        canvas.commit_event(&drawing(legacy_erase, RenderCell::BLANK));
+        let grid = &canvas.layers[0].raster.grid;
+        assert_eq!(grid.get_checked(Coord::new(0, 0)), &RenderCell::BLANK);
+        assert_eq!(grid.get_checked(Coord::new(1, 0)), &Raster::BLANK_CELL);
+    }
+
+    #[test]
+    fn flatten_skips_hidden_layers_and_applies_opacity() {
+        let red = RenderCell::BLANK.with_background(Rgba32::new(255, 0, 0, 255));
+        let mut canvas = Canvas::new(Size::new(1, 1));
+        canvas.commit_layer_event(&LayerEvent::Add {
+            index: 1,
+            name: "top".to_string(),
+        });
+        canvas.layers[1].raster.grid = Grid::new_clone(Size::new(1, 1), red);
+        let background = |canvas: &Canvas| {
+            canvas
+                .flatten()
+                .grid
+                .get_checked(Coord::new(0, 0))
+                .background()
+        };
+        let black = Raster::BLANK_CELL.background();
+        assert_eq!(background(&canvas), red.background());
+        canvas.commit_layer_event(&LayerEvent::SetOpacity {
+            index: 1,
+            opacity: 0,
+        });
+        assert_eq!(background(&canvas), black);
+        canvas.commit_layer_event(&LayerEvent::SetOpacity {
+            index: 1,
+            opacity: 255,
+        });
+        canvas.commit_layer_event(&LayerEvent::SetVisible {
+            index: 1,
+            visible: false,
+        });
+        assert_eq!(background(&canvas), black);
+    }
+
+    #[test]
+    fn resize_keeps_contents_around_the_anchor() {
+        let resized = |size: Size, anchor: Anchor| {
+            let mut canvas = Canvas::from_raster(raster_from_rows(&["ab", "cd"]));
+            canvas.resize(size, anchor);
+            characters(&canvas.layers[0].raster)
+        };
+        assert_eq!(resized(Size::new(3, 3), Anchor::TopLeft), "ab.cd....");
+        assert_eq!(resized(Size::new(4, 4), Anchor::Centre), ".....ab..cd.....");
+        assert_eq!(resized(Size::new(5, 2), Anchor::Right), "...ab...cd");
+        assert_eq!(resized(Size::new(3, 3), Anchor::Bottom), "...ab.cd.");
+        assert_eq!(resized(Size::new(1, 1), Anchor::BottomRight), "d");
+        assert_eq!(resized(Size::new(1, 2), Anchor::Top), "ac");
+    }
+
+    #[test]
+    fn layer_events_keep_at_least_one_layer() {
+        let mut canvas = Canvas::new(Size::new(2, 2));
+        let name = |name: &str| name.to_string();
+        canvas.commit_layer_event(&LayerEvent::Add {
+            index: 5,
+            name: name("a"),
+        });
+        canvas.commit_layer_event(&LayerEvent::Add {
+            index: 0,
+            name: name("b"),
+        });
+        canvas.commit_layer_event(&LayerEvent::Move { from: 0, to: 2 });
+        let names = |canvas: &Canvas| {
+            canvas
+                .layers
+                .iter()
+                .map(|layer| layer.name.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(&canvas), ["Background", "a", "b"]);
+        assert_eq!(canvas.layers[2].raster.grid.size(), Size::new(2, 2));
+        for _ in 0..3 {
+            canvas.commit_layer_event(&LayerEvent::Delete { index: 0 });
+        }
+        assert_eq!(names(&canvas), ["b"]);
+    }
+
+    fn shape(shape: BrushShape, size: u32) -> Brush {
+        Brush::Shape { shape, size }
+    }
+
+    #[test]
+    fn brush_footprints_have_the_chosen_shape_and_size() {
+        let offsets = |brush: Brush| brush.footprint().into_iter().collect::<HashSet<_>>();
+        assert_eq!(
+            offsets(shape(BrushShape::Circle, 1)),
+            iter::once(Coord::new(0, 0)).collect()
+        );
+        let square = offsets(shape(BrushShape::Square, 3));
+        assert_eq!(square.len(), 9);
+        assert!(square.iter().all(|c| c.x.abs() <= 1 && c.y.abs() <= 1));
+        // even sizes extend further to the right and down
+        let even = offsets(shape(BrushShape::Square, 2));
+        assert_eq!(
+            even,
+            [(0, 0), (1, 0), (0, 1), (1, 1)]
+                .into_iter()
+                .map(|(x, y)| Coord::new(x, y))
+                .collect()
+        );
+        // small circles and diamonds lose their corners
+        let plus = offsets(shape(BrushShape::Diamond, 3));
+        assert_eq!(plus.len(), 5);
+        assert_eq!(offsets(shape(BrushShape::Circle, 3)), plus);
+        let circle = offsets(shape(BrushShape::Circle, 5));
+        let diamond = offsets(shape(BrushShape::Diamond, 5));
+        assert_eq!(circle.len(), 21);
+        assert_eq!(diamond.len(), 13);
+        assert!(diamond.is_subset(&circle));
+        assert!(circle.contains(&Coord::new(2, 1)));
+        assert!(!circle.contains(&Coord::new(2, 2)));
+    }
+
+    #[test]
+    fn stroke_paints_each_cell_once_per_pass() {
+        let mut event =
+            StrokeEvent::mouse_press(Coord::new(1, 1), shape(BrushShape::Square, 3), false);
+        event.mouse_move(Coord::new(5, 1));
+        let coords = event
+            .cells(RenderCell::BLANK)
+            .into_iter()
+            .map(|(coord, _)| coord)
+            .collect::<Vec<_>>();
+        assert_eq!(coords.len(), 21);
+        assert_eq!(coords.iter().collect::<HashSet<_>>().len(), 21);
+        // going back over the stroke paints the cells it moves onto again
+        event.mouse_move(Coord::new(3, 1));
+        assert_eq!(event.cells(RenderCell::BLANK).len(), 27);
+    }
+
+    #[test]
+    fn single_cell_strokes_match_the_pencil() {
+        let translucent = RenderCell::BLANK.with_background(Rgba32::new(255, 0, 0, 127));
+        let path = [Coord::new(3, 0), Coord::new(1, 0)];
+        let mut pencil = Raster::new_transparent(Size::new(4, 1));
+        dragged(DrawingEvent::pencil(Coord::new(0, 0)), &path).commit(translucent, &mut pencil);
+        let mut stroke = Raster::new_transparent(Size::new(4, 1));
+        dragged(
+            DrawingEvent::stroke(Coord::new(0, 0), shape(BrushShape::Square, 1), false),
+            &path,
+        )
+        .commit(translucent, &mut stroke);
+        assert!(pencil == stroke);
+        // cells passed over twice are darker than those passed over once
+        let background = |x| pencil.grid.get_checked(Coord::new(x, 0)).background();
+        assert_eq!(background(1), background(2));
+        assert_eq!(background(0), background(3));
+        assert!(background(0) != background(1));
+    }
+
+    fn lock(channel: Channel) -> ChannelLocks {
+        let mut locks = ChannelLocks::default();
+        locks.toggle(channel);
+        locks
+    }
+
+    #[test]
+    fn locked_channels_survive_pencil_fill_and_erase() {
+        let style = |ch, r, g, b, bold, underline| RenderCell {
+            character: Some(ch),
+            style: Style {
+                foreground: Some(Rgba32::new(r, g, b, 255)),
+                background: Some(Rgba32::new(b, g, r, 255)),
+                bold: Some(bold),
+                underline: Some(underline),
+            },
+        };
+        let before = style('a', 0, 255, 0, true, false);
+        let drawn = style('z', 255, 0, 0, false, true);
+        // a channel is unchanged if restoring it from `a` leaves `b` as it is
+        let same = |channel, a, b| lock(channel).restore(a, b) == b;
+        let events: [fn(Coord) -> DrawingEvent; 3] = [
+            DrawingEvent::pencil,
+            DrawingEvent::flood_fill,
+            DrawingEvent::erase,
+        ];
+        for event in events {
+            for channel in Channel::ALL {
+                let mut raster = Raster {
+                    grid: Grid::new_clone(Size::new(2, 1), before),
+                };
+                event(Coord::new(0, 0))
+                    .locked(lock(channel))
+                    .commit(drawn, &mut raster);
+                let after = *raster.grid.get_checked(Coord::new(0, 0));
+                assert!(same(channel, before, after), "{} changed", channel.name());
+                for other in Channel::ALL.into_iter().filter(|&c| c != channel) {
+                    assert!(!same(other, before, after), "{} unchanged", other.name());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn stacking_takes_the_underline_from_the_underline() {
+        let cell = |bold, underline| RenderCell {
+            character: None,
+            style: Style {
+                bold,
+                underline,
+                ..Style::default()
+            },
+        };
+        let stacked = Raster::stack_render_cells(cell(None, Some(false)), cell(Some(true), None));
+        assert_eq!(stacked.style.bold, Some(true));
+        assert_eq!(stacked.style.underline, Some(false));
+        let stacked = Raster::stack_render_cells(cell(None, None), cell(Some(false), Some(true)));
+        assert_eq!(stacked.style.bold, Some(false));
+        assert_eq!(stacked.style.underline, Some(true));
+    }
+
+    fn dragged(mut event: DrawingEvent, coords: &[Coord]) -> DrawingEvent {
+        for &coord in coords {
+            event.mouse_move(coord);
+        }
+        event
+    }
+
+    // A history which uses every kind of event, where each event changes the canvas
+    fn sample_history() -> Vec<HistoryEvent> {
+        let red = RenderCell::BLANK
+            .with_character('r')
+            .with_background(Rgba32::new(255, 0, 0, 127));
+        let blue = RenderCell::BLANK.with_background(Rgba32::new(0, 0, 255, 255));
+        let mut text = DrawingEvent::text(Coord::new(1, 3));
+        if let DrawingEvent::Text(text) = &mut text {
+            "hi".chars()
+                .for_each(|ch| text.keyboard_input(KeyboardInput::Char(ch)));
+        }
+        let selection = Selection::from_corners(Coord::new(0, 0), Coord::new(2, 1));
+        let mut bg_lock = ChannelLocks::default();
+        bg_lock.toggle(Channel::Bg);
+        let layer = HistoryEvent::Layer;
+        vec![
+            drawing(
+                dragged(DrawingEvent::pencil(Coord::new(0, 0)), &[Coord::new(3, 2)]),
+                red,
+            ),
+            drawing(DrawingEvent::flood_fill(Coord::new(5, 1)), blue),
+            drawing(
+                dragged(DrawingEvent::line(Coord::new(0, 3)), &[Coord::new(5, 3)]),
+                blue,
+            ),
+            layer(LayerEvent::Add {
+                index: 1,
+                name: "top".to_string(),
+            }),
+            drawing_on(
+                1,
+                dragged(
+                    DrawingEvent::rectangle(Coord::new(1, 1), RectangleMode::BoxDrawing),
+                    &[Coord::new(4, 3)],
+                ),
+                red,
+            ),
+            drawing_on(
+                1,
+                dragged(
+                    DrawingEvent::ellipse(Coord::new(0, 0), EllipseMode::Filled, None),
+                    &[Coord::new(2, 2)],
+                ),
+                blue,
+            ),
+            layer(LayerEvent::Rename {
+                index: 1,
+                name: "renamed".to_string(),
+            }),
+            layer(LayerEvent::SetOpacity {
+                index: 1,
+                opacity: 100,
+            }),
+            layer(LayerEvent::SetVisible {
+                index: 0,
+                visible: false,
+            }),
+            layer(LayerEvent::SetLocked {
+                index: 0,
+                locked: true,
+            }),
+            drawing_on(
+                1,
+                dragged(
+                    DrawingEvent::stroke(
+                        Coord::new(2, 2),
+                        Brush::Shape {
+                            shape: BrushShape::Circle,
+                            size: 3,
+                        },
+                        false,
+                    ),
+                    &[Coord::new(5, 2)],
+                ),
+                red,
+            ),
+            HistoryEvent::Resize {
+                size: Size::new(8, 6),
+                anchor: Anchor::Centre,
+            },
+            drawing(
+                dragged(
+                    DrawingEvent::move_selection(selection, Coord::new(0, 0)),
+                    &[Coord::new(2, 2)],
+                ),
+                red,
+            ),
+            drawing(
+                DrawingEvent::paste(Coord::new(5, 4), raster_from_rows(&["xy", "z."]).grid),
+                red,
+            ),
+            drawing(
+                DrawingEvent::cut(Selection::from_corners(Coord::new(5, 4), Coord::new(5, 5))),
+                red,
+            ),
+            layer(LayerEvent::Move { from: 0, to: 1 }),
+            drawing(
+                dragged(DrawingEvent::erase(Coord::new(0, 2)), &[Coord::new(7, 2)]).locked(bg_lock),
+                red,
+            ),
+            drawing(text, blue),
+            layer(LayerEvent::Delete { index: 1 }),
+            HistoryEvent::Resize {
+                size: Size::new(4, 4),
+                anchor: Anchor::TopLeft,
+            },
+            layer(LayerEvent::Add {
+                index: 9,
+                name: "last".to_string(),
+            }),
+        ]
+    }
+
+    fn sample_canvas() -> Canvas {
+        Canvas::from_raster(raster_from_rows(&["abcdef", "......", "gh....", "......"]))
+    }
+
+    #[test]
+    fn undo_and_redo_step_through_the_same_canvases() {
+        let initial = sample_canvas();
+        let mut canvas = initial.clone();
+        let mut undo_buffer = UndoBuffer::new(initial.clone());
+        let mut canvases = vec![canvas.clone()];
+        for event in sample_history() {
+            undo_buffer.commit_event(event, &mut canvas);
+            canvases.push(canvas.clone());
+        }
+        for (i, pair) in canvases.windows(2).enumerate() {
+            assert!(pair[0] != pair[1], "event {} didn't change the canvas", i);
+        }
+        for (i, expected) in canvases.iter().enumerate().rev().skip(1) {
+            undo_buffer.undo(&mut canvas);
+            assert!(
+                &canvas == expected,
+                "undoing event {} gave the wrong canvas",
+                i
+            );
+        }
+        for (i, expected) in canvases.iter().enumerate().skip(1) {
+            undo_buffer.redo(&mut canvas);
+            assert!(
+                &canvas == expected,
+                "redoing event {} gave the wrong canvas",
+                i - 1
+            );
+        }
+    }
+
+    #[test]
+    fn saved_history_replays_to_the_same_canvases() {
+        let initial = sample_canvas();
+        let mut canvas = initial.clone();
+        let mut undo_buffer = UndoBuffer::new(initial.clone());
+        for event in sample_history() {
+            undo_buffer.commit_event(event, &mut canvas);
+        }
+        // leave some steps in the redo buffer
+        undo_buffer.undo(&mut canvas);
+        undo_buffer.undo(&mut canvas);
+        let data = bincode::serialize(&undo_buffer).unwrap();
+        let mut loaded: UndoBuffer = bincode::deserialize(&data).unwrap();
+        assert!(loaded.initial() == &initial);
+        assert_eq!(loaded.num_undo_steps(), undo_buffer.num_undo_steps());
+        assert_eq!(loaded.num_redo_steps(), 2);
+        let mut loaded_canvas = canvas.clone();
+        while loaded.num_redo_steps() > 0 {
+            loaded.redo(&mut loaded_canvas);
+            undo_buffer.redo(&mut canvas);
+            assert!(loaded_canvas == canvas);
+        }
+        while loaded.num_undo_steps() > 0 {
+            loaded.undo(&mut loaded_canvas);
+        }
+        assert!(loaded_canvas == initial);
+    }
+
+    #[test]
+    fn steps_only_record_what_changed() {
+        let mut canvas = Canvas::new(Size::new(100, 100));
+        let mut undo_buffer = UndoBuffer::new(canvas.clone());
+        let mut commit = |event| {
+            undo_buffer.commit_event(event, &mut canvas);
+            undo_buffer.undo_steps.pop().unwrap().change
+        };
+        let red = RenderCell::BLANK.with_background(Rgba32::new(255, 0, 0, 255));
+        let change = commit(drawing(DrawingEvent::pencil(Coord::new(5, 5)), red));
+        assert!(matches!(change, CanvasChange::Cells { cells, .. } if cells.len() == 1));
+        let change = commit(HistoryEvent::Layer(LayerEvent::Rename {
+            index: 0,
+            name: "renamed".to_string(),
+        }));
+        assert!(matches!(change, CanvasChange::Properties { index: 0, .. }));
+        let change = commit(HistoryEvent::Layer(LayerEvent::Add {
+            index: 1,
+            name: "top".to_string(),
+        }));
+        assert!(matches!(change, CanvasChange::Remove { index: 1 }));
+        let change = commit(HistoryEvent::Layer(LayerEvent::Move { from: 0, to: 1 }));
+        assert!(matches!(change, CanvasChange::Move { from: 1, to: 0 }));
+        let change = commit(HistoryEvent::Layer(LayerEvent::Delete { index: 5 }));
+        assert!(matches!(change, CanvasChange::Nothing));
+    }
+}
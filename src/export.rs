@@ -0,0 +1,169 @@
+use crate::{ansi, atomic, bitmap, html, svg};
+use chargrid::prelude::*;
+use grid_2d::Grid;
+use std::path::{Path, PathBuf};
+
+// Where the export command and the e key write to, and the size of cells in exported images
+pub struct ExportTarget {
+    pub path: Option<PathBuf>,
+    pub cell_size_px: Size,
+}
+
+pub enum ExportFormat {
+    Ansi,
+    Png { cell_size_px: Size },
+    Html,
+    Svg,
+}
+
+impl ExportFormat {
+    // Chooses a format based on the extension of a file. Images are drawn with each cell taking up
+    // `cell_size_px` pixels.
+    pub fn from_path<P: AsRef<Path>>(path: P, cell_size_px: Size) -> Result<Self, String> {
+        let path = path.as_ref();
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.to_lowercase());
+        match extension.as_deref() {
+            Some("ans" | "txt") => Ok(Self::Ansi),
+            Some("png") => Ok(Self::Png { cell_size_px }),
+            Some("html" | "htm") => Ok(Self::Html),
+            Some("svg") => Ok(Self::Svg),
+            _ => Err(format!(
+                "can't export to \"{}\" (supported extensions are .ans, .txt, .png, .html and .svg)",
+                path.display()
+            )),
+        }
+    }
+
+    pub fn export(&self, grid: &Grid<RenderCell>) -> Result<Vec<u8>, String> {
+        match self {
+            Self::Ansi => Ok(ansi::export(grid).into_bytes()),
+            Self::Png { cell_size_px } => bitmap::export(grid, *cell_size_px),
+            Self::Html => Ok(html::export(grid).into_bytes()),
+            Self::Svg => Ok(svg::export(grid).into_bytes()),
+        }
+    }
+}
+
+// Writes a grid to a file in the format implied by the file's extension
+pub fn export_to_path<P: AsRef<Path>>(
+    grid: &Grid<RenderCell>,
+    path: P,
+    cell_size_px: Size,
+) -> Result<(), String> {
+    let path = path.as_ref();
+    let data = ExportFormat::from_path(path, cell_size_px)?.export(grid)?;
+    atomic::write(path, data).map_err(|e| format!("failed to write \"{}\" ({})", path.display(), e))
+}
+
+// A horizontal sequence of cells within a row which share a style
+pub struct Run {
+    pub x: u32,
+    pub style: Style,
+    pub text: String,
+}
+
+impl Run {
+    pub fn len(&self) -> u32 {
+        self.text.chars().count() as u32
+    }
+}
+
+// Splits a row of cells into runs of the same style. Cells with no visible character take the
+// foreground colour and boldness of the preceding cell so they don't needlessly split runs.
+pub fn runs(row: &[RenderCell]) -> Vec<Run> {
+    let mut ret: Vec<Run> = Vec::new();
+    for (x, cell) in row.iter().enumerate() {
+        let character = cell.character.unwrap_or(' ');
+        let mut style = Style {
+            bold: Some(cell.style.bold == Some(true)),
+            underline: Some(cell.style.underline == Some(true)),
+            ..cell.style
+        };
+        if let Some(current) = ret.last_mut() {
+            if character == ' ' && style.underline == Some(false) {
+                style.foreground = current.style.foreground;
+                style.bold = current.style.bold;
+            }
+            if current.style == style {
+                current.text.push(character);
+                continue;
+            }
+        }
+        ret.push(Run {
+            x: x as u32,
+            style,
+            text: character.to_string(),
+        });
+    }
+    ret
+}
+
+// Escapes text for inclusion in HTML or XML
+pub fn escape_markup(text: &str) -> String {
+    let mut ret = String::new();
+    for character in text.chars() {
+        match character {
+            '&' => ret.push_str("&amp;"),
+            '<' => ret.push_str("&lt;"),
+            '>' => ret.push_str("&gt;"),
+            '"' => ret.push_str("&quot;"),
+            _ => ret.push(character),
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_is_chosen_by_extension() {
+        let cell_size_px = Size::new(8, 8);
+        let format = |path| ExportFormat::from_path(path, cell_size_px);
+        assert!(matches!(format("a.ANS"), Ok(ExportFormat::Ansi)));
+        assert!(matches!(format("a.txt"), Ok(ExportFormat::Ansi)));
+        assert!(matches!(
+            format("dir.png/a.png"),
+            Ok(ExportFormat::Png { cell_size_px: size }) if size == cell_size_px
+        ));
+        assert!(format("a.tp").is_err());
+        assert!(format("png").is_err());
+    }
+
+    #[test]
+    fn spaces_join_the_preceding_run_unless_underlined() {
+        let red = Rgba32::new(255, 0, 0, 255);
+        let blue = Rgba32::new(0, 0, 255, 255);
+        let row = [
+            RenderCell::BLANK.with_character('a').with_foreground(red),
+            RenderCell::BLANK.with_foreground(blue),
+            RenderCell::BLANK.with_character('b').with_foreground(red),
+            RenderCell::BLANK.with_underline(true),
+            RenderCell::BLANK.with_character('c').with_foreground(blue),
+        ];
+        let runs = runs(&row)
+            .into_iter()
+            .map(|run| (run.x, run.text))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            runs,
+            [
+                (0, "a b".to_string()),
+                (3, " ".to_string()),
+                (4, "c".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn markup_is_escaped() {
+        assert_eq!(
+            escape_markup("<a href=\"x\">&</a>"),
+            "&lt;a href=&quot;x&quot;&gt;&amp;&lt;/a&gt;"
+        );
+    }
+}
@@ -0,0 +1,25 @@
+// The CGA fonts are used both by the wgpu frontend and when rendering images
+pub const NORMAL: &[u8] = include_bytes!("./fonts/PxPlus_IBM_CGAthin.ttf");
+pub const BOLD: &[u8] = include_bytes!("./fonts/PxPlus_IBM_CGA.ttf");
+
+// Where underlines are drawn in each cell, as fractions of the cell height. These are used by the
+// wgpu frontend and when rendering images so underlines look the same everywhere.
+pub const UNDERLINE_WIDTH_CELL_RATIO: f64 = 0.1;
+pub const UNDERLINE_TOP_OFFSET_CELL_RATIO: f64 = 0.8;
+
+// The name given to the fonts by `css_font_faces`
+pub const CSS_FONT_FAMILY: &str = "PxPlus IBM CGA";
+
+// CSS which embeds both fonts as data URIs, using the bold font for bold text
+pub fn css_font_faces() -> String {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    let font_face = |weight: &str, bytes: &[u8]| {
+        format!(
+            "@font-face {{ font-family: \"{}\"; font-weight: {}; src: url(data:font/ttf;base64,{}); }}\n",
+            CSS_FONT_FAMILY,
+            weight,
+            STANDARD.encode(bytes)
+        )
+    };
+    font_face("normal", NORMAL) + &font_face("bold", BOLD)
+}
@@ -0,0 +1,80 @@
+use crate::{export, fonts};
+use chargrid::prelude::*;
+use grid_2d::Grid;
+use std::fmt::Write;
+
+fn css_colour(Rgba32 { r, g, b, a }: Rgba32) -> String {
+    if a == 255 {
+        format!("#{:02x}{:02x}{:02x}", r, g, b)
+    } else {
+        format!("rgba({}, {}, {}, {:.3})", r, g, b, a as f32 / 255.)
+    }
+}
+
+fn css_style(style: Style) -> String {
+    let mut ret = String::new();
+    if let Some(foreground) = style.foreground {
+        write!(ret, "color: {};", css_colour(foreground)).unwrap();
+    }
+    if let Some(background) = style.background {
+        write!(ret, "background-color: {};", css_colour(background)).unwrap();
+    }
+    if style.bold == Some(true) {
+        ret.push_str("font-weight: bold;");
+    }
+    if style.underline == Some(true) {
+        ret.push_str("text-decoration: underline;");
+    }
+    ret
+}
+
+// Converts a grid of cells into a standalone HTML document containing a `<pre>` element with a
+// styled `<span>` for each run of cells with the same style
+pub fn export(grid: &Grid<RenderCell>) -> String {
+    let mut out = String::new();
+    out.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<style>\n");
+    out.push_str(&fonts::css_font_faces());
+    writeln!(
+        out,
+        "pre {{ font-family: \"{}\", monospace; line-height: 1; }}",
+        fonts::CSS_FONT_FAMILY
+    )
+    .unwrap();
+    out.push_str("</style>\n</head>\n<body>\n<pre>");
+    for row in grid.rows() {
+        for run in export::runs(row) {
+            let style = css_style(run.style);
+            let text = export::escape_markup(&run.text);
+            if style.is_empty() {
+                out.push_str(&text);
+            } else {
+                write!(out, "<span style=\"{}\">{}</span>", style, text).unwrap();
+            }
+        }
+        out.push('\n');
+    }
+    out.push_str("</pre>\n</body>\n</html>\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_run_is_a_styled_span() {
+        let cells = [
+            RenderCell::BLANK
+                .with_character('<')
+                .with_foreground(Rgba32::new(255, 0, 0, 255))
+                .with_bold(true),
+            RenderCell::BLANK.with_background(Rgba32::new(0, 0, 255, 51)),
+        ];
+        let html = export(&Grid::new_iterator(Size::new(2, 1), cells.into_iter()));
+        assert!(html.contains(concat!(
+            "<pre><span style=\"color: #ff0000;font-weight: bold;\">&lt;</span>",
+            "<span style=\"color: #ff0000;background-color: rgba(0, 0, 255, 0.200);",
+            "font-weight: bold;\"> </span>\n</pre>"
+        )));
+    }
+}
@@ -0,0 +1,143 @@
+use chargrid::input::{keys, KeyboardInput};
+use std::{fs, path::Path};
+
+// Keymap files map action names to a key or a list of keys, e.g.
+//
+// undo = ["u", "ctrl+z"]
+// tool-pencil = "b"
+//
+// Keys are single characters, or names like "space", "f1" and "page-up". Modifiers are only
+// supported where they change the character that is received, so "shift+a" is the same as "A",
+// and "ctrl+a" is the control character sent by terminals (the graphical backend doesn't send
+// control characters).
+
+const NAMED_KEYS: &[(&str, KeyboardInput)] = &[
+    ("up", KeyboardInput::Up),
+    ("down", KeyboardInput::Down),
+    ("left", KeyboardInput::Left),
+    ("right", KeyboardInput::Right),
+    ("home", KeyboardInput::Home),
+    ("end", KeyboardInput::End),
+    ("page-up", KeyboardInput::PageUp),
+    ("page-down", KeyboardInput::PageDown),
+    ("delete", KeyboardInput::Delete),
+    ("escape", keys::ESCAPE),
+    ("backspace", keys::BACKSPACE),
+    ("tab", keys::TAB),
+    ("enter", keys::RETURN),
+    ("space", KeyboardInput::Char(' ')),
+];
+
+pub fn parse_key(s: &str) -> Result<KeyboardInput, String> {
+    let s = s.trim();
+    let mut chars = s.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return Ok(KeyboardInput::Char(c));
+    }
+    let lower = s.to_lowercase();
+    if let Some(&(_, key)) = NAMED_KEYS.iter().find(|(name, _)| *name == lower) {
+        return Ok(key);
+    }
+    if let Some(Ok(n)) = lower.strip_prefix('f').map(str::parse::<u8>) {
+        return Ok(KeyboardInput::Function(n));
+    }
+    let letter = |rest: &str| {
+        let mut chars = rest.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) if c.is_ascii_alphabetic() => Ok(c.to_ascii_lowercase()),
+            _ => Err(format!("expected a letter after the modifier in \"{}\"", s)),
+        }
+    };
+    if let Some(rest) = lower.strip_prefix("shift+") {
+        return Ok(KeyboardInput::Char(letter(rest)?.to_ascii_uppercase()));
+    }
+    if let Some(rest) = lower.strip_prefix("ctrl+") {
+        let code = letter(rest)? as u32 - 'a' as u32 + 1;
+        return Ok(KeyboardInput::Char(char::from_u32(code).unwrap()));
+    }
+    Err(format!("unknown key \"{}\"", s))
+}
+
+// The inverse of `parse_key`
+pub fn key_to_string(key: KeyboardInput) -> String {
+    if let Some((name, _)) = NAMED_KEYS.iter().find(|&&(_, k)| k == key) {
+        return name.to_string();
+    }
+    match key {
+        KeyboardInput::Function(n) => format!("f{}", n),
+        KeyboardInput::Char(c @ 'A'..='Z') => format!("shift+{}", c.to_ascii_lowercase()),
+        KeyboardInput::Char(c @ '\u{1}'..='\u{1a}') => {
+            format!(
+                "ctrl+{}",
+                char::from_u32(c as u32 - 1 + 'a' as u32).unwrap()
+            )
+        }
+        KeyboardInput::Char(c) => c.to_string(),
+        // all other keys are named, so this is only reached by keys added to chargrid later
+        _ => format!("{:?}", key).to_lowercase(),
+    }
+}
+
+// Returns the action names in the file along with the keys bound to each
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Vec<(String, Vec<KeyboardInput>)>, String> {
+    use toml::Value;
+    let string = fs::read_to_string(path).map_err(|e| format!("failed to read file ({})", e))?;
+    let toml = string
+        .parse::<toml::Table>()
+        .map_err(|e| format!("failed to parse file ({})", e))?;
+    let parse_key_value = |value: &Value| {
+        value
+            .as_str()
+            .ok_or_else(|| format!("expected string, got {:?}", value))
+            .and_then(parse_key)
+    };
+    toml.iter()
+        .map(|(action, value)| {
+            let keys = match value {
+                Value::Array(array) => array
+                    .iter()
+                    .map(parse_key_value)
+                    .collect::<Result<Vec<_>, _>>()?,
+                value => vec![parse_key_value(value)?],
+            };
+            Ok((action.clone(), keys))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn named_keys_round_trip() {
+        for &(name, key) in NAMED_KEYS {
+            assert_eq!(parse_key(name), Ok(key));
+            assert_eq!(key_to_string(key), name);
+        }
+    }
+
+    #[test]
+    fn keys_round_trip() {
+        let chars = ('\u{1}'..='\u{7f}').chain(['é', '█']);
+        let keys = chars
+            .map(KeyboardInput::Char)
+            .chain((1..=12).map(KeyboardInput::Function));
+        for key in keys {
+            assert_eq!(parse_key(&key_to_string(key)), Ok(key), "{:?}", key);
+        }
+    }
+
+    #[test]
+    fn modifiers_change_the_character() {
+        assert_eq!(parse_key("Shift+A"), Ok(KeyboardInput::Char('A')));
+        assert_eq!(parse_key(" ctrl+z "), Ok(KeyboardInput::Char('\u{1a}')));
+        assert_eq!(parse_key("PAGE-UP"), Ok(KeyboardInput::PageUp));
+        assert_eq!(parse_key("F5"), Ok(KeyboardInput::Function(5)));
+        assert_eq!(
+            parse_key("ctrl+1"),
+            Err("expected a letter after the modifier in \"ctrl+1\"".to_string())
+        );
+        assert_eq!(parse_key("hyper"), Err("unknown key \"hyper\"".to_string()));
+    }
+}
@@ -1,43 +1,116 @@
-use chargrid_wgpu;
-use std::path::PathBuf;
+use chargrid::prelude::Size;
+use std::{path::PathBuf, str::FromStr};
 
+mod ansi;
 mod app;
+mod atomic;
+mod bitmap;
+mod command;
+mod cp437;
+mod document;
+mod drawing;
+mod export;
+mod fonts;
+mod html;
+mod keymap;
 mod palette;
+mod svg;
+mod xp;
+
+// A size in cells or pixels written as WIDTHxHEIGHT (e.g. 80x25)
+struct CellSize(Size);
+
+impl FromStr for CellSize {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid size \"{}\" (expected WIDTHxHEIGHT)", s);
+        let (width, height) = s.split_once('x').ok_or_else(invalid)?;
+        let width = width.parse::<u32>().map_err(|_| invalid())?;
+        let height = height.parse::<u32>().map_err(|_| invalid())?;
+        if width == 0 || height == 0 {
+            return Err(invalid());
+        }
+        Ok(Self(Size::new(width, height)))
+    }
+}
 
 struct Args {
-    palette_path: PathBuf,
+    command: Option<command::Command>,
+    frame_index: usize,
+    palette_path: Option<PathBuf>,
     terminal: bool,
     input_path: Option<PathBuf>,
-    output_path: PathBuf,
+    image_path: Option<PathBuf>,
+    image_mode: bitmap::ImportMode,
+    image_size: Option<CellSize>,
+    size: Option<CellSize>,
+    output_path: Option<PathBuf>,
+    export_path: Option<PathBuf>,
+    cell_size_px: Option<CellSize>,
 }
 
 impl Args {
     fn parser() -> impl meap::Parser<Item = Self> {
         meap::let_map! {
             let {
-                palette_path = opt_req("PATH", "palette").name('p');
+                command = pos_opt("COMMAND")
+                    .desc("export, convert or info to run without starting the ui");
+                frame_index = opt_opt("INDEX", "frame")
+                    .desc("frame written by the export and convert commands (starting at 0)")
+                    .with_default(0);
+                palette_path = opt_opt("PATH", "palette").name('p')
+                    .desc("defaults to the palette the input file was saved with");
                 terminal = flag("terminal").name('t').desc("run in a terminal");
-                input_path = opt_opt("PATH", "input").name('i');
-                output_path = opt_req("PATH", "output").name('o');
+                input_path = opt_opt("PATH", "input").name('i')
+                    .desc("drawing to open (.xp and .ans files are converted)");
+                image_path = opt_opt("PATH", "image")
+                    .desc("png image to convert into a new drawing");
+                image_mode = opt_opt("MODE", "image-mode")
+                    .desc("how images are converted (half-block, shade or palette)")
+                    .with_default(bitmap::ImportMode::HalfBlock);
+                image_size = opt_opt("WxH", "image-size")
+                    .desc("size in cells of converted images (defaults to at most 80 cells wide)");
+                size = opt_opt("WxH", "size").name('s')
+                    .desc("size in cells of new drawings (defaults to 80x60)");
+                output_path = opt_opt("PATH", "output").name('o')
+                    .desc("file written when pressing s or by convert (.xp and .ans files are converted)");
+                export_path = opt_opt("PATH", "export").name('e')
+                    .desc("file written when pressing e or by export (.ans, .txt, .png, .html or .svg)");
+                cell_size_px = opt_opt("WxH", "cell-size")
+                    .desc("size in pixels of each cell in png exports (defaults to 16x16)");
             } in {
                 Self {
+                    command,
+                    frame_index,
                     palette_path,
                     terminal,
                     input_path,
+                    image_path,
+                    image_mode,
+                    image_size,
+                    size,
                     output_path,
+                    export_path,
+                    cell_size_px,
                 }
             }
         }
     }
 }
 
+// Terminal cells are roughly twice as tall as they are wide
+const TERMINAL_CELL_ASPECT_RATIO: f64 = 2.;
+
+// The wgpu context is configured with square cells
+const WGPU_CELL_ASPECT_RATIO: f64 = 1.;
+
 fn wgpu_context() -> chargrid_wgpu::Context {
     use chargrid_wgpu::*;
     const CELL_SIZE_PX: f64 = 12.;
     Context::new(Config {
         font_bytes: FontBytes {
-            normal: include_bytes!("./fonts/PxPlus_IBM_CGAthin.ttf").to_vec(),
-            bold: include_bytes!("./fonts/PxPlus_IBM_CGA.ttf").to_vec(),
+            normal: fonts::NORMAL.to_vec(),
+            bold: fonts::BOLD.to_vec(),
         },
         title: "Text Paint".to_string(),
         window_dimensions_px: Dimensions {
@@ -52,8 +125,8 @@ fn wgpu_context() -> chargrid_wgpu::Context {
             width: CELL_SIZE_PX,
             height: CELL_SIZE_PX,
         },
-        underline_width_cell_ratio: 0.1,
-        underline_top_offset_cell_ratio: 0.8,
+        underline_width_cell_ratio: fonts::UNDERLINE_WIDTH_CELL_RATIO,
+        underline_top_offset_cell_ratio: fonts::UNDERLINE_TOP_OFFSET_CELL_RATIO,
         resizable: false,
         force_secondary_adapter: false,
     })
@@ -62,12 +135,68 @@ fn wgpu_context() -> chargrid_wgpu::Context {
 fn main() {
     use meap::Parser;
     let Args {
+        command,
+        frame_index,
         palette_path,
         terminal,
         input_path,
+        image_path,
+        image_mode,
+        image_size,
+        size,
         output_path,
+        export_path,
+        cell_size_px,
     } = Args::parser().with_help_default().parse_env_or_exit();
-    let app = app::app(palette_path, input_path, output_path);
+    let export_target = export::ExportTarget {
+        path: export_path,
+        cell_size_px: cell_size_px.map(|CellSize(size)| size).unwrap_or_else(|| {
+            Size::new(bitmap::DEFAULT_CELL_SIZE_PX, bitmap::DEFAULT_CELL_SIZE_PX)
+        }),
+    };
+    if let Some(command) = command {
+        if let Err(e) = command::run(
+            command,
+            palette_path,
+            input_path,
+            output_path,
+            export_target,
+            frame_index,
+        ) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    let Some(output_path) = output_path else {
+        eprintln!("--output is required when starting the ui");
+        std::process::exit(1);
+    };
+    let cell_aspect_ratio = if terminal {
+        TERMINAL_CELL_ASPECT_RATIO
+    } else {
+        WGPU_CELL_ASPECT_RATIO
+    };
+    let image_import = image_path.map(|path| app::ImageImport {
+        path,
+        mode: image_mode,
+        size: image_size.map(|CellSize(size)| size),
+    });
+    let app = match app::app(
+        palette_path,
+        input_path,
+        image_import,
+        size.map(|CellSize(size)| size),
+        output_path,
+        export_target,
+        cell_aspect_ratio,
+    ) {
+        Ok(app) => app,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
     if terminal {
         use chargrid_ansi_terminal::{Context, XtermTrueColour};
         let context = Context::new().expect("Failed to initialize terminal");
@@ -1,9 +1,10 @@
+use crate::atomic;
 use rgb_int::Rgb24;
 use serde::{Deserialize, Serialize};
-use std::{fs, path::Path};
-use toml;
+use std::{fs, io, path::Path};
+use toml_edit::{Array, Decor, Document, Item, RawString, Value};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Palette {
     pub fg: Vec<Rgb24>,
     pub bg: Vec<Rgb24>,
@@ -24,7 +25,7 @@ mod hex_rgb24 {
     }
 
     fn is_hex_digit(c: char) -> bool {
-        c.is_digit(16)
+        c.is_ascii_hexdigit()
     }
 
     fn hex_primary(input: &str) -> IResult<&str, u8> {
@@ -58,6 +59,10 @@ mod palette_toml {
         let str = toml
             .as_str()
             .ok_or_else(|| format!("expected string, got {:?}", toml))?;
+        parse_ch_str(str)
+    }
+
+    pub fn parse_ch_str(str: &str) -> Result<char, String> {
         let chars = str.chars().collect::<Vec<_>>();
         if chars.len() == 1 {
             Ok(chars[0])
@@ -118,4 +123,361 @@ impl Palette {
             .map_err(|e| format!("failed to parse file ({})", e))?;
         palette_toml::parse_palette(&toml)
     }
+
+    // Writes the palette in the format read by `load`. An existing file is edited in place so
+    // its comments, formatting and any other keys are kept.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        let path = path.as_ref();
+        let mut document = match fs::read_to_string(path) {
+            Ok(string) => string
+                .parse::<Document>()
+                .map_err(|e| format!("failed to parse file ({})", e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Document::new(),
+            Err(e) => return Err(format!("failed to read file ({})", e)),
+        };
+        let fg = self.fg.iter().map(|&rgb24| rgb24_to_hex(rgb24));
+        let bg = self.bg.iter().map(|&rgb24| rgb24_to_hex(rgb24));
+        let ch = self.ch.iter().map(|ch| ch.to_string());
+        set_array(&mut document, "fg", fg.collect());
+        set_array(&mut document, "bg", bg.collect());
+        set_array(&mut document, "ch", ch.collect());
+        atomic::write(path, document.to_string())
+            .map_err(|e| format!("failed to write file ({})", e))
+    }
+
+    pub fn apply_edit(&mut self, row: PaletteRow, edit: PaletteEdit) -> Result<(), String> {
+        match row {
+            PaletteRow::Ch => edit.apply(&mut self.ch, palette_toml::parse_ch_str),
+            PaletteRow::Fg => edit.apply(&mut self.fg, rgb24_from_hex),
+            PaletteRow::Bg => edit.apply(&mut self.bg, rgb24_from_hex),
+        }
+    }
+}
+
+// An entry of an array in a palette file along with the text around it
+struct ArrayEntry {
+    value: Option<String>,
+    // whitespace and comment lines before the entry
+    leading: String,
+    // whitespace between the entry and its comma
+    suffix: String,
+    // a comment on the same line after the entry
+    line_comment: String,
+}
+
+// Splits text at its first line break. In toml_edit, the text before it ends the previous line.
+fn split_line(s: &str) -> (&str, &str) {
+    s.split_at(s.find('\n').unwrap_or(0))
+}
+
+fn raw_str(raw: Option<&RawString>) -> &str {
+    raw.and_then(RawString::as_str).unwrap_or("")
+}
+
+// Replaces the contents of an array of strings. Entries which are still present keep their
+// comments and formatting, even if they've moved, and other entries are formatted like the entry
+// previously at their position.
+fn set_array(document: &mut Document, key: &str, values: Vec<String>) {
+    let Some(array) = document.get_mut(key).and_then(Item::as_array_mut) else {
+        document[key] = toml_edit::value(values.into_iter().collect::<Array>());
+        return;
+    };
+    let prefixes = array
+        .iter()
+        .map(|value| raw_str(value.decor().prefix()))
+        .collect::<Vec<_>>();
+    let (head, _) = split_line(prefixes.first().copied().unwrap_or(""));
+    let (last_line_comment, tail) = split_line(raw_str(Some(array.trailing())));
+    let mut entries = array
+        .iter()
+        .enumerate()
+        .map(|(i, value)| {
+            let line_comment = match prefixes.get(i + 1) {
+                Some(next) => split_line(next).0,
+                None => last_line_comment,
+            };
+            ArrayEntry {
+                value: value.as_str().map(str::to_string),
+                leading: split_line(prefixes[i]).1.to_string(),
+                suffix: raw_str(value.decor().suffix()).to_string(),
+                line_comment: line_comment.to_string(),
+            }
+        })
+        .map(Some)
+        .collect::<Vec<_>>();
+    let (head, tail) = (head.to_string(), tail.to_string());
+    // the whitespace before the entry at index `i`, without any comments
+    let plain = |i: usize| {
+        let leading = prefixes
+            .get(i)
+            .or(prefixes.last())
+            .map(|prefix| split_line(prefix).1)
+            .unwrap_or("");
+        match &leading[leading.rfind('\n').unwrap_or(0)..] {
+            "" if i > 0 => " ".to_string(),
+            whitespace => whitespace.to_string(),
+        }
+    };
+    let new_entries = values
+        .into_iter()
+        .enumerate()
+        .map(|(i, value)| {
+            let existing = entries.iter_mut().find(|entry| {
+                entry
+                    .as_ref()
+                    .is_some_and(|entry| entry.value.as_ref() == Some(&value))
+            });
+            match existing.and_then(Option::take) {
+                // entries on their own lines keep the comments above them
+                Some(entry) if entry.leading.contains('\n') => ArrayEntry {
+                    value: Some(value),
+                    ..entry
+                },
+                Some(entry) => ArrayEntry {
+                    value: Some(value),
+                    leading: plain(i),
+                    ..entry
+                },
+                None => ArrayEntry {
+                    value: Some(value),
+                    leading: plain(i),
+                    suffix: String::new(),
+                    line_comment: String::new(),
+                },
+            }
+        })
+        .collect::<Vec<_>>();
+    array.clear();
+    let mut line_comment = head;
+    for entry in new_entries {
+        let mut value = Value::from(entry.value.unwrap_or_default());
+        *value.decor_mut() = Decor::new(line_comment + &entry.leading, entry.suffix);
+        array.push_formatted(value);
+        line_comment = entry.line_comment;
+    }
+    array.set_trailing(line_comment + &tail);
+}
+
+pub fn rgb24_to_hex(Rgb24 { r, g, b }: Rgb24) -> String {
+    format!("#{:02x}{:02x}{:02x}", r, g, b)
+}
+
+pub fn rgb24_from_hex(s: &str) -> Result<Rgb24, String> {
+    match hex_rgb24::parse_hex_rgb24(s.trim()) {
+        Ok(("", rgb24)) => Ok(rgb24),
+        _ => Err(format!("expected a colour like #ff8000, got \"{}\"", s)),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PaletteRow {
+    Ch,
+    Fg,
+    Bg,
+}
+
+impl PaletteRow {
+    pub const ALL: [Self; 3] = [Self::Ch, Self::Fg, Self::Bg];
+
+    pub fn len(self, palette: &Palette) -> usize {
+        match self {
+            Self::Ch => palette.ch.len(),
+            Self::Fg => palette.fg.len(),
+            Self::Bg => palette.bg.len(),
+        }
+    }
+
+    // The entry at `index` in the form it's written in palette files
+    pub fn entry_string(self, palette: &Palette, index: usize) -> Option<String> {
+        match self {
+            Self::Ch => palette.ch.get(index).map(|ch| ch.to_string()),
+            Self::Fg => palette.fg.get(index).map(|&rgb24| rgb24_to_hex(rgb24)),
+            Self::Bg => palette.bg.get(index).map(|&rgb24| rgb24_to_hex(rgb24)),
+        }
+    }
+}
+
+// A change to one row of a palette. Entries are given as strings in the form they're written in
+// palette files.
+pub enum PaletteEdit {
+    Set(usize, String),
+    Insert(usize, String),
+    Remove(usize),
+    Swap(usize, usize),
+}
+
+impl PaletteEdit {
+    fn apply<T, F: Fn(&str) -> Result<T, String>>(
+        self,
+        entries: &mut Vec<T>,
+        parse: F,
+    ) -> Result<(), String> {
+        let len = entries.len();
+        let out_of_range = |index| format!("no entry at index {}", index);
+        match self {
+            Self::Set(index, string) => {
+                let value = parse(&string)?;
+                *entries.get_mut(index).ok_or_else(|| out_of_range(index))? = value;
+            }
+            Self::Insert(index, string) => {
+                let value = parse(&string)?;
+                entries.insert(index.min(len), value);
+            }
+            Self::Remove(index) => {
+                if index >= len {
+                    return Err(out_of_range(index));
+                }
+                // palettes must have at least one entry in each row
+                if len == 1 {
+                    return Err("can't remove the last entry".to_string());
+                }
+                entries.remove(index);
+            }
+            Self::Swap(a, b) => {
+                if a >= len || b >= len {
+                    return Err(out_of_range(a.max(b)));
+                }
+                entries.swap(a, b);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn palette() -> Palette {
+        Palette {
+            fg: vec![Rgb24::new(255, 0, 0)],
+            bg: vec![Rgb24::new(0, 0, 0), Rgb24::new(0, 0, 255)],
+            ch: vec!['a', 'b', 'c'],
+        }
+    }
+
+    #[test]
+    fn edits_change_one_row() {
+        let mut palette = palette();
+        palette
+            .apply_edit(PaletteRow::Ch, PaletteEdit::Set(1, "█".to_string()))
+            .unwrap();
+        palette
+            .apply_edit(PaletteRow::Ch, PaletteEdit::Swap(0, 2))
+            .unwrap();
+        palette
+            .apply_edit(PaletteRow::Ch, PaletteEdit::Insert(10, "d".to_string()))
+            .unwrap();
+        assert_eq!(palette.ch, ['c', '█', 'a', 'd']);
+        palette
+            .apply_edit(
+                PaletteRow::Fg,
+                PaletteEdit::Insert(0, " #00ff00".to_string()),
+            )
+            .unwrap();
+        palette
+            .apply_edit(PaletteRow::Bg, PaletteEdit::Remove(0))
+            .unwrap();
+        assert_eq!(palette.fg, [Rgb24::new(0, 255, 0), Rgb24::new(255, 0, 0)]);
+        assert_eq!(palette.bg, [Rgb24::new(0, 0, 255)]);
+        assert_eq!(
+            PaletteRow::Fg.entry_string(&palette, 0).as_deref(),
+            Some("#00ff00")
+        );
+    }
+
+    #[test]
+    fn invalid_edits_are_rejected() {
+        let mut palette = palette();
+        let mut edit = |row, edit| palette.apply_edit(row, edit).err().unwrap();
+        assert_eq!(
+            edit(PaletteRow::Ch, PaletteEdit::Set(0, "ab".to_string())),
+            "expected string of length 1, got ab"
+        );
+        assert_eq!(
+            edit(PaletteRow::Fg, PaletteEdit::Set(0, "red".to_string())),
+            "expected a colour like #ff8000, got \"red\""
+        );
+        assert_eq!(
+            edit(PaletteRow::Fg, PaletteEdit::Remove(0)),
+            "can't remove the last entry"
+        );
+        assert_eq!(
+            edit(PaletteRow::Bg, PaletteEdit::Swap(0, 2)),
+            "no entry at index 2"
+        );
+        assert_eq!(palette.fg, [Rgb24::new(255, 0, 0)]);
+    }
+
+    #[test]
+    fn saved_palettes_can_be_loaded() {
+        let path =
+            std::env::temp_dir().join(format!("text-paint-palette-{}.toml", std::process::id()));
+        let palette = palette();
+        palette.save(&path).unwrap();
+        let loaded = Palette::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.fg, palette.fg);
+        assert_eq!(loaded.bg, palette.bg);
+        assert_eq!(loaded.ch, palette.ch);
+    }
+
+    #[test]
+    fn saving_keeps_comments_and_formatting() {
+        let path = std::env::temp_dir().join(format!(
+            "text-paint-palette-comments-{}.toml",
+            std::process::id()
+        ));
+        let original = r##"# my palette
+fg = [
+    "#ff0000", # red
+    # a nice blue
+    "#0000ff",
+]
+bg = ["#000000", "#ffffff"] # greys
+
+# characters
+ch = ["a"]
+name = "test"
+"##;
+        fs::write(&path, original).unwrap();
+        let mut palette = Palette::load(&path).unwrap();
+        palette.fg.swap(0, 1);
+        palette.fg.push(Rgb24::new(0, 255, 0));
+        palette.bg.remove(0);
+        palette.bg.insert(0, Rgb24::new(1, 2, 3));
+        palette.ch.push('b');
+        palette.save(&path).unwrap();
+        let saved = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let expected = r##"# my palette
+fg = [
+    # a nice blue
+    "#0000ff",
+    "#ff0000", # red
+    "#00ff00",
+]
+bg = ["#010203", "#ffffff"] # greys
+
+# characters
+ch = ["a", "b"]
+name = "test"
+"##;
+        assert_eq!(saved, expected);
+    }
+
+    #[test]
+    fn saving_an_unchanged_palette_keeps_the_file_the_same() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/resources/test_palette.toml");
+        let original = fs::read_to_string(path).unwrap();
+        let copy = std::env::temp_dir().join(format!(
+            "text-paint-palette-unchanged-{}.toml",
+            std::process::id()
+        ));
+        fs::write(&copy, &original).unwrap();
+        Palette::load(&copy).unwrap().save(&copy).unwrap();
+        let saved = fs::read_to_string(&copy).unwrap();
+        fs::remove_file(&copy).unwrap();
+        assert_eq!(saved, original);
+    }
 }
@@ -0,0 +1,147 @@
+use crate::{export, fonts};
+use ab_glyph::{Font, FontRef};
+use chargrid::prelude::*;
+use grid_2d::Grid;
+use std::fmt::Write;
+
+// Cells are 8 user units square, matching the size the CGA fonts were designed for
+const CELL_SIZE: u32 = 8;
+
+// Returns the attributes for filling a shape with a colour, using a separate opacity attribute
+// since not all SVG renderers understand colours with alpha
+fn fill(Rgba32 { r, g, b, a }: Rgba32) -> String {
+    let mut ret = format!("fill=\"#{:02x}{:02x}{:02x}\"", r, g, b);
+    if a != 255 {
+        write!(ret, " fill-opacity=\"{:.3}\"", a as f32 / 255.).unwrap();
+    }
+    ret
+}
+
+// Converts a grid of cells into an SVG image with a `<rect>` for the background of each run of
+// cells with the same style, and a `<text>` for its characters
+pub fn export(grid: &Grid<RenderCell>) -> String {
+    let font = FontRef::try_from_slice(fonts::NORMAL).expect("invalid font");
+    // the font size is the em size, which may differ from the distance between the ascender and
+    // descender that fits in a cell
+    let cell_size = CELL_SIZE as f32;
+    let font_size =
+        cell_size * font.units_per_em().unwrap_or(font.height_unscaled()) / font.height_unscaled();
+    let baseline = cell_size * font.ascent_unscaled() / font.height_unscaled();
+    let width = grid.width() * CELL_SIZE;
+    let height = grid.height() * CELL_SIZE;
+    let mut out = String::new();
+    writeln!(
+        out,
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" viewBox=\"0 0 {} {}\">",
+        width, height, width, height
+    )
+    .unwrap();
+    out.push_str("<style>\n");
+    out.push_str(&fonts::css_font_faces());
+    writeln!(
+        out,
+        "text {{ font-family: \"{}\", monospace; font-size: {}px; white-space: pre; }}",
+        fonts::CSS_FONT_FAMILY,
+        font_size
+    )
+    .unwrap();
+    out.push_str("</style>\n");
+    for (y, row) in grid.rows().enumerate() {
+        let y = y as u32 * CELL_SIZE;
+        let runs = export::runs(row);
+        // backgrounds are merged separately as neighbouring runs often share a background
+        let mut backgrounds: Vec<(u32, u32, Option<Rgba32>)> = Vec::new();
+        for run in &runs {
+            match backgrounds.last_mut() {
+                Some((_, len, background)) if *background == run.style.background => {
+                    *len += run.len()
+                }
+                _ => backgrounds.push((run.x, run.len(), run.style.background)),
+            }
+        }
+        for (x, len, background) in backgrounds {
+            if let Some(background) = background {
+                writeln!(
+                    out,
+                    "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" {}/>",
+                    x * CELL_SIZE,
+                    y,
+                    len * CELL_SIZE,
+                    CELL_SIZE,
+                    fill(background)
+                )
+                .unwrap();
+            }
+        }
+        for run in runs {
+            let x = run.x * CELL_SIZE;
+            let run_width = run.len() * CELL_SIZE;
+            let Some(foreground) = run.style.foreground else {
+                continue;
+            };
+            if !run.text.trim().is_empty() {
+                let weight = if run.style.bold == Some(true) {
+                    " font-weight=\"bold\""
+                } else {
+                    ""
+                };
+                // textLength keeps the characters aligned to the grid if the font doesn't load
+                writeln!(
+                    out,
+                    "<text x=\"{}\" y=\"{}\" textLength=\"{}\" {}{}>{}</text>",
+                    x,
+                    y as f32 + baseline,
+                    run_width,
+                    fill(foreground),
+                    weight,
+                    export::escape_markup(&run.text)
+                )
+                .unwrap();
+            }
+            if run.style.underline == Some(true) {
+                writeln!(
+                    out,
+                    "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" {}/>",
+                    x,
+                    y as f32 + cell_size * fonts::UNDERLINE_TOP_OFFSET_CELL_RATIO as f32,
+                    run_width,
+                    (cell_size * fonts::UNDERLINE_WIDTH_CELL_RATIO as f32).max(1.),
+                    fill(foreground)
+                )
+                .unwrap();
+            }
+        }
+    }
+    out.push_str("</svg>\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn neighbouring_runs_share_a_background_rect() {
+        let blue = Rgba32::new(0, 0, 255, 255);
+        let cells = [
+            RenderCell::BLANK.with_character('a').with_background(blue),
+            RenderCell::BLANK
+                .with_character('b')
+                .with_foreground(Rgba32::new(255, 0, 0, 255))
+                .with_background(blue),
+            RenderCell::BLANK,
+        ];
+        let svg = export(&Grid::new_iterator(Size::new(3, 1), cells.into_iter()));
+        let rects = svg
+            .lines()
+            .filter(|line| line.starts_with("<rect"))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rects,
+            ["<rect x=\"0\" y=\"0\" width=\"16\" height=\"8\" fill=\"#0000ff\"/>"]
+        );
+        // the first run has no foreground so its character isn't drawn
+        assert_eq!(svg.matches("<text").count(), 1);
+        assert!(svg.contains("textLength=\"8\" fill=\"#ff0000\">b</text>"));
+    }
+}
@@ -0,0 +1,220 @@
+use crate::{
+    cp437,
+    drawing::{Canvas, Layer, Raster},
+};
+use chargrid::prelude::*;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use grid_2d::Grid;
+use rgb_int::Rgb24;
+use std::io::{Read, Write};
+
+// REXPaint's .xp format is a gzip-compressed sequence of little-endian integers. After a version
+// number and a layer count, each layer has a width and height followed by its cells in
+// column-major order. Each cell is a CP437 glyph index, a foreground colour and a background
+// colour.
+
+const VERSION: i32 = -1;
+
+// Cells with this background colour are transparent
+const TRANSPARENT: Rgb24 = Rgb24::new(255, 0, 255);
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        let (bytes, rest) = self
+            .data
+            .split_first_chunk::<N>()
+            .ok_or_else(|| "unexpected end of file".to_string())?;
+        self.data = rest;
+        Ok(*bytes)
+    }
+
+    fn i32(&mut self) -> Result<i32, String> {
+        self.bytes().map(i32::from_le_bytes)
+    }
+
+    fn rgb24(&mut self) -> Result<Rgb24, String> {
+        self.bytes().map(|[r, g, b]| Rgb24::new(r, g, b))
+    }
+}
+
+fn read_cell(reader: &mut Reader) -> Result<RenderCell, String> {
+    let glyph = reader.i32()?;
+    let foreground = reader.rgb24()?;
+    let background = reader.rgb24()?;
+    if background == TRANSPARENT {
+        return Ok(RenderCell::BLANK);
+    }
+    let character = match glyph {
+        0 => None,
+        1..=255 => Some(cp437::to_char(glyph as u8)),
+        _ => return Err(format!("invalid glyph index {}", glyph)),
+    };
+    Ok(RenderCell {
+        character,
+        style: Style::default()
+            .with_foreground(foreground.to_rgba32(255))
+            .with_background(background.to_rgba32(255)),
+    })
+}
+
+fn read_layer(reader: &mut Reader) -> Result<Grid<RenderCell>, String> {
+    let width = reader.i32()?;
+    let height = reader.i32()?;
+    if width <= 0 || height <= 0 {
+        return Err(format!("invalid layer size {}x{}", width, height));
+    }
+    let size = Size::new(width as u32, height as u32);
+    // check there's enough data for every cell before allocating space for them
+    const CELL_BYTES: usize = 10;
+    if reader.data.len() / CELL_BYTES < size.count() {
+        return Err("unexpected end of file".to_string());
+    }
+    let mut grid = Grid::new_copy(size, RenderCell::BLANK);
+    for x in 0..width {
+        for y in 0..height {
+            *grid.get_checked_mut(Coord::new(x, y)) = read_cell(reader)?;
+        }
+    }
+    Ok(grid)
+}
+
+// Reads each layer of a .xp file into a layer of a canvas
+pub fn import(data: &[u8]) -> Result<Canvas, String> {
+    let mut decompressed = Vec::new();
+    GzDecoder::new(data)
+        .read_to_end(&mut decompressed)
+        .map_err(|e| format!("failed to decompress ({})", e))?;
+    let mut reader = Reader {
+        data: &decompressed,
+    };
+    // files written by early versions of REXPaint start with the layer count
+    let num_layers = match reader.i32()? {
+        version if version < 0 => reader.i32()?,
+        num_layers => num_layers,
+    };
+    if num_layers <= 0 {
+        return Err(format!("invalid number of layers {}", num_layers));
+    }
+    let mut layers = Vec::new();
+    for i in 0..num_layers {
+        let grid = read_layer(&mut reader)?;
+        if let Some(first) = layers.first().map(|layer: &Layer| layer.raster.grid.size()) {
+            if grid.size() != first {
+                return Err("layers have different sizes".to_string());
+            }
+        }
+        layers.push(Layer::new(format!("Layer {}", i + 1), Raster { grid }));
+    }
+    Ok(Canvas { layers })
+}
+
+// Colours are written without alpha, and fully transparent backgrounds become the transparent
+// colour. REXPaint draws the transparent colour as black on the bottom layer, so characters
+// without a background are written on black there rather than being dropped.
+fn write_cell(out: &mut Vec<u8>, cell: RenderCell, bottom_layer: bool) {
+    let background = cell
+        .background()
+        .filter(|background| background.a > 0)
+        .map(Rgba32::to_rgb24)
+        .or_else(|| {
+            (bottom_layer && cell.character.is_some_and(|character| character != ' '))
+                .then_some(Rgb24::new_grey(0))
+        });
+    let (glyph, foreground, background) = match background {
+        None => (0, Rgb24::new_grey(0), TRANSPARENT),
+        Some(background) => {
+            // characters which aren't in CP437 are replaced with '?'
+            let glyph = cell
+                .character
+                .map(|character| cp437::from_char(character).unwrap_or(b'?'))
+                .unwrap_or(0);
+            let foreground = cell
+                .foreground()
+                .map(Rgba32::to_rgb24)
+                .unwrap_or(Rgb24::new_grey(0));
+            (glyph, foreground, background)
+        }
+    };
+    out.extend_from_slice(&(glyph as i32).to_le_bytes());
+    out.extend_from_slice(&[foreground.r, foreground.g, foreground.b]);
+    out.extend_from_slice(&[background.r, background.g, background.b]);
+}
+
+// Writes each layer of a canvas to a layer of a .xp file. Layer names, visibility and opacity
+// can't be represented in the format.
+pub fn export(canvas: &Canvas) -> Result<Vec<u8>, String> {
+    let mut out = Vec::new();
+    out.extend_from_slice(&VERSION.to_le_bytes());
+    out.extend_from_slice(&(canvas.layers.len() as i32).to_le_bytes());
+    for (i, layer) in canvas.layers.iter().enumerate() {
+        let grid = &layer.raster.grid;
+        out.extend_from_slice(&(grid.width() as i32).to_le_bytes());
+        out.extend_from_slice(&(grid.height() as i32).to_le_bytes());
+        for x in 0..grid.width() as i32 {
+            for y in 0..grid.height() as i32 {
+                write_cell(&mut out, *grid.get_checked(Coord::new(x, y)), i == 0);
+            }
+        }
+    }
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder
+        .write_all(&out)
+        .and_then(|()| encoder.finish())
+        .map_err(|e| format!("failed to compress ({})", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layers_round_trip() {
+        let red = Rgba32::new(255, 0, 0, 255);
+        let blue = Rgba32::new(0, 0, 255, 255);
+        let mut canvas = Canvas::new(Size::new(2, 1));
+        let bottom = &mut canvas.layers[0].raster.grid;
+        *bottom.get_checked_mut(Coord::new(0, 0)) = RenderCell::BLANK
+            .with_character('☺')
+            .with_foreground(red)
+            .with_background(blue);
+        *bottom.get_checked_mut(Coord::new(1, 0)) = RenderCell::BLANK;
+        let mut top = Raster::new_transparent(Size::new(2, 1));
+        *top.grid.get_checked_mut(Coord::new(1, 0)) = RenderCell::BLANK
+            .with_character('x')
+            .with_foreground(blue)
+            .with_background(red);
+        canvas.layers.push(Layer::new("top".to_string(), top));
+        let imported = import(&export(&canvas).unwrap()).unwrap();
+        assert_eq!(imported.layers.len(), 2);
+        for (imported, layer) in imported.layers.iter().zip(&canvas.layers) {
+            assert_eq!(imported.raster.grid, layer.raster.grid);
+        }
+    }
+
+    #[test]
+    fn characters_without_a_background_are_kept_on_the_bottom_layer() {
+        let cell = RenderCell::BLANK
+            .with_character('a')
+            .with_foreground(Rgba32::new(255, 0, 0, 255));
+        let mut canvas = Canvas::from_raster(Raster {
+            grid: Grid::new_copy(Size::new(1, 1), cell),
+        });
+        canvas.layers.push(Layer::new(
+            "top".to_string(),
+            Raster {
+                grid: Grid::new_copy(Size::new(1, 1), cell),
+            },
+        ));
+        let imported = import(&export(&canvas).unwrap()).unwrap();
+        let cell = |i: usize| *imported.layers[i].raster.grid.get_checked(Coord::new(0, 0));
+        assert_eq!(cell(0).character, Some('a'));
+        assert_eq!(cell(0).foreground(), Some(Rgba32::new(255, 0, 0, 255)));
+        assert_eq!(cell(0).background(), Some(Rgba32::new_grey(0)));
+        // REXPaint can't draw a character over the layers beneath it
+        assert_eq!(cell(1), RenderCell::BLANK);
+    }
+}